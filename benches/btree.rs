@@ -0,0 +1,83 @@
+//! Benchmarks for the in-memory B+tree across node orders, from the default order up to the
+//! maximum order targeted by the page size calculations.
+//!
+//! Nodes are searched with binary search, while the linear-search feature scans them linearly
+//! instead. To compare the two, save a baseline with binary search and then benchmark linear
+//! search against it:
+//!
+//! ```text
+//! cargo bench --bench btree -- --save-baseline binary
+//! cargo bench --bench btree --features linear-search -- --baseline binary
+//! ```
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use kupier_btree::{Memory, Store};
+use rand::{Rng, SeedableRng};
+
+/// The node orders to benchmark.
+const ORDERS: [usize; 5] = [8, 64, 256, 1024, 4096];
+
+/// The number of items in the store.
+const ITEMS: u64 = 100_000;
+
+/// Creates a store with the given order, filled with random items, and returns their keys.
+fn setup(order: usize) -> (Memory, Vec<Vec<u8>>) {
+    let mut rng = rand::rngs::StdRng::seed_from_u64(397_427_893);
+    let mut store = Memory::new_with_order(order).unwrap();
+    let mut keys = Vec::with_capacity(ITEMS as usize);
+    for i in 0..ITEMS {
+        let key = rng.gen::<[u8; 16]>().to_vec();
+        store.set(&key, i.to_be_bytes().to_vec()).unwrap();
+        keys.push(key);
+    }
+    (store, keys)
+}
+
+fn get(c: &mut Criterion) {
+    let mut group = c.benchmark_group("get");
+    for order in ORDERS {
+        let (store, keys) = setup(order);
+        let mut i = 0;
+        group.bench_with_input(BenchmarkId::from_parameter(order), &order, |b, _| {
+            b.iter(|| {
+                i = (i + 1) % keys.len();
+                store.get(&keys[i]).unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+fn set(c: &mut Criterion) {
+    let mut group = c.benchmark_group("set");
+    for order in ORDERS {
+        let (mut store, keys) = setup(order);
+        let mut i = 0;
+        group.bench_with_input(BenchmarkId::from_parameter(order), &order, |b, _| {
+            b.iter(|| {
+                i = (i + 1) % keys.len();
+                store.set(&keys[i], vec![0x01]).unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+fn delete_set(c: &mut Criterion) {
+    let mut group = c.benchmark_group("delete_set");
+    for order in ORDERS {
+        let (mut store, keys) = setup(order);
+        let mut i = 0;
+        group.bench_with_input(BenchmarkId::from_parameter(order), &order, |b, _| {
+            b.iter(|| {
+                i = (i + 1) % keys.len();
+                store.delete(&keys[i]).unwrap();
+                store.set(&keys[i], vec![0x01]).unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, get, set, delete_set);
+criterion_main!(benches);
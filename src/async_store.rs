@@ -0,0 +1,221 @@
+use super::{Range, Scan, Store, WriteBatch};
+use crate::error::Result;
+
+use async_trait::async_trait;
+use futures::Stream;
+use std::fmt::Display;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+use tokio::sync::mpsc;
+
+/// The number of key/value pairs an async scan reads ahead of the consumer.
+const SCAN_BUFFER: usize = 64;
+
+/// An asynchronous key/value store, for use with tokio. Unlike Store, all methods take a shared
+/// reference, so implementations handle their own synchronization, and the store can be shared
+/// between tasks (e.g. in an Arc). Implementations must never block the executor threads, e.g. by
+/// running blocking I/O and locking on tokio's blocking thread pool.
+#[async_trait]
+pub trait AsyncStore: Display + Send + Sync {
+    /// Deletes a key, or does nothing if it does not exist.
+    async fn delete(&self, key: &[u8]) -> Result<()>;
+
+    /// Flushes any buffered data to the underlying storage medium.
+    async fn flush(&self) -> Result<()>;
+
+    /// Gets a value for a key, if it exists.
+    async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    /// Streams an ordered range of key/value pairs. This must be called within a tokio runtime.
+    fn scan(&self, range: Range) -> AsyncScan;
+
+    /// Sets a value for a key, replacing the existing value if any.
+    async fn set(&self, key: &[u8], value: Vec<u8>) -> Result<()>;
+
+    /// Applies a batch of writes atomically, in order, as Store::write().
+    async fn write(&self, batch: WriteBatch) -> Result<()>;
+}
+
+/// Stream over a key/value range.
+pub type AsyncScan = Pin<Box<dyn Stream<Item = Result<(Vec<u8>, Vec<u8>)>> + Send>>;
+
+/// Adapts any Store into an AsyncStore. The store is guarded by an RwLock, and all operations run
+/// on tokio's blocking thread pool, so neither lock contention nor blocking I/O in the store block
+/// executor threads. Cloning the adapter shares the underlying store.
+pub struct AsyncAdapter<S: Store> {
+    /// The underlying store.
+    store: Arc<RwLock<S>>,
+}
+
+impl<S: Store> Clone for AsyncAdapter<S> {
+    fn clone(&self) -> Self {
+        Self { store: self.store.clone() }
+    }
+}
+
+impl<S: Store> Display for AsyncAdapter<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.store.read() {
+            Ok(store) => write!(f, "async:{}", store),
+            Err(err) => write!(f, "async:{}", err.into_inner()),
+        }
+    }
+}
+
+impl<S: Store + 'static> AsyncAdapter<S> {
+    /// Creates a new async adapter for the given store.
+    pub fn new(store: S) -> Self {
+        Self { store: Arc::new(RwLock::new(store)) }
+    }
+
+    /// Runs a read operation against the store on the blocking thread pool.
+    async fn read<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&S) -> Result<T> + Send + 'static,
+    {
+        let store = self.store.clone();
+        blocking(move || f(&*store.read()?)).await
+    }
+
+    /// Runs a write operation against the store on the blocking thread pool.
+    async fn write_with<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut S) -> Result<T> + Send + 'static,
+    {
+        let store = self.store.clone();
+        blocking(move || f(&mut *store.write()?)).await
+    }
+}
+
+#[async_trait]
+impl<S: Store + 'static> AsyncStore for AsyncAdapter<S> {
+    async fn delete(&self, key: &[u8]) -> Result<()> {
+        let key = key.to_vec();
+        self.write_with(move |store| store.delete(&key)).await
+    }
+
+    async fn flush(&self) -> Result<()> {
+        self.write_with(|store| store.flush()).await
+    }
+
+    async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let key = key.to_vec();
+        self.read(move |store| store.get(&key)).await
+    }
+
+    fn scan(&self, range: Range) -> AsyncScan {
+        let store = self.store.clone();
+        stream(move || match store.read() {
+            Ok(store) => store.scan(range),
+            Err(err) => Box::new(std::iter::once(Err(err.into()))),
+        })
+    }
+
+    async fn set(&self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        let key = key.to_vec();
+        self.write_with(move |store| store.set(&key, value)).await
+    }
+
+    async fn write(&self, batch: WriteBatch) -> Result<()> {
+        self.write_with(move |store| store.write(batch)).await
+    }
+}
+
+/// Runs a blocking function on tokio's blocking thread pool, and returns its result.
+pub(crate) async fn blocking<T, F>(f: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T> + Send + 'static,
+{
+    tokio::task::spawn_blocking(f).await?
+}
+
+/// Streams the items of a scan, by iterating over it on tokio's blocking thread pool and sending
+/// the items through a bounded channel. The scan is created on the blocking thread pool too, since
+/// it may need to take locks. Iteration stops when the stream is dropped.
+pub(crate) fn stream<F>(scan: F) -> AsyncScan
+where
+    F: FnOnce() -> Scan + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel(SCAN_BUFFER);
+    tokio::task::spawn_blocking(move || {
+        for item in scan() {
+            if tx.blocking_send(item).is_err() {
+                break;
+            }
+        }
+    });
+    Box::pin(ScanStream { rx })
+}
+
+/// A stream of scan items received from a blocking scan task.
+struct ScanStream {
+    rx: mpsc::Receiver<Result<(Vec<u8>, Vec<u8>)>>,
+}
+
+impl Stream for ScanStream {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.rx.poll_recv(cx)
+    }
+}
+
+/// Runs the async store test suite against the given store.
+#[cfg(test)]
+pub(crate) async fn test_suite(s: &dyn AsyncStore) -> Result<()> {
+    use futures::{StreamExt, TryStreamExt};
+    use pretty_assertions::assert_eq;
+
+    assert_eq!(None, s.get(b"a").await?);
+    s.set(b"a", vec![0x01]).await?;
+    s.set(b"b", vec![0x02]).await?;
+    s.set(b"c", vec![0x03]).await?;
+    assert_eq!(Some(vec![0x01]), s.get(b"a").await?);
+    s.delete(b"b").await?;
+    s.delete(b"x").await?;
+    assert_eq!(None, s.get(b"b").await?);
+
+    let mut batch = WriteBatch::new();
+    batch.set(b"d", vec![0x04]).set(b"a", vec![0x0a]).delete(b"c");
+    s.write(batch).await?;
+    s.flush().await?;
+    assert_eq!(
+        vec![(b"a".to_vec(), vec![0x0a]), (b"d".to_vec(), vec![0x04])],
+        s.scan(Range::from(..)).try_collect::<Vec<_>>().await?
+    );
+
+    // Long scans are streamed, and can be dropped early.
+    for i in 0..1000_u16 {
+        s.set(&i.to_be_bytes(), i.to_be_bytes().to_vec()).await?;
+    }
+    let range = Range::from(100_u16.to_be_bytes().to_vec()..900_u16.to_be_bytes().to_vec());
+    let scan: Vec<_> = s.scan(range.clone()).try_collect().await?;
+    assert_eq!(800, scan.len());
+    assert!(scan.iter().zip(100_u16..).all(|((k, v), i)| k == &i.to_be_bytes() && k == v));
+    let first: Vec<_> = s.scan(range).take(3).try_collect().await?;
+    assert_eq!(scan[..3].to_vec(), first);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::Memory;
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[tokio::test]
+    async fn adapter() -> Result<()> {
+        let s = AsyncAdapter::new(Memory::new());
+        assert_eq!("async:memory", s.to_string());
+        test_suite(&s).await?;
+
+        // Clones share the store.
+        s.clone().set(b"x", vec![0xff]).await?;
+        assert_eq!(Some(vec![0xff]), s.get(b"x").await?);
+        Ok(())
+    }
+}
@@ -0,0 +1,71 @@
+//! Verifies the integrity of a paged store file, and optionally salvages its readable key/value
+//! pairs into a new file. The file is opened read-only, and should not be in use by a server. Exits
+//! with status 1 if any integrity problems are found.
+//!
+//! Usage: kupier-verify FILE [--salvage TARGET] [--verbose]
+//!
+//! --salvage TARGET  Copy all readable key/value pairs into a new paged store in TARGET.
+//! --verbose         List orphaned pages and undersized nodes, as well as integrity problems.
+
+use kupier_btree::{init_logging, Error, Paged, Result};
+
+use std::path::PathBuf;
+
+fn main() -> Result<()> {
+    let mut path = None;
+    let mut salvage = None;
+    let mut verbose = false;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--salvage" => match args.next() {
+                Some(target) => salvage = Some(PathBuf::from(target)),
+                None => return Err(Error::Config("No value for --salvage".into())),
+            },
+            "--verbose" => verbose = true,
+            _ if arg.starts_with("--") || path.is_some() => {
+                return Err(Error::Config(format!("Unknown argument {}", arg)))
+            }
+            _ => path = Some(PathBuf::from(arg)),
+        }
+    }
+    let path = path.ok_or_else(|| Error::Config("No file given".into()))?;
+    init_logging(None, "warn")?;
+
+    let report = Paged::verify(&path)?;
+    println!(
+        "{:?}: {} pages, {} keys, height {}, {} free pages, {} orphaned pages, {} undersized nodes",
+        path,
+        report.pages,
+        report.keys,
+        report.height,
+        report.free,
+        report.orphans.len(),
+        report.warnings.len()
+    );
+    if verbose {
+        if !report.orphans.is_empty() {
+            println!("orphaned pages: {:?}", report.orphans);
+        }
+        for warning in &report.warnings {
+            println!("warning: {}", warning);
+        }
+    }
+    for error in &report.errors {
+        println!("error: {}", error);
+    }
+    if report.is_ok() {
+        println!("ok");
+    } else {
+        println!("{} integrity problems found", report.errors.len());
+    }
+
+    if let Some(target) = salvage {
+        let count = Paged::salvage(&path, &target)?;
+        println!("salvaged {} key/value pairs into {:?}", count, target);
+    }
+    if !report.is_ok() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
@@ -0,0 +1,40 @@
+//! Serves a key/value store over TCP. By default, an in-memory store is served on 127.0.0.1:9605.
+//!
+//! Usage: kupierd [--listen ADDR] [--data FILE] [--log-level LEVEL] [--log-config FILE]
+//!
+//! --listen ADDR        The address to listen on.
+//! --data FILE          Serve a paged store in the given file, creating it if it does not exist.
+//! --log-level LEVEL    The minimum level of log records to write to stderr (default info).
+//! --log-config FILE    A log4rs config file (e.g. YAML) to use instead of --log-level.
+
+use kupier_btree::{init_logging, AsyncAdapter, Error, Memory, Paged, Result, Server};
+
+use std::path::PathBuf;
+use tokio::net::TcpListener;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let mut listen = "127.0.0.1:9605".to_string();
+    let mut data = None;
+    let mut log_level = "info".to_string();
+    let mut log_config = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value =
+            || args.next().ok_or_else(|| Error::Config(format!("No value for {}", arg)));
+        match arg.as_str() {
+            "--listen" => listen = value()?,
+            "--data" => data = Some(PathBuf::from(value()?)),
+            "--log-level" => log_level = value()?,
+            "--log-config" => log_config = Some(PathBuf::from(value()?)),
+            _ => return Err(Error::Config(format!("Unknown argument {}", arg))),
+        }
+    }
+    init_logging(log_config.as_deref(), &log_level)?;
+
+    let server = match data {
+        Some(path) => Server::new(Paged::new(&path)?),
+        None => Server::new(AsyncAdapter::new(Memory::new())),
+    };
+    server.serve(TcpListener::bind(&listen).await?).await
+}
@@ -1,10 +1,12 @@
-use super::{Range, Scan, Store};
+use super::{BytewiseComparator, KeyComparator, Range, Scan, Store, WriteBatch};
 use crate::error::{Error, Result};
 
+use log::{debug, trace};
 use std::cmp::Ordering;
-use std::fmt::Display;
+use std::fmt::{Debug, Display};
+use std::iter::Sum;
 use std::mem::replace;
-use std::ops::{Bound, Deref, DerefMut};
+use std::ops::{AddAssign, Bound, Deref, DerefMut, RangeBounds, SubAssign};
 use std::sync::{Arc, RwLock};
 
 
@@ -17,13 +19,26 @@ const DEFAULT_ORDER: usize = 8;
 /// order. Leaf and inner nodes contain between order/2 and order items, and will be split, rotated,
 /// or merged as appropriate, while the root node can have between 0 and order children.
 ///
-/// This implementation differs from a standard B+tree in that leaf nodes do not have pointers to
-/// the sibling leaf nodes. Iterator traversal is instead done via lookups from the root node. This
-/// has O(log n) complexity rather than O(1) for iterators, but is left as a future performance
-/// optimization if it is shown to be necessary.
+/// Nodes are immutable once shared, and held behind an Arc: writers copy the nodes along the path
+/// they modify if they are shared (copy-on-write), and then swap in the new root node. Reads and
+/// scans run against a point-in-time snapshot of the tree, i.e. a clone of the root Arc, so they
+/// never observe concurrent writes and only hold the root lock while taking the snapshot. Since
+/// leaf nodes may be shared between several versions of the tree, they can't link to their
+/// siblings; iterators instead keep the path of nodes from the snapshot root to their current leaf
+/// node, and move along it to the sibling leaf nodes without looking them up from the root. This
+/// takes amortized O(1) time per step.
+///
+/// Keys are ordered by their raw bytes by default, but a custom KeyComparator can be given when
+/// creating the store. All lookups, range bounds and scans then use the comparator's order.
 pub struct Memory {
-    /// The tree root, guarded by an RwLock to support multiple iterators across it.
-    root: Arc<RwLock<Node>>,
+    /// The current tree root, guarded by an RwLock which is only held while taking a snapshot or
+    /// modifying the tree.
+    root: Arc<RwLock<Arc<Node>>>,
+    /// The number of keys and key/value bytes in the tree, maintained on every write. Writes
+    /// require a mutable reference, so this is always in sync with the root node.
+    count: Count,
+    /// The key comparator, which determines the key order.
+    comparator: Arc<dyn KeyComparator>,
 }
 
 impl Display for Memory {
@@ -40,130 +55,691 @@ impl Memory {
 
     /// Creates a new in-memory store using the given order.
     pub fn new_with_order(order: usize) -> Result<Self> {
+        Self::new_with_comparator(order, BytewiseComparator)
+    }
+
+    /// Creates a new in-memory store using the given order and key comparator.
+    pub fn new_with_comparator<C>(order: usize, comparator: C) -> Result<Self>
+    where
+        C: KeyComparator + 'static,
+    {
         if order < 2 {
             return Err(Error::Internal("Order must be at least 2".into()));
         }
-        Ok(Self { root: Arc::new(RwLock::new(Node::Root(Children::new(order)))) })
+        let root = Node::Root(Arc::new(Children::new(order)));
+        Ok(Self {
+            root: Arc::new(RwLock::new(Arc::new(root))),
+            count: Count::default(),
+            comparator: Arc::new(comparator),
+        })
+    }
+
+    /// Creates a new in-memory store using the given order, and bulk loads it with the given
+    /// key/value pairs, which must be sorted by key and unique. The tree is built bottom-up, one
+    /// level at a time, filling nodes up to the given fill factor of the order (between 0 and 1),
+    /// but always at least half full. This is much faster than setting the pairs one by one, and
+    /// the fill factor can leave room for later writes to avoid immediate splits.
+    pub fn load<I>(order: usize, fill: f64, items: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+    {
+        Self::load_with_comparator(order, BytewiseComparator, fill, items)
+    }
+
+    /// Like load(), but uses the given key comparator. The items must be sorted by it.
+    pub fn load_with_comparator<C, I>(
+        order: usize,
+        comparator: C,
+        fill: f64,
+        items: I,
+    ) -> Result<Self>
+    where
+        C: KeyComparator + 'static,
+        I: IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+    {
+        let mut store = Self::new_with_comparator(order, comparator)?;
+        if !(fill > 0.0 && fill <= 1.0) {
+            return Err(Error::Config(format!("Fill factor must be in (0, 1], got {}", fill)));
+        }
+        // Nodes need at least two children for the levels to converge on a root node.
+        let size = ((order as f64 * fill).ceil() as usize).clamp(order.div_ceil(2).max(2), order);
+
+        // Build the leaf nodes, and then the inner node levels above them, until the top level
+        // fits in the root node. Each level keeps the first key of every node as separator keys
+        // for the level above.
+        let mut items = items.into_iter().peekable();
+        let mut pairs = Vec::new();
+        while let Some((key, value)) = items.next() {
+            if let Some((next, _)) = items.peek() {
+                if store.comparator.compare(next, &key) != Ordering::Greater {
+                    return Err(Error::Value(format!(
+                        "Keys must be sorted and unique, got {:x?} after {:x?}",
+                        next, key
+                    )));
+                }
+            }
+            store.count += Count::of(&key, &value);
+            pairs.push((key, value));
+        }
+
+        let mut level: Vec<(Vec<u8>, Node)> = Self::chunk(pairs, order, size)
+            .into_iter()
+            .map(|chunk| {
+                let mut values = Values::new(order);
+                values.extend(chunk);
+                (values[0].0.clone(), Node::Leaf(Arc::new(values)))
+            })
+            .collect();
+        while level.len() > order {
+            level = Self::chunk(level, order, size)
+                .into_iter()
+                .map(|chunk| {
+                    let (key, children) = Self::children(order, chunk);
+                    (key, Node::Inner(Arc::new(children)))
+                })
+                .collect();
+        }
+        let (_, root) = Self::children(order, level);
+        *store.root.write()? = Arc::new(Node::Root(Arc::new(root)));
+        Ok(store)
+    }
+
+    /// Builds a child set from nodes and their first keys for a bulk load, returning it along with
+    /// its first key.
+    fn children(order: usize, nodes: Vec<(Vec<u8>, Node)>) -> (Vec<u8>, Children) {
+        let mut children = Children::new(order);
+        let mut first_key = Vec::new();
+        for (i, (key, node)) in nodes.into_iter().enumerate() {
+            if i == 0 {
+                first_key = key;
+            } else {
+                children.keys.push(key);
+            }
+            children.nodes.push(node);
+        }
+        children.recount();
+        (first_key, children)
+    }
+
+    /// Splits items into chunks of the given target size for a bulk load. The chunks are evened
+    /// out such that all of them are at least half full and at most the order, as long as there
+    /// are enough items for more than one chunk.
+    fn chunk<T>(items: Vec<T>, order: usize, size: usize) -> Vec<Vec<T>> {
+        let len = items.len();
+        if len == 0 {
+            return Vec::new();
+        }
+        let count = (len / size).max(len.div_ceil(order)).max(1);
+        let mut chunks = Vec::with_capacity(count);
+        let mut items = items.into_iter();
+        for i in 0..count {
+            let chunk_size = len / count + usize::from(i < len % count);
+            chunks.push(items.by_ref().take(chunk_size).collect());
+        }
+        chunks
+    }
+
+    /// Checks the tree invariants, returning an Error::Internal describing the first violation:
+    ///
+    /// - Keys are sorted and unique, both in leaf nodes and separator keys in inner nodes.
+    /// - Keys are within the range given by the parent node's separator keys.
+    /// - Inner nodes have one less separator key than children.
+    /// - All nodes have the tree's order, and at most order items.
+    /// - Non-root nodes have at least order/2 items, except a single leaf node below the root.
+    /// - All leaf nodes are at the same depth.
+    ///
+    /// This walks the entire tree, and is mostly useful for testing.
+    pub fn check(&self) -> Result<()> {
+        let root = self.root.read()?.clone();
+        root.check(&*self.comparator, root.order(), (None, None), &mut Vec::new())?;
+        if root.count() != self.count {
+            return Err(Error::Internal(format!(
+                "Tree contains {:?}, but store has {:?}",
+                root.count(),
+                self.count
+            )));
+        }
+        Ok(())
+    }
+
+    /// Returns the total size of all keys and values, in bytes.
+    pub fn bytes(&self) -> usize {
+        self.count.bytes
+    }
+
+    /// Counts the keys in the given range, in O(log n) time.
+    pub fn count(&self, range: Range) -> Result<usize> {
+        let root = self.root.read()?.clone();
+        let cmp = &*self.comparator;
+        let start = match range.start_bound() {
+            Bound::Included(k) => root.rank(cmp, k, false),
+            Bound::Excluded(k) => root.rank(cmp, k, true),
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(k) => root.rank(cmp, k, true),
+            Bound::Excluded(k) => root.rank(cmp, k, false),
+            Bound::Unbounded => root.len(),
+        };
+        Ok(end.saturating_sub(start))
+    }
+
+    /// Checks if the store is empty.
+    pub fn is_empty(&self) -> bool {
+        self.count.keys == 0
+    }
+
+    /// Returns the number of keys in the store.
+    pub fn len(&self) -> usize {
+        self.count.keys
+    }
+
+    /// Returns the n-th key/value pair in key order (starting at 0), if any, in O(log n) time.
+    pub fn nth(&self, n: usize) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        let root = self.root.read()?.clone();
+        Ok(root.nth(n))
+    }
+
+    /// Returns the rank of a key, i.e. the number of keys less than it, in O(log n) time. This is
+    /// the key's index if it exists, and otherwise the index it would be inserted at.
+    pub fn rank(&self, key: &[u8]) -> Result<usize> {
+        let root = self.root.read()?.clone();
+        Ok(root.rank(&*self.comparator, key, false))
+    }
+
+    /// Creates a point-in-time snapshot of the store. The snapshot shares nodes with the store
+    /// using copy-on-write, so it is cheap to create, and is itself a store: writes to either the
+    /// snapshot or the original store are not visible in the other.
+    pub fn snapshot(&self) -> Result<Self> {
+        Ok(Self {
+            root: Arc::new(RwLock::new(self.root.read()?.clone())),
+            count: self.count,
+            comparator: self.comparator.clone(),
+        })
+    }
+
+    /// Returns statistics about the tree structure. The key and byte counts are maintained on
+    /// writes, while the rest requires walking all nodes, but not the keys and values themselves.
+    pub fn stats(&self) -> Result<Stats> {
+        let root = self.root.read()?.clone();
+        let mut stats = Stats {
+            keys: self.count.keys,
+            bytes: self.count.bytes,
+            height: 0,
+            nodes: Vec::new(),
+            fill: 0.0,
+            utilization: 0.0,
+        };
+        let (mut fill, mut count) = (0.0, 0);
+        let mut level = vec![&*root];
+        while !level.is_empty() {
+            stats.height += 1;
+            stats.nodes.push(level.len());
+            if stats.height > 1 {
+                fill += level.iter().map(|n| n.size() as f64 / n.order() as f64).sum::<f64>();
+                count += level.len();
+            }
+            if let Node::Leaf(_) = level[0] {
+                let capacity: usize = level.iter().map(|n| n.order()).sum();
+                stats.utilization = self.count.keys as f64 / capacity as f64;
+            }
+            level = level.into_iter().flat_map(|n| n.children()).collect();
+        }
+        if count > 0 {
+            stats.fill = fill / count as f64;
+        }
+        Ok(stats)
     }
 }
 
 impl Store for Memory {
     fn delete(&mut self, key: &[u8]) -> Result<()> {
-        self.root.write()?.delete(key);
+        let mut root = self.root.write()?;
+        if let Some(value) = Arc::make_mut(&mut root).delete(&*self.comparator, key) {
+            self.count -= Count::of(key, &value);
+        }
         Ok(())
     }
 
+    fn delete_range(&mut self, range: Range) -> Result<usize> {
+        let mut root = self.root.write()?;
+        let deleted = Arc::make_mut(&mut root).delete_range(&*self.comparator, &range);
+        self.count -= deleted;
+        Ok(deleted.keys)
+    }
+
+    fn cursor(&self) -> Box<dyn super::Cursor> {
+        Box::new(Cursor::new(self.root.clone(), self.comparator.clone()))
+    }
+
     fn flush(&mut self) -> Result<()> {
         Ok(())
     }
 
     fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
-        Ok(self.root.read()?.get(key))
+        let root = self.root.read()?.clone();
+        Ok(root.get(&*self.comparator, key))
     }
 
     fn scan(&self, range: Range) -> Scan {
-        Box::new(Iter::new(self.root.clone(), range))
+        // The Store trait doesn't allow scan() to fail, so we take the snapshot even if the lock is
+        // poisoned, like the other Store methods would if they didn't return the error.
+        let root = match self.root.read() {
+            Ok(root) => root.clone(),
+            Err(err) => err.into_inner().clone(),
+        };
+        Box::new(Iter::new(root, range, self.comparator.clone()))
     }
 
     fn set(&mut self, key: &[u8], value: Vec<u8>) -> Result<()> {
-        self.root.write()?.set(key, value);
+        let mut root = self.root.write()?;
+        self.count += Count::of(key, &value);
+        if let (Some(replaced), _) = Arc::make_mut(&mut root).set(&*self.comparator, key, value) {
+            self.count -= Count::of(key, &replaced);
+        }
         Ok(())
     }
+
+    fn write(&mut self, batch: WriteBatch) -> Result<()> {
+        let mut guard = self.root.write()?;
+        let root = Arc::make_mut(&mut guard);
+        let cmp = &*self.comparator;
+        for (key, value) in batch {
+            match value {
+                Some(value) => {
+                    self.count += Count::of(&key, &value);
+                    if let (Some(replaced), _) = root.set(cmp, &key, value) {
+                        self.count -= Count::of(&key, &replaced);
+                    }
+                }
+                None => {
+                    if let Some(value) = root.delete(cmp, &key) {
+                        self.count -= Count::of(&key, &value);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Statistics about an in-memory B+tree.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Stats {
+    /// The number of keys.
+    pub keys: usize,
+    /// The total size of all keys and values, in bytes.
+    pub bytes: usize,
+    /// The number of node levels, including the root node.
+    pub height: usize,
+    /// The number of nodes at each level, starting with the root node.
+    pub nodes: Vec<usize>,
+    /// The average fill factor of non-root nodes, i.e. their number of items relative to the order.
+    pub fill: f64,
+    /// The leaf utilization, i.e. the number of keys relative to the capacity of all leaf nodes.
+    pub utilization: f64,
+}
+
+/// The number of keys and key/value bytes in a tree or subtree.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct Count {
+    keys: usize,
+    bytes: usize,
+}
+
+impl Count {
+    /// Returns the count of a single key/value pair.
+    fn of(key: &[u8], value: &[u8]) -> Self {
+        Self { keys: 1, bytes: key.len() + value.len() }
+    }
+}
+
+impl AddAssign for Count {
+    fn add_assign(&mut self, other: Self) {
+        self.keys += other.keys;
+        self.bytes += other.bytes;
+    }
+}
+
+impl SubAssign for Count {
+    fn sub_assign(&mut self, other: Self) {
+        self.keys -= other.keys;
+        self.bytes -= other.bytes;
+    }
+}
+
+impl Sum for Count {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        iter.fold(Self::default(), |mut sum, count| {
+            sum += count;
+            sum
+        })
+    }
 }
 
+/// The result of setting a key in a node: the replaced value, if any, and the split key and new
+/// (right) node, if the node split.
+type SetResult<T> = (Option<Vec<u8>>, Option<(Vec<u8>, T)>);
+
 /// B-tree node variants. Most internal logic is delegated to the contained Children/Values structs,
 /// while this outer structure manages the overall tree, particularly root special-casing.
 ///
 /// All nodes in a tree have the same order (i.e. the same maximum number of children/values). The
 /// root node can contain anywhere between 0 and the maximum number of items, while inner and leaf
 /// nodes try to stay between order/2 and order items.
-#[derive(Debug, PartialEq)]
+///
+/// Child and value sets are held behind an Arc, and may be shared between several versions of the
+/// tree, so they must be copied via Arc::make_mut() before they are modified.
+#[derive(Clone, Debug, PartialEq)]
 enum Node {
-    Root(Children),
-    Inner(Children),
-    Leaf(Values),
+    Root(Arc<Children>),
+    Inner(Arc<Children>),
+    Leaf(Arc<Values>),
 }
 
 impl Node {
-    /// Deletes a key from the node, if it exists.
-    fn delete(&mut self, key: &[u8]) {
+    /// Checks the invariants of the node and its children, returning the depth of its leaf nodes.
+    /// All keys must be within the given lower (inclusive) and upper (exclusive) bounds. The path
+    /// contains the child indexes leading to the node, for error messages.
+    fn check(
+        &self,
+        cmp: &dyn KeyComparator,
+        order: usize,
+        bounds: (Option<&[u8]>, Option<&[u8]>),
+        path: &mut Vec<usize>,
+    ) -> Result<usize> {
+        let fail = |path: &[usize], msg: String| -> Result<usize> {
+            Err(Error::Internal(format!("Invalid node at path {:?}: {}", path, msg)))
+        };
+        let in_bounds = |key: &[u8]| {
+            bounds.0.is_none_or(|lower| cmp.compare(lower, key) != Ordering::Greater)
+                && bounds.1.is_none_or(|upper| cmp.compare(key, upper) == Ordering::Less)
+        };
+        if self.order() != order {
+            return fail(path, format!("order {} differs from tree order {}", self.order(), order));
+        }
+        if self.size() > order {
+            return fail(path, format!("{} items exceeds order {}", self.size(), order));
+        }
+
+        match self {
+            Self::Root(children) | Self::Inner(children) => {
+                if children.keys.len() != children.len().saturating_sub(1) {
+                    let (keys, nodes) = (children.keys.len(), children.len());
+                    return fail(path, format!("{} keys for {} children", keys, nodes));
+                }
+                for (i, key) in children.keys.iter().enumerate() {
+                    if i > 0 && cmp.compare(&children.keys[i - 1], key) != Ordering::Less {
+                        return fail(path, format!("unsorted separator key {:x?}", key));
+                    }
+                    if !in_bounds(key) {
+                        return fail(path, format!("separator key {:x?} out of bounds", key));
+                    }
+                }
+                let count: usize = children.iter().map(Node::len).sum();
+                if children.count != count {
+                    let msg = format!("count {} differs from {} child keys", children.count, count);
+                    return fail(path, msg);
+                }
+                if matches!(self, Self::Root(_)) && matches!(children.nodes[..], [Self::Inner(_)]) {
+                    return fail(path, "single inner child of root node".into());
+                }
+
+                // Non-root nodes must be at least half full, except a single leaf below the root.
+                let min = if matches!(self, Self::Root(_)) && children.len() == 1 {
+                    1
+                } else {
+                    order.div_ceil(2)
+                };
+                let mut depth = None;
+                for (i, child) in children.iter().enumerate() {
+                    path.push(i);
+                    if matches!(child, Self::Root(_)) {
+                        return fail(path, "nested root node".into());
+                    }
+                    if child.size() < min {
+                        return fail(path, format!("{} items below minimum {}", child.size(), min));
+                    }
+                    let lower = if i > 0 { Some(&*children.keys[i - 1]) } else { bounds.0 };
+                    let upper = children.keys.get(i).map(|k| &**k).or(bounds.1);
+                    let child_depth = child.check(cmp, order, (lower, upper), path)?;
+                    path.pop();
+                    match depth {
+                        Some(depth) if depth != child_depth => {
+                            return fail(
+                                path,
+                                format!("leaf nodes at depths {} and {}", depth, child_depth),
+                            )
+                        }
+                        _ => depth = Some(child_depth),
+                    }
+                }
+                Ok(depth.unwrap_or(0) + 1)
+            }
+            Self::Leaf(values) => {
+                for (i, (key, _)) in values.iter().enumerate() {
+                    if i > 0 && cmp.compare(&values[i - 1].0, key) != Ordering::Less {
+                        return fail(path, format!("unsorted key {:x?}", key));
+                    }
+                    if !in_bounds(key) {
+                        return fail(path, format!("key {:x?} out of bounds", key));
+                    }
+                }
+                Ok(0)
+            }
+        }
+    }
+
+    /// Deletes a key from the node, if it exists, and returns its value.
+    fn delete(&mut self, cmp: &dyn KeyComparator, key: &[u8]) -> Option<Vec<u8>> {
         match self {
             Self::Root(children) => {
-                children.delete(key);
+                let children = Arc::make_mut(children);
+                let value = children.delete(cmp, key);
                 // If we now have a single child, pull it up into the root.
                 while children.len() == 1 && matches!(children[0], Node::Inner { .. }) {
                     if let Node::Inner(c) = children.remove(0) {
-                        *children = c;
+                        *children = Arc::try_unwrap(c).unwrap_or_else(|c| (*c).clone());
                     }
+                    debug!("Collapsed root node, tree height is now {}", 1 + children[0].height());
                 }
                 // If we have a single empty child, remove it.
                 if children.len() == 1 && children[0].size() == 0 {
                     children.remove(0);
                 }
+                value
             }
-            Self::Inner(children) => children.delete(key),
-            Self::Leaf(values) => values.delete(key),
+            Self::Inner(children) => Arc::make_mut(children).delete(cmp, key),
+            Self::Leaf(values) => Arc::make_mut(values).delete(cmp, key),
         }
     }
 
-    /// Fetches a value for a key, if it exists.
-    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+    /// Deletes all keys in the given range, returning the number of keys and bytes deleted.
+    fn delete_range(&mut self, cmp: &dyn KeyComparator, range: &Range) -> Count {
         match self {
-            Self::Root(children) | Self::Inner(children) => children.get(key),
-            Self::Leaf(values) => values.get(key),
+            Self::Root(children) => {
+                let children = Arc::make_mut(children);
+                let count = children.delete_range(cmp, range);
+                // If we now have a single inner child, pull it up into the root. Its children may
+                // not have been rebalanced, since they had no siblings, so we do that here.
+                while children.len() == 1 && matches!(children[0], Node::Inner { .. }) {
+                    if let Node::Inner(c) = children.remove(0) {
+                        *children = Arc::try_unwrap(c).unwrap_or_else(|c| (*c).clone());
+                    }
+                    children.rebalance();
+                    debug!("Collapsed root node, tree height is now {}", 1 + children[0].height());
+                }
+                // If we have a single empty child, remove it.
+                if children.len() == 1 && children[0].size() == 0 {
+                    children.remove(0);
+                }
+                count
+            }
+            Self::Inner(children) => Arc::make_mut(children).delete_range(cmp, range),
+            Self::Leaf(values) => Arc::make_mut(values).delete_range(cmp, range),
+        }
+    }
+
+    /// Returns the number of keys in the node and its children. This is O(1), since child sets
+    /// maintain the key count of their subtree.
+    fn len(&self) -> usize {
+        match self {
+            Self::Root(children) | Self::Inner(children) => children.count,
+            Self::Leaf(values) => values.len(),
         }
     }
 
-    /// Fetches the first key/value pair, if any.
-    fn get_first(&self) -> Option<(Vec<u8>, Vec<u8>)> {
+    /// Returns the n-th key/value pair in the node and its children, if any.
+    fn nth(&self, mut n: usize) -> Option<(Vec<u8>, Vec<u8>)> {
         match self {
-            Self::Root(children) | Self::Inner(children) => children.get_first(),
-            Self::Leaf(values) => values.get_first(),
+            Self::Root(children) | Self::Inner(children) => {
+                for child in children.iter() {
+                    if n < child.len() {
+                        return child.nth(n);
+                    }
+                    n -= child.len();
+                }
+                None
+            }
+            Self::Leaf(values) => values.0.get(n).cloned(),
+        }
+    }
+
+    /// Returns the number of keys less than the given key (or equal to it if inclusive) in the
+    /// node and its children.
+    fn rank(&self, cmp: &dyn KeyComparator, key: &[u8], inclusive: bool) -> usize {
+        match self {
+            Self::Root(children) | Self::Inner(children) => {
+                if children.is_empty() {
+                    return 0;
+                }
+                let i = children.search(cmp, key);
+                let before: usize = children[..i].iter().map(Node::len).sum();
+                before + children[i].rank(cmp, key, inclusive)
+            }
+            Self::Leaf(values) => values.rank(cmp, key, inclusive),
         }
     }
 
-    /// Fetches the last key/value pair, if any.
-    fn get_last(&self) -> Option<(Vec<u8>, Vec<u8>)> {
+    /// Returns the height of the node, i.e. the number of node levels including this one.
+    fn height(&self) -> usize {
         match self {
-            Self::Root(children) | Self::Inner(children) => children.get_last(),
-            Self::Leaf(values) => values.get_last(),
+            Self::Root(children) | Self::Inner(children) => {
+                1 + children.first().map(Node::height).unwrap_or(0)
+            }
+            Self::Leaf(_) => 1,
         }
     }
 
-    /// Fetches the next key/value pair after the given key.
-    fn get_next(&self, key: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+    /// Returns the node's children, which is empty for leaf nodes.
+    fn children(&self) -> &[Node] {
         match self {
-            Self::Root(children) | Self::Inner(children) => children.get_next(key),
-            Self::Leaf(values) => values.get_next(key),
+            Self::Root(children) | Self::Inner(children) => children,
+            Self::Leaf(_) => &[],
         }
     }
 
-    /// Fetches the previous key/value pair before the given key.
-    fn get_prev(&self, key: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+    /// Counts the keys and bytes in the node and its children, by walking them.
+    fn count(&self) -> Count {
         match self {
-            Self::Root(children) | Self::Inner(children) => children.get_prev(key),
-            Self::Leaf(values) => values.get_prev(key),
+            Self::Root(children) | Self::Inner(children) => children.iter().map(Node::count).sum(),
+            Self::Leaf(values) => values.iter().map(|(key, value)| Count::of(key, value)).sum(),
         }
     }
 
-    /// Sets a key to a value in the node, inserting or updating the key as appropriate. If the
-    /// node splits, return the split key and new (right) node.
-    fn set(&mut self, key: &[u8], value: Vec<u8>) -> Option<(Vec<u8>, Node)> {
+    /// Fetches a value for a key, if it exists.
+    fn get(&self, cmp: &dyn KeyComparator, key: &[u8]) -> Option<Vec<u8>> {
+        match self {
+            Self::Root(children) | Self::Inner(children) => children.get(cmp, key),
+            Self::Leaf(values) => values.get(cmp, key),
+        }
+    }
+
+    /// Descends from the node to a leaf node, using the given function to pick the index of the
+    /// child to descend into, and returns the position of the leaf's first key/value pair. Returns
+    /// None if the node has no children.
+    fn descend(&self, pick: impl Fn(&Children) -> usize) -> Option<Position> {
+        let mut path = Path::new();
+        let leaf = match self {
+            Self::Root(children) | Self::Inner(children) if children.is_empty() => return None,
+            Self::Root(children) | Self::Inner(children) => {
+                path.push((children.clone(), pick(children)));
+                Position::descend(&mut path, pick)
+            }
+            Self::Leaf(values) => values.clone(),
+        };
+        Some(Position { path, leaf, index: 0 })
+    }
+
+    /// Finds the position of the first key/value pair after the given lower bound, if any.
+    fn seek(&self, cmp: &dyn KeyComparator, bound: Bound<&[u8]>) -> Option<Position> {
+        let mut position = self.descend(|children| match bound {
+            Bound::Included(k) | Bound::Excluded(k) => children.search(cmp, k),
+            Bound::Unbounded => 0,
+        })?;
+        let index = match bound {
+            Bound::Included(k) => position.leaf.rank(cmp, k, false),
+            Bound::Excluded(k) => position.leaf.rank(cmp, k, true),
+            Bound::Unbounded => 0,
+        };
+        if index < position.leaf.len() {
+            position.index = index;
+            return Some(position);
+        }
+        // If the responsible leaf has no matching items, the first item of the following leaf
+        // will be the next one.
+        position.index = position.leaf.len().saturating_sub(1);
+        position.next().then_some(position)
+    }
+
+    /// Finds the position of the last key/value pair before the given upper bound, if any.
+    fn seek_back(&self, cmp: &dyn KeyComparator, bound: Bound<&[u8]>) -> Option<Position> {
+        let mut position = self.descend(|children| match bound {
+            Bound::Included(k) | Bound::Excluded(k) => children.search(cmp, k),
+            Bound::Unbounded => children.len() - 1,
+        })?;
+        let index = match bound {
+            Bound::Included(k) => position.leaf.rank(cmp, k, true),
+            Bound::Excluded(k) => position.leaf.rank(cmp, k, false),
+            Bound::Unbounded => position.leaf.len(),
+        };
+        if index > 0 {
+            position.index = index - 1;
+            return Some(position);
+        }
+        // If the responsible leaf has no matching items, the last item of the preceding leaf will
+        // be the previous one.
+        position.prev().then_some(position)
+    }
+
+    /// Sets a key to a value in the node, inserting or updating the key as appropriate, and returns
+    /// the replaced value if any. If the node splits, return the split key and new (right) node.
+    fn set(&mut self, cmp: &dyn KeyComparator, key: &[u8], value: Vec<u8>) -> SetResult<Node> {
         match self {
             Self::Root(ref mut children) => {
                 // Set the key/value pair in the children. If the children split, create a new
                 // child set for the root node with two new inner nodes for the split children.
-                if let Some((split_key, split_children)) = children.set(key, value) {
+                let (replaced, split) = Arc::make_mut(children).set(cmp, key, value);
+                if let Some((split_key, split_children)) = split {
                     let mut root_children = Children::new(children.capacity());
                     root_children.keys.push(split_key);
-                    root_children.nodes.push(Node::Inner(replace(children, Children::empty())));
-                    root_children.nodes.push(Node::Inner(split_children));
-                    *children = root_children;
+                    let left = replace(children, Arc::new(Children::empty()));
+                    root_children.nodes.push(Node::Inner(left));
+                    root_children.nodes.push(Node::Inner(Arc::new(split_children)));
+                    root_children.recount();
+                    *children = Arc::new(root_children);
+                    debug!("Split root node, tree height increased to {}", self.height());
                 }
-                None
+                (replaced, None)
+            }
+            Self::Inner(children) => {
+                let (replaced, split) = Arc::make_mut(children).set(cmp, key, value);
+                (replaced, split.map(|(sk, c)| (sk, Node::Inner(Arc::new(c)))))
+            }
+            Self::Leaf(values) => {
+                let (replaced, split) = Arc::make_mut(values).set(cmp, key, value);
+                (replaced, split.map(|(sk, v)| (sk, Node::Leaf(Arc::new(v)))))
             }
-            Self::Inner(children) => children.set(key, value).map(|(sk, c)| (sk, Node::Inner(c))),
-            Self::Leaf(values) => values.set(key, value).map(|(sk, v)| (sk, Node::Leaf(v))),
         }
     }
 
@@ -196,12 +772,27 @@ impl Node {
 /// 1      f     d=4,e=5            Nodes:  a=1,b=2,c=3 | d=4,e=5 | f=6,g=7
 /// 2            f=6,g=7
 ///
-/// Thus, to find the node responsible for a given key, scan the keys until encountering one greater
-/// than the given key (if any) - the index of that key corresponds to the index of the node.
+/// Thus, to find the node responsible for a given key, binary search the keys for the first one
+/// greater than the given key (if any) - the index of that key corresponds to the index of the
+/// node.
 #[derive(Debug, PartialEq)]
 struct Children {
     keys: Vec<Vec<u8>>,
     nodes: Vec<Node>,
+    /// The total number of keys in the child nodes and their children, i.e. in the subtree. This
+    /// allows order-statistic lookups (nth, rank, count) in O(log n) time.
+    count: usize,
+}
+
+impl Clone for Children {
+    /// Clones the child set, retaining the vector capacities since they determine the order.
+    fn clone(&self) -> Self {
+        let mut children = Self::new(self.capacity());
+        children.keys.extend(self.keys.iter().cloned());
+        children.nodes.extend(self.nodes.iter().cloned());
+        children.count = self.count;
+        children
+    }
 }
 
 impl Deref for Children {
@@ -220,27 +811,31 @@ impl DerefMut for Children {
 impl Children {
     /// Creates a new child set of the given order (maximum capacity).
     fn new(order: usize) -> Self {
-        Self { keys: Vec::with_capacity(order - 1), nodes: Vec::with_capacity(order) }
+        Self { keys: Vec::with_capacity(order - 1), nodes: Vec::with_capacity(order), count: 0 }
     }
 
     /// Creates an empty child set, for use with replace().
     fn empty() -> Self {
-        Self { keys: Vec::new(), nodes: Vec::new() }
+        Self { keys: Vec::new(), nodes: Vec::new(), count: 0 }
     }
 
-    /// Deletes a key from the children, if it exists.
-    fn delete(&mut self, key: &[u8]) {
+    /// Deletes a key from the children, if it exists, and returns its value.
+    fn delete(&mut self, cmp: &dyn KeyComparator, key: &[u8]) -> Option<Vec<u8>> {
         if self.is_empty() {
-            return;
+            return None;
         }
 
         // Delete the key in the relevant child.
-        let (i, child) = self.lookup_mut(key);
-        child.delete(key);
+        let (i, child) = self.lookup_mut(cmp, key);
+        let value = child.delete(cmp, key);
+        let underflow = child.size() < child.order().div_ceil(2);
+        if value.is_some() {
+            self.count -= 1;
+        }
 
         // If the child does not underflow, or it has no siblings, we're done.
-        if child.size() >= (child.order() + 1) / 2 || self.len() == 1 {
-            return;
+        if !underflow || self.len() == 1 {
+            return value;
         }
 
         // Attempt to rotate or merge with the left or right siblings.
@@ -254,63 +849,50 @@ impl Children {
             self.rotate_right(i - 1);
         } else if rsize > (rorder + 1) / 2 {
             self.rotate_left(i + 1);
-        } else if lsize + size <= lorder {
+        } else if i > 0 && lsize + size <= lorder {
             self.merge(i - 1);
-        } else if rsize + size <= order {
+        } else if i < self.len() - 1 && rsize + size <= order {
             self.merge(i);
         }
+        value
     }
 
-    /// Fetches a value for a key, if it exists.
-    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
-        if !self.is_empty() {
-            self.lookup(key).1.get(key)
-        } else {
-            None
-        }
-    }
-
-    /// Fetches the first key/value pair, if any.
-    fn get_first(&self) -> Option<(Vec<u8>, Vec<u8>)> {
-        self.nodes.first().and_then(|n| n.get_first())
-    }
-
-    /// Fetches the last key/value pair, if any.
-    fn get_last(&self) -> Option<(Vec<u8>, Vec<u8>)> {
-        self.nodes.last().and_then(|n| n.get_last())
-    }
-
-    /// Fetches the next key/value pair after the given key, if it exists.
-    fn get_next(&self, key: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+    /// Deletes all keys in the given range from the children, returning the number of keys and
+    /// bytes deleted. Children that are entirely within the range are removed as a whole, so only
+    /// the children containing the range bounds are traversed, and rebalanced afterwards.
+    fn delete_range(&mut self, cmp: &dyn KeyComparator, range: &Range) -> Count {
         if self.is_empty() {
-            return None;
+            return Count::default();
         }
-        // First, look in the child responsible for the given key.
-        let (i, child) = self.lookup(key);
-        if let Some(item) = child.get_next(key) {
-            Some(item)
-            // Otherwise, try the next child.
-        } else if i < self.len() - 1 {
-            self[i + 1].get_next(key)
-            // We don't have it.
-        } else {
-            None
+        let start = match range.start_bound() {
+            Bound::Included(k) | Bound::Excluded(k) => self.search(cmp, k),
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(k) | Bound::Excluded(k) => self.search(cmp, k),
+            Bound::Unbounded => self.len() - 1,
+        };
+        if start > end {
+            return Count::default();
         }
-    }
 
-    /// Fetches the previous key/value pair before the given key, if it exists.
-    fn get_prev(&self, key: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
-        if self.is_empty() {
-            return None;
+        // Delete from the boundary children, and remove the children between them along with
+        // their keys. The key before the end child remains as the separator between them.
+        let mut count = self[start].delete_range(cmp, range);
+        if end > start {
+            count += self.nodes.drain(start + 1..end).map(|node| node.count()).sum();
+            self.keys.drain(start..end - 1);
+            count += self[start + 1].delete_range(cmp, range);
         }
-        // First, look in the child responsible for the given key.
-        let (i, child) = self.lookup(key);
-        if let Some(item) = child.get_prev(key) {
-            Some(item)
-            // Otherwise, try the previous child.
-        } else if i > 0 {
-            self[i - 1].get_prev(key)
-            // We don't have it
+        self.count -= count.keys;
+        self.rebalance();
+        count
+    }
+
+    /// Fetches a value for a key, if it exists.
+    fn get(&self, cmp: &dyn KeyComparator, key: &[u8]) -> Option<Vec<u8>> {
+        if !self.is_empty() {
+            self.lookup(cmp, key).1.get(cmp, key)
         } else {
             None
         }
@@ -318,59 +900,132 @@ impl Children {
 
     /// Looks up the child responsible for a given key. This can only be called on non-empty
     /// child sets, which should be all child sets except for the initial root node.
-    fn lookup(&self, key: &[u8]) -> (usize, &Node) {
-        let i = self.keys.iter().position(|k| k.deref() > key).unwrap_or_else(|| self.keys.len());
+    fn lookup(&self, cmp: &dyn KeyComparator, key: &[u8]) -> (usize, &Node) {
+        let i = self.search(cmp, key);
         (i, &self[i])
     }
 
     /// Looks up the child responsible for a given key, and returns a mutable reference to it. This
     /// can only be called on non-empty child sets, which should be all child sets except for the
     /// initial root node.
-    fn lookup_mut(&mut self, key: &[u8]) -> (usize, &mut Node) {
-        let i = self.keys.iter().position(|k| k.deref() > key).unwrap_or_else(|| self.keys.len());
+    fn lookup_mut(&mut self, cmp: &dyn KeyComparator, key: &[u8]) -> (usize, &mut Node) {
+        let i = self.search(cmp, key);
         (i, &mut self[i])
     }
 
+    /// Searches for the index of the child responsible for a given key, i.e. the index of the first
+    /// key greater than the given key, or the number of keys if there is none. The linear-search
+    /// feature scans the keys linearly instead, as a baseline for benchmarks.
+    fn search(&self, cmp: &dyn KeyComparator, key: &[u8]) -> usize {
+        if cfg!(feature = "linear-search") {
+            return self
+                .keys
+                .iter()
+                .position(|k| cmp.compare(k, key) == Ordering::Greater)
+                .unwrap_or(self.keys.len());
+        }
+        self.keys.partition_point(|k| cmp.compare(k, key) != Ordering::Greater)
+    }
+
     /// Merges the node at index i with it's right sibling.
     fn merge(&mut self, i: usize) {
         let parent_key = self.keys.remove(i);
+        trace!("Merging nodes at separator key {:x?}", parent_key);
         let right = &mut self.remove(i + 1);
         let left = &mut self[i];
         match (left, right) {
             (Node::Inner(lc), Node::Inner(rc)) => {
+                let (lc, rc) = (Arc::make_mut(lc), Arc::make_mut(rc));
                 lc.keys.push(parent_key);
                 lc.keys.append(&mut rc.keys);
                 lc.nodes.append(&mut rc.nodes);
+                lc.count += rc.count;
             }
-            (Node::Leaf(lv), Node::Leaf(rv)) => lv.append(rv),
+            (Node::Leaf(lv), Node::Leaf(rv)) => Arc::make_mut(lv).append(&mut Arc::make_mut(rv).0),
             (left, right) => panic!("Can't merge {:?} and {:?}", left, right),
         }
     }
 
+    /// Recomputes the key count from the child nodes, e.g. after splitting the child set.
+    fn recount(&mut self) {
+        self.count = self.nodes.iter().map(Node::len).sum();
+    }
+
+    /// Rebalances the children after a range deletion, which can leave children underflowed by
+    /// more than a single item, or even empty. Empty children are removed, and underflowed
+    /// children are merged with or rotated from a sibling until they're at least half full. The
+    /// underflowed children of these are then rebalanced recursively. Children without siblings
+    /// can't be rebalanced, and are left for the parent to handle.
+    fn rebalance(&mut self) {
+        let mut i = 0;
+        while i < self.len() {
+            if self[i].size() == 0 {
+                self.remove(i);
+                if !self.keys.is_empty() {
+                    self.keys.remove(i.saturating_sub(1));
+                }
+            } else {
+                i += 1;
+            }
+        }
+
+        let mut i = 0;
+        while i < self.len() {
+            let (size, order) = (self[i].size(), self[i].order());
+            if size >= order.div_ceil(2) || self.len() == 1 {
+                i += 1;
+                continue;
+            }
+
+            // Merge with a sibling if possible, otherwise rotate an item from it. Since the child
+            // underflows and they don't fit in a single node, the sibling has items to spare.
+            // Either way, check the child again in the next iteration.
+            let sibling = if i > 0 { i - 1 } else { i + 1 };
+            if size + self[sibling].size() <= order {
+                i = i.min(sibling);
+                self.merge(i);
+            } else if sibling < i {
+                self.rotate_right(sibling);
+            } else {
+                self.rotate_left(sibling);
+            }
+            if let Node::Inner(children) = &mut self[i] {
+                Arc::make_mut(children).rebalance();
+            }
+        }
+    }
+
     /// Rotates children to the left, by transferring items from the node at the given index to
     /// its left sibling and adjusting the separator key.
     fn rotate_left(&mut self, i: usize) {
         if matches!(self[i], Node::Inner(_)) {
             let (key, node) = match &mut self[i] {
-                Node::Inner(c) => (c.keys.remove(0), c.nodes.remove(0)),
+                Node::Inner(c) => {
+                    let c = Arc::make_mut(c);
+                    let node = c.nodes.remove(0);
+                    c.count -= node.len();
+                    (c.keys.remove(0), node)
+                }
                 n => panic!("Left rotation from unexpected node {:?}", n),
             };
             let key = replace(&mut self.keys[i - 1], key); // rotate separator key
             match &mut self[i - 1] {
                 Node::Inner(c) => {
+                    let c = Arc::make_mut(c);
                     c.keys.push(key);
+                    c.count += node.len();
                     c.nodes.push(node);
                 }
                 n => panic!("Left rotation into unexpected node {:?}", n),
             }
         } else if matches!(self[i], Node::Leaf(_)) {
             let (sep_key, (key, value)) = match &mut self[i] {
-                Node::Leaf(v) => (v[1].0.clone(), v.remove(0)),
+                Node::Leaf(v) => (v[1].0.clone(), Arc::make_mut(v).remove(0)),
                 n => panic!("Left rotation from unexpected node {:?}", n),
             };
             self.keys[i - 1] = sep_key;
             match &mut self[i - 1] {
-                Node::Leaf(v) => v.push((key, value)),
+                Node::Leaf(v) => Arc::make_mut(v).push((key, value)),
                 n => panic!("Left rotation into unexpected node {:?}", n),
             }
         } else {
@@ -383,25 +1038,32 @@ impl Children {
     fn rotate_right(&mut self, i: usize) {
         if matches!(self[i], Node::Inner(_)) {
             let (key, node) = match &mut self[i] {
-                Node::Inner(c) => (c.keys.pop().unwrap(), c.nodes.pop().unwrap()),
+                Node::Inner(c) => {
+                    let c = Arc::make_mut(c);
+                    let node = c.nodes.pop().unwrap();
+                    c.count -= node.len();
+                    (c.keys.pop().unwrap(), node)
+                }
                 n => panic!("Right rotation from unexpected node {:?}", n),
             };
             let key = replace(&mut self.keys[i], key); // rotate separator key
             match &mut self[i + 1] {
                 Node::Inner(c) => {
+                    let c = Arc::make_mut(c);
                     c.keys.insert(0, key);
+                    c.count += node.len();
                     c.nodes.insert(0, node);
                 }
                 n => panic!("Right rotation into unexpected node {:?}", n),
             }
         } else if matches!(self[i], Node::Leaf(_)) {
             let (key, value) = match &mut self[i] {
-                Node::Leaf(v) => v.pop().unwrap(),
+                Node::Leaf(v) => Arc::make_mut(v).pop().unwrap(),
                 n => panic!("Right rotation from unexpected node {:?}", n),
             };
             self.keys[i] = key.clone(); // update separator key
             match &mut self[i + 1] {
-                Node::Leaf(v) => v.insert(0, (key, value)),
+                Node::Leaf(v) => Arc::make_mut(v).insert(0, (key, value)),
                 n => panic!("Right rotation into unexpected node {:?}", n),
             }
         } else {
@@ -409,21 +1071,27 @@ impl Children {
         }
     }
 
-    /// Sets a key to a value in the children, delegating to the child responsible. If the node
-    /// splits, returns the split key and new (right) node.
-    fn set(&mut self, key: &[u8], value: Vec<u8>) -> Option<(Vec<u8>, Children)> {
+    /// Sets a key to a value in the children, delegating to the child responsible, and returns the
+    /// replaced value if any. If the node splits, returns the split key and new (right) node.
+    fn set(&mut self, cmp: &dyn KeyComparator, key: &[u8], value: Vec<u8>) -> SetResult<Children> {
         // For empty child sets, just create a new leaf node for the key.
         if self.is_empty() {
             let mut values = Values::new(self.capacity());
             values.push((key.to_vec(), value));
-            self.push(Node::Leaf(values));
-            return None;
+            self.push(Node::Leaf(Arc::new(values)));
+            self.count += 1;
+            return (None, None);
         }
 
         // Find the child and insert the value into it. If the child splits, try to insert the
-        // new right node into this child set.
-        let (i, child) = self.lookup_mut(key);
-        if let Some((split_key, split_child)) = child.set(key, value) {
+        // new right node into this child set. Splits only happen for new keys, so there is no
+        // replaced value in that case.
+        let (i, child) = self.lookup_mut(cmp, key);
+        let (replaced, split) = child.set(cmp, key, value);
+        if replaced.is_none() {
+            self.count += 1;
+        }
+        if let Some((split_key, split_child)) = split {
             // The split child should be insert next to the original target.
             let insert_at = i + 1;
 
@@ -432,17 +1100,14 @@ impl Children {
             if self.len() < self.capacity() {
                 self.keys.insert(insert_at - 1, split_key.to_vec());
                 self.nodes.insert(insert_at, split_child);
-                return None;
+                return (None, None);
             }
 
             // If the set is full, we need to split it and return the right node. The split-off
             // right child node goes after the original target node. We split the node in the
-            // middle, but if we're inserting after the split point we move the split point by one
-            // to help balance splitting of odd-ordered nodes.
-            let mut split_at = self.len() / 2;
-            if insert_at >= split_at {
-                split_at += 1;
-            }
+            // middle, counting the new child, such that the halves differ by at most one child.
+            let size = self.len().div_ceil(2);
+            let split_at = if insert_at < size { size - 1 } else { size };
 
             // Split the existing children and keys into two parts. The left parts will now have an
             // equal number of keys and children, where the last key points to the first node in
@@ -457,7 +1122,7 @@ impl Children {
             // the child, they may end up in different halves in which case the split key will be
             // promoted to a split key and the extra key from the left half is moved to the right
             // half. Otherwise, the extra key from the left half becomes the split key.
-            let split_key = match insert_at.cmp(&self.nodes.len()) {
+            let split_key = match insert_at.cmp(&size) {
                 Ordering::Greater => {
                     rkeys.insert(insert_at - 1 - self.keys.len(), split_key);
                     rnodes.insert(insert_at - self.nodes.len(), split_child);
@@ -475,19 +1140,33 @@ impl Children {
                 }
             };
 
-            Some((split_key, Children { keys: rkeys, nodes: rnodes }))
+            trace!("Split inner node at key {:x?}", split_key);
+            let mut rchildren = Children { keys: rkeys, nodes: rnodes, count: 0 };
+            rchildren.recount();
+            self.recount();
+            (None, Some((split_key, rchildren)))
         } else {
-            None
+            (replaced, None)
         }
     }
 }
 
 /// Leaf node key/value pairs. The value set (leaf node) order determines the maximum number
 /// of key/value items, which is tracked via the internal vector capacity. Items are ordered by key,
-/// and looked up via linear search due to the low cardinality. Derefs to the inner vec.
+/// and looked up via binary search, since large orders may hold thousands of items. Derefs to the
+/// inner vec.
 #[derive(Debug, PartialEq)]
 struct Values(Vec<(Vec<u8>, Vec<u8>)>);
 
+impl Clone for Values {
+    /// Clones the value set, retaining the vector capacity since it determines the order.
+    fn clone(&self) -> Self {
+        let mut values = Self::new(self.capacity());
+        values.extend(self.iter().cloned());
+        values
+    }
+}
+
 impl Deref for Values {
     type Target = Vec<(Vec<u8>, Vec<u8>)>;
     fn deref(&self) -> &Self::Target {
@@ -507,192 +1186,329 @@ impl Values {
         Self(Vec::with_capacity(order))
     }
 
-    /// Deletes a key from the set, if it exists.
-    fn delete(&mut self, key: &[u8]) {
-        for (i, (k, _)) in self.iter().enumerate() {
-            match (&**k).cmp(key) {
-                Ordering::Greater => break,
-                Ordering::Equal => {
-                    self.remove(i);
-                    break;
-                }
-                Ordering::Less => {}
-            }
-        }
-    }
-
-    /// Fetches a value from the set, if the key exists.
-    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
-        self.iter()
-            .find_map(|(k, v)| match (&**k).cmp(key) {
-                Ordering::Greater => Some(None),
-                Ordering::Equal => Some(Some(v.to_vec())),
-                Ordering::Less => None,
-            })
-            .flatten()
+    /// Deletes a key from the set, if it exists, and returns its value.
+    fn delete(&mut self, cmp: &dyn KeyComparator, key: &[u8]) -> Option<Vec<u8>> {
+        self.search(cmp, key).ok().map(|i| self.remove(i).1)
     }
 
-    /// Fetches the first key/value pair from the set, if any.
-    fn get_first(&self) -> Option<(Vec<u8>, Vec<u8>)> {
-        self.0.first().cloned()
-    }
-
-    /// Fetches the last key/value pair from the set, if any.
-    fn get_last(&self) -> Option<(Vec<u8>, Vec<u8>)> {
-        self.0.last().cloned()
+    /// Deletes all keys in the given range, returning the number of keys and bytes deleted.
+    fn delete_range(&mut self, cmp: &dyn KeyComparator, range: &Range) -> Count {
+        let start = match range.start_bound() {
+            Bound::Included(k) => self.rank(cmp, k, false),
+            Bound::Excluded(k) => self.rank(cmp, k, true),
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(k) => self.rank(cmp, k, true),
+            Bound::Excluded(k) => self.rank(cmp, k, false),
+            Bound::Unbounded => self.len(),
+        };
+        self.drain(start..end.max(start)).map(|(key, value)| Count::of(&key, &value)).sum()
     }
 
-    /// Fetches the next value after the given key, if it exists.
-    fn get_next(&self, key: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
-        self.iter()
-            .find_map(|(k, v)| match (&**k).cmp(key) {
-                Ordering::Greater => Some(Some((k.to_vec(), v.to_vec()))),
-                Ordering::Equal => None,
-                Ordering::Less => None,
-            })
-            .flatten()
+    /// Fetches a value from the set, if the key exists.
+    fn get(&self, cmp: &dyn KeyComparator, key: &[u8]) -> Option<Vec<u8>> {
+        self.search(cmp, key).ok().map(|i| self[i].1.clone())
     }
 
-    /// Fetches the previous value before the given key, if it exists.
-    fn get_prev(&self, key: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
-        self.iter()
-            .rev()
-            .find_map(|(k, v)| match (&**k).cmp(key) {
-                Ordering::Less => Some(Some((k.to_vec(), v.to_vec()))),
-                Ordering::Equal => None,
-                Ordering::Greater => None,
-            })
-            .flatten()
+    /// Returns the number of keys less than the given key (or equal to it if inclusive).
+    fn rank(&self, cmp: &dyn KeyComparator, key: &[u8], inclusive: bool) -> usize {
+        if inclusive {
+            self.partition_point(|(k, _)| cmp.compare(k, key) != Ordering::Greater)
+        } else {
+            self.partition_point(|(k, _)| cmp.compare(k, key) == Ordering::Less)
+        }
     }
 
-    /// Sets a key to a value, inserting of updating it. If the value set is full, it is split
-    /// in the middle and the split key and right values are returned.
-    fn set(&mut self, key: &[u8], value: Vec<u8>) -> Option<(Vec<u8>, Values)> {
-        // Find position to insert at, or if the key already exists just update it.
-        let mut insert_at = self.len();
-        for (i, (k, v)) in self.iter_mut().enumerate() {
-            match (&**k).cmp(key) {
-                Ordering::Greater => {
-                    insert_at = i;
-                    break;
+    /// Searches for the given key, returning its index if found, or otherwise the index where it
+    /// would be inserted. The linear-search feature scans the values linearly instead, as a
+    /// baseline for benchmarks.
+    fn search(&self, cmp: &dyn KeyComparator, key: &[u8]) -> std::result::Result<usize, usize> {
+        if cfg!(feature = "linear-search") {
+            for (i, (k, _)) in self.iter().enumerate() {
+                match cmp.compare(k, key) {
+                    Ordering::Less => {}
+                    Ordering::Equal => return Ok(i),
+                    Ordering::Greater => return Err(i),
                 }
-                Ordering::Equal => {
-                    *v = value;
-                    return None;
-                }
-                Ordering::Less => {}
             }
+            return Err(self.len());
         }
+        self.binary_search_by(|(k, _)| cmp.compare(k, key))
+    }
+
+    /// Sets a key to a value, inserting of updating it, and returns the replaced value if any. If
+    /// the value set is full, it is split in the middle and the split key and right values are
+    /// returned.
+    fn set(&mut self, cmp: &dyn KeyComparator, key: &[u8], value: Vec<u8>) -> SetResult<Values> {
+        // Find position to insert at, or if the key already exists just update it.
+        let insert_at = match self.search(cmp, key) {
+            Ok(i) => return (Some(replace(&mut self[i].1, value)), None),
+            Err(i) => i,
+        };
 
         // If we have capacity, just insert the value.
         if self.len() < self.capacity() {
             self.insert(insert_at, (key.to_vec(), value));
-            return None;
+            return (None, None);
         }
 
-        // If we're full, split in the middle and return split key and right values. If inserting
-        // to the right of the split, move split by one to better balance odd-ordered nodes.
-        let mut split_at = self.len() / 2;
-        if insert_at >= split_at {
-            split_at += 1;
-        }
+        // If we're full, split in the middle and return split key and right values. The new value
+        // is counted when picking the split point, so the halves differ by at most one value, with
+        // the larger half on the right.
+        let size = self.len().div_ceil(2);
+        let split_at = if insert_at < size { size - 1 } else { size };
         let mut rvalues = Values::new(self.capacity());
         rvalues.extend(self.drain(split_at..));
-        if insert_at >= self.len() {
-            rvalues.insert(insert_at - self.len(), (key.to_vec(), value));
-        } else {
+        if insert_at < size {
             self.insert(insert_at, (key.to_vec(), value));
+        } else {
+            rvalues.insert(insert_at - size, (key.to_vec(), value));
         }
-        Some((rvalues[0].0.clone(), rvalues))
+
+        trace!("Split leaf node at key {:x?}", rvalues[0].0);
+        (None, Some((rvalues[0].0.clone(), rvalues)))
     }
 }
 
-/// A key range scan.
-/// FIXME This is O(log n), and should use the normal B+tree approach of storing pointers in
-/// the leaf nodes instead. See: https://github.com/erikgrinaker/toydb/issues/32
+/// A path from the root node to a leaf node, as the child sets of the root and inner nodes along
+/// the way and the index of the child taken in each.
+type Path = Vec<(Arc<Children>, usize)>;
+
+/// A position of a key/value pair within a leaf node, along with the path to the leaf node from the
+/// root node. It holds references to the nodes, which are immutable, so the position remains valid
+/// regardless of later writes to the tree, and can move to the sibling leaf nodes along the path.
+#[derive(Clone)]
+struct Position {
+    path: Path,
+    leaf: Arc<Values>,
+    index: usize,
+}
+
+impl Position {
+    /// Descends from the last child in the path to a leaf node, using the given function to pick
+    /// the index of the child to descend into, and returns the leaf node.
+    fn descend(path: &mut Path, pick: impl Fn(&Children) -> usize) -> Arc<Values> {
+        loop {
+            let (children, i) = path.last().expect("empty path");
+            match &children[*i] {
+                Node::Root(children) | Node::Inner(children) => {
+                    let children = children.clone();
+                    let i = pick(&children);
+                    path.push((children, i));
+                }
+                Node::Leaf(values) => return values.clone(),
+            }
+        }
+    }
+
+    /// Returns the key/value pair at the position.
+    fn get(&self) -> &(Vec<u8>, Vec<u8>) {
+        &self.leaf[self.index]
+    }
+
+    /// Moves to the next key/value pair, returning false if there is none. Once the leaf node is
+    /// exhausted, this moves up the path to the nearest node with a following child, and down to
+    /// the first leaf node below that child.
+    fn next(&mut self) -> bool {
+        if self.index + 1 < self.leaf.len() {
+            self.index += 1;
+            return true;
+        }
+        // Order 2 trees may have empty leaf nodes, which are skipped. If only empty leaf nodes
+        // follow, move back to the original position.
+        let mut moved = false;
+        while let Some(depth) = self.path.iter().rposition(|(children, i)| i + 1 < children.len()) {
+            self.path.truncate(depth + 1);
+            self.path[depth].1 += 1;
+            self.leaf = Self::descend(&mut self.path, |_| 0);
+            self.index = 0;
+            if !self.leaf.is_empty() {
+                return true;
+            }
+            moved = true;
+        }
+        if moved {
+            self.prev();
+        }
+        false
+    }
+
+    /// Moves to the previous key/value pair, returning false if there is none. Once the leaf node
+    /// is exhausted, this moves up the path to the nearest node with a preceding child, and down to
+    /// the last leaf node below that child.
+    fn prev(&mut self) -> bool {
+        if self.index > 0 {
+            self.index -= 1;
+            return true;
+        }
+        // Order 2 trees may have empty leaf nodes, which are skipped. If only empty leaf nodes
+        // precede, move back to the original position.
+        let mut moved = false;
+        while let Some(depth) = self.path.iter().rposition(|(_, i)| *i > 0) {
+            self.path.truncate(depth + 1);
+            self.path[depth].1 -= 1;
+            self.leaf = Self::descend(&mut self.path, |children| children.len() - 1);
+            if let Some(index) = self.leaf.len().checked_sub(1) {
+                self.index = index;
+                return true;
+            }
+            moved = true;
+        }
+        if moved {
+            self.next();
+        }
+        false
+    }
+}
+
+/// A key range scan over a snapshot of the tree. The iterator seeks from the snapshot root to the
+/// start and end of the range, and then moves the positions at either end along their paths.
 struct Iter {
-    /// The root node of the tree we're iterating across.
-    root: Arc<RwLock<Node>>,
+    /// The root node of the snapshot we're iterating across.
+    root: Arc<Node>,
     /// The range we're iterating over.
     range: Range,
-    /// The front cursor keeps track of the last returned value from the front.
-    front_cursor: Option<Vec<u8>>,
-    /// The back cursor keeps track of the last returned value from the back.
-    back_cursor: Option<Vec<u8>>,
+    /// The key comparator.
+    cmp: Arc<dyn KeyComparator>,
+    /// The position of the last returned value from the front.
+    front: Option<Position>,
+    /// The position of the last returned value from the back.
+    back: Option<Position>,
 }
 
 impl Iter {
     /// Creates a new iterator.
-    fn new(root: Arc<RwLock<Node>>, range: Range) -> Self {
-        Self { root, range, front_cursor: None, back_cursor: None }
+    fn new(root: Arc<Node>, range: Range, cmp: Arc<dyn KeyComparator>) -> Self {
+        Self { root, range, cmp, front: None, back: None }
     }
+}
 
-    // next() with error handling.
-    fn try_next(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
-        let root = self.root.read()?;
-        let next = match &self.front_cursor {
-            None => match &self.range.start {
-                Bound::Included(k) => {
-                    root.get(k).map(|v| (k.clone(), v)).or_else(|| root.get_next(k))
+impl Iterator for Iter {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let cmp = &*self.cmp;
+        match &mut self.front {
+            Some(front) => {
+                if !front.next() {
+                    return None;
                 }
-                Bound::Excluded(k) => root.get_next(k),
-                Bound::Unbounded => root.get_first(),
-            },
-            Some(k) => root.get_next(k),
-        };
-        if let Some((k, _)) = &next {
-            if !self.range.contains(k) {
-                return Ok(None);
             }
-            if let Some(bc) = &self.back_cursor {
-                if bc <= k {
-                    return Ok(None);
-                }
+            None => {
+                let bound = self.range.start_bound().map(|k| k.as_slice());
+                self.front = Some(self.root.seek(cmp, bound)?);
+            }
+        }
+        let (k, v) = self.front.as_ref()?.get();
+        if !self.range.contains_by(cmp, k) {
+            return None;
+        }
+        if let Some(back) = &self.back {
+            if cmp.compare(&back.get().0, k) != Ordering::Greater {
+                return None;
             }
-            self.front_cursor = Some(k.clone())
         }
-        Ok(next)
+        Some(Ok((k.clone(), v.clone())))
     }
+}
 
-    /// next_back() with error handling.
-    fn try_next_back(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
-        let root = self.root.read()?;
-        let prev = match &self.back_cursor {
-            None => match &self.range.end {
-                Bound::Included(k) => {
-                    root.get(k).map(|v| (k.clone(), v)).or_else(|| root.get_prev(k))
+impl DoubleEndedIterator for Iter {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let cmp = &*self.cmp;
+        match &mut self.back {
+            Some(back) => {
+                if !back.prev() {
+                    return None;
                 }
-                Bound::Excluded(k) => root.get_prev(k),
-                Bound::Unbounded => root.get_last(),
-            },
-            Some(k) => root.get_prev(k),
-        };
-        if let Some((k, _)) = &prev {
-            if !self.range.contains(k) {
-                return Ok(None);
             }
-            if let Some(fc) = &self.front_cursor {
-                if fc >= k {
-                    return Ok(None);
-                }
+            None => {
+                let bound = self.range.end_bound().map(|k| k.as_slice());
+                self.back = Some(self.root.seek_back(cmp, bound)?);
             }
-            self.back_cursor = Some(k.clone())
         }
-        Ok(prev)
+        let (k, v) = self.back.as_ref()?.get();
+        if !self.range.contains_by(cmp, k) {
+            return None;
+        }
+        if let Some(front) = &self.front {
+            if cmp.compare(&front.get().0, k) != Ordering::Less {
+                return None;
+            }
+        }
+        Some(Ok((k.clone(), v.clone())))
     }
 }
 
-impl Iterator for Iter {
-    type Item = Result<(Vec<u8>, Vec<u8>)>;
+/// A cursor over the tree. Seeks take a new snapshot of the tree, and the cursor then moves within
+/// that snapshot along the position's path, in the same way as an iterator.
+struct Cursor {
+    /// The current root of the tree, used to take snapshots when seeking.
+    tree: Arc<RwLock<Arc<Node>>>,
+    /// The key comparator.
+    cmp: Arc<dyn KeyComparator>,
+    /// The cursor position, if valid.
+    position: Option<Position>,
+}
 
-    fn next(&mut self) -> Option<Self::Item> {
-        self.try_next().transpose()
+impl Cursor {
+    /// Creates a new, unpositioned cursor.
+    fn new(tree: Arc<RwLock<Arc<Node>>>, cmp: Arc<dyn KeyComparator>) -> Self {
+        Self { tree, cmp, position: None }
+    }
+
+    /// Takes a new snapshot of the tree and positions the cursor using the given function.
+    fn seek_with(
+        &mut self,
+        f: impl FnOnce(&Node, &dyn KeyComparator) -> Option<Position>,
+    ) -> Result<()> {
+        let root = self.tree.read()?.clone();
+        self.position = f(&root, &*self.cmp);
+        Ok(())
     }
 }
 
-impl DoubleEndedIterator for Iter {
-    fn next_back(&mut self) -> Option<Self::Item> {
-        self.try_next_back().transpose()
+impl super::Cursor for Cursor {
+    fn seek(&mut self, key: &[u8]) -> Result<()> {
+        self.seek_with(|root, cmp| root.seek(cmp, Bound::Included(key)))
+    }
+
+    fn seek_for_prev(&mut self, key: &[u8]) -> Result<()> {
+        self.seek_with(|root, cmp| root.seek_back(cmp, Bound::Included(key)))
+    }
+
+    fn seek_to_first(&mut self) -> Result<()> {
+        self.seek_with(|root, cmp| root.seek(cmp, Bound::Unbounded))
+    }
+
+    fn seek_to_last(&mut self) -> Result<()> {
+        self.seek_with(|root, cmp| root.seek_back(cmp, Bound::Unbounded))
+    }
+
+    fn next(&mut self) -> Result<()> {
+        if self.position.as_mut().is_some_and(|position| !position.next()) {
+            self.position = None;
+        }
+        Ok(())
+    }
+
+    fn prev(&mut self) -> Result<()> {
+        if self.position.as_mut().is_some_and(|position| !position.prev()) {
+            self.position = None;
+        }
+        Ok(())
+    }
+
+    fn valid(&self) -> bool {
+        self.position.is_some()
+    }
+
+    fn key(&self) -> Option<&[u8]> {
+        self.position.as_ref().map(|p| p.get().0.as_slice())
+    }
+
+    fn value(&self) -> Option<&[u8]> {
+        self.position.as_ref().map(|p| p.get().1.as_slice())
     }
 }
 
@@ -702,8 +1518,8 @@ mod tests {
     use pretty_assertions::assert_eq;
 
     impl super::super::TestSuite<Memory> for Memory {
-        fn setup() -> Result<Self> {
-            Ok(Memory::new())
+        fn setup() -> Result<(Self, Option<tempdir::TempDir>)> {
+            Ok((Memory::new(), None))
         }
     }
 
@@ -713,260 +1529,770 @@ mod tests {
         Memory::test()
     }
 
+    /// Creates a leaf node with the given items.
+    fn leaf(items: Vec<(Vec<u8>, Vec<u8>)>) -> Node {
+        Node::Leaf(Arc::new(Values(items)))
+    }
+
+    /// Creates a vector with the given capacity, which determines the order of nodes.
+    fn with_capacity<T>(capacity: usize, items: Vec<T>) -> Vec<T> {
+        let mut vec = Vec::with_capacity(capacity);
+        vec.extend(items);
+        vec
+    }
+
+    /// Creates a child set with the given keys and nodes, counting the keys in the nodes.
+    fn children(keys: Vec<Vec<u8>>, nodes: Vec<Node>) -> Children {
+        let count = nodes.iter().map(Node::len).sum();
+        Children { keys, nodes, count }
+    }
+
+    /// Creates a store with the given root node.
+    fn memory(root: Node) -> Memory {
+        Memory {
+            count: root.count(),
+            root: Arc::new(RwLock::new(Arc::new(root))),
+            comparator: Arc::new(BytewiseComparator),
+        }
+    }
+
+    #[test]
+    fn check() -> Result<()> {
+        use rand::Rng;
+        let mut rng: rand::rngs::StdRng = rand::SeedableRng::seed_from_u64(397_427_893);
+
+        // Random writes should maintain the invariants for any order. Order 2 trees may end up
+        // with empty leaves below single-child inner nodes, so only check their contents.
+        for order in 2..=8 {
+            let mut m = Memory::new_with_order(order)?;
+            let mut expect = std::collections::BTreeMap::new();
+            m.check()?;
+            for _ in 0..500 {
+                let key = vec![rng.gen_range(0..64)];
+                if rng.gen_bool(0.6) {
+                    m.set(&key, vec![])?;
+                    expect.insert(key, vec![]);
+                } else {
+                    m.delete(&key)?;
+                    expect.remove(&key);
+                }
+                if order > 2 {
+                    m.check()?;
+                }
+            }
+            let mut expect: Vec<_> = expect.into_iter().collect();
+            assert_eq!(expect, m.scan(Range::from(..)).collect::<Result<Vec<_>>>()?);
+            expect.reverse();
+            assert_eq!(expect, m.scan(Range::from(..)).rev().collect::<Result<Vec<_>>>()?);
+        }
+
+        // Build a valid tree of order 3, and then check various violations.
+        let leaf = |items: &[(Vec<u8>, Vec<u8>)]| leaf(with_capacity(3, items.to_vec()));
+        let root = |keys: Vec<Vec<u8>>, nodes| {
+            Node::Root(Arc::new(children(with_capacity(2, keys), with_capacity(3, nodes))))
+        };
+        let inner = |keys: Vec<Vec<u8>>, nodes| {
+            Node::Inner(Arc::new(children(with_capacity(2, keys), with_capacity(3, nodes))))
+        };
+        let tree = |leaves: [&[(Vec<u8>, Vec<u8>)]; 4], keys: [u8; 2]| {
+            root(
+                vec![vec![6]],
+                vec![
+                    inner(vec![vec![keys[0]]], vec![leaf(leaves[0]), leaf(leaves[1])]),
+                    inner(vec![vec![keys[1]]], vec![leaf(leaves[2]), leaf(leaves[3])]),
+                ],
+            )
+        };
+        let check = |root: Node| match memory(root).check() {
+            Err(Error::Internal(msg)) => msg,
+            r => panic!("expected check error, got {:?}", r),
+        };
+
+        let items: Vec<_> = (0..10_u8).map(|i| (vec![i], vec![])).collect();
+        let (a, b, c, d) = (&items[0..3], &items[3..6], &items[6..8], &items[8..]);
+        memory(tree([a, b, c, d], [3, 8])).check()?;
+
+        let unsorted = [b[1].clone(), b[0].clone(), b[2].clone()];
+        assert_eq!(
+            "Invalid node at path [0, 1]: unsorted key [3]",
+            check(tree([a, &unsorted, c, d], [3, 8]))
+        );
+        assert_eq!(
+            "Invalid node at path [0, 0]: key [2] out of bounds",
+            check(tree([a, b, c, d], [2, 8]))
+        );
+        assert_eq!(
+            "Invalid node at path [1]: separator key [5] out of bounds",
+            check(tree([a, b, c, d], [3, 5]))
+        );
+        assert_eq!(
+            "Invalid node at path [1, 1]: 1 items below minimum 2",
+            check(tree([a, b, &items[6..9], &items[9..]], [3, 9]))
+        );
+        assert_eq!(
+            "Invalid node at path []: leaf nodes at depths 1 and 0",
+            check(root(
+                vec![vec![6]],
+                vec![inner(vec![vec![3]], vec![leaf(a), leaf(b)]), leaf(&items[6..9])],
+            ))
+        );
+        assert_eq!(
+            "Invalid node at path []: single inner child of root node",
+            check(root(vec![], vec![inner(vec![vec![3]], vec![leaf(a), leaf(b)])]))
+        );
+        assert_eq!(
+            "Invalid node at path [0]: order 4 differs from tree order 3",
+            check(root(vec![], vec![Node::Leaf(Arc::new(Values(with_capacity(4, a.to_vec()))))]))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn comparator() -> Result<()> {
+        use rand::Rng;
+        let mut rng: rand::rngs::StdRng = rand::SeedableRng::seed_from_u64(397_427_893);
+
+        // Little-endian integer keys, whose byte order differs from their numeric order.
+        let numeric = |a: &[u8], b: &[u8]| {
+            let int = |k: &[u8]| u64::from_le_bytes(k.try_into().unwrap());
+            int(a).cmp(&int(b))
+        };
+        let key = |i: u64| i.to_le_bytes().to_vec();
+
+        // Random writes should keep the numeric order for lookups, scans, cursors and range
+        // deletes, and maintain the tree invariants under the comparator.
+        for order in 3..=8 {
+            let mut m = Memory::new_with_comparator(order, numeric)?;
+            let mut expect = std::collections::BTreeMap::new();
+            for _ in 0..1000 {
+                let i = rng.gen_range(0..1024);
+                match rng.gen_range(0..10) {
+                    0..=5 => {
+                        m.set(&key(i), vec![i as u8])?;
+                        expect.insert(i, vec![i as u8]);
+                    }
+                    6..=8 => {
+                        m.delete(&key(i))?;
+                        expect.remove(&i);
+                    }
+                    _ => {
+                        assert_eq!(
+                            expect.range(i..i + 16).count(),
+                            m.delete_range(Range::from(key(i)..key(i + 16)))?
+                        );
+                        expect.retain(|k, _| !(i..i + 16).contains(k));
+                    }
+                }
+            }
+            m.check()?;
+
+            let items: Vec<_> = expect.iter().map(|(k, v)| (key(*k), v.clone())).collect();
+            assert_eq!(items, m.scan(Range::from(..)).collect::<Result<Vec<_>>>()?);
+            assert_eq!(
+                items.iter().rev().cloned().collect::<Vec<_>>(),
+                m.scan(Range::from(..)).rev().collect::<Result<Vec<_>>>()?
+            );
+            for i in (0..1040).step_by(7) {
+                assert_eq!(expect.get(&i).cloned(), m.get(&key(i))?);
+                assert_eq!(expect.range(..i).count(), m.rank(&key(i))?);
+
+                let range = Range::from(key(i)..=key(i + 100));
+                let scan: Vec<_> =
+                    expect.range(i..=i + 100).map(|(k, v)| (key(*k), v.clone())).collect();
+                assert_eq!(scan, m.scan(range.clone()).collect::<Result<Vec<_>>>()?);
+                assert_eq!(scan.len(), m.count(range)?);
+
+                let mut cursor = m.cursor();
+                cursor.seek(&key(i))?;
+                let next = expect.range(i..).next().map(|(k, _)| key(*k));
+                assert_eq!(next.as_deref(), cursor.key());
+                cursor.seek_for_prev(&key(i))?;
+                let prev = expect.range(..=i).next_back().map(|(k, _)| key(*k));
+                assert_eq!(prev.as_deref(), cursor.key());
+            }
+        }
+
+        // Bulk loads must be sorted by the comparator, here in reverse byte order.
+        let reverse = |a: &[u8], b: &[u8]| b.cmp(a);
+        let items: Vec<_> = (0..100_u8).rev().map(|i| (vec![i], vec![i])).collect();
+        let m = Memory::load_with_comparator(4, reverse, 1.0, items.clone())?;
+        m.check()?;
+        assert_eq!(items, m.scan(Range::from(..)).collect::<Result<Vec<_>>>()?);
+        assert_eq!(
+            items[10..=20].to_vec(),
+            m.scan(Range::from(vec![89]..=vec![79])).collect::<Result<Vec<_>>>()?
+        );
+        assert!(Memory::load(4, 1.0, items.clone()).is_err());
+        assert!(Memory::load_with_comparator(4, reverse, 1.0, items.into_iter().rev()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn delete_range() -> Result<()> {
+        use rand::Rng;
+        let mut rng: rand::rngs::StdRng = rand::SeedableRng::seed_from_u64(397_427_893);
+
+        // Delete random ranges from trees of various orders and sizes, checking the invariants and
+        // contents after each deletion.
+        for order in 3..=8 {
+            for count in [10, 100, 1000] {
+                let mut m = Memory::new_with_order(order)?;
+                let mut expect = std::collections::BTreeMap::new();
+                for i in 0..count as u16 {
+                    m.set(&i.to_be_bytes(), vec![])?;
+                    expect.insert(i.to_be_bytes().to_vec(), vec![]);
+                }
+                while !expect.is_empty() {
+                    let start = rng.gen_range(0..count as u16).to_be_bytes().to_vec();
+                    let end = rng.gen_range(0..count as u16).to_be_bytes().to_vec();
+                    let range = match rng.gen_range(0..4) {
+                        0 => Range::from(start.clone()..),
+                        1 => Range::from(..=end.clone()),
+                        _ if start <= end => Range::from(start.clone()..end.clone()),
+                        _ => Range::from(end.clone()..start.clone()),
+                    };
+                    let deleted: Vec<_> =
+                        expect.keys().filter(|k| range.contains(k)).cloned().collect();
+                    for key in &deleted {
+                        expect.remove(key);
+                    }
+                    assert_eq!(deleted.len(), m.delete_range(range)?);
+                    m.check()?;
+                    assert_eq!(
+                        expect.clone().into_iter().collect::<Vec<_>>(),
+                        m.scan(Range::from(..)).collect::<Result<Vec<_>>>()?
+                    );
+                }
+                assert_eq!(Node::Root(Arc::new(Children::new(order))), **m.root.read()?);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn load() -> Result<()> {
+        // A small tree with a full fill factor should pack the leaves, evening out the last ones.
+        let items: Vec<_> = (0..10_u8).map(|i| (vec![i], vec![i])).collect();
+        let m = Memory::load(3, 1.0, items.clone())?;
+        assert_eq!(
+            Node::Root(Arc::new(children(
+                vec![vec![6]],
+                vec![
+                    Node::Inner(Arc::new(children(
+                        vec![vec![3]],
+                        vec![leaf(items[0..3].to_vec()), leaf(items[3..6].to_vec())],
+                    ))),
+                    Node::Inner(Arc::new(children(
+                        vec![vec![8]],
+                        vec![leaf(items[6..8].to_vec()), leaf(items[8..10].to_vec())],
+                    ))),
+                ],
+            ))),
+            **m.root.read()?
+        );
+        m.check()?;
+
+        // Loaded trees should contain the items, and support further writes, across orders and
+        // fill factors.
+        for order in [2, 3, 8, 31] {
+            for fill in [0.1, 0.5, 0.7, 1.0] {
+                for count in [0, 1, order, order + 1, 1000] {
+                    let items: Vec<_> =
+                        (0..count as u64).map(|i| (i.to_be_bytes().to_vec(), vec![])).collect();
+                    let mut m = Memory::load(order, fill, items.clone())?;
+                    m.check()?;
+                    assert_eq!(items, m.scan(Range::from(..)).collect::<Result<Vec<_>>>()?);
+                    for (key, _) in items.iter().step_by(2) {
+                        m.delete(key)?;
+                    }
+                    m.set(b"x", vec![])?;
+                    m.check()?;
+                    let mut expect: Vec<_> = items.iter().skip(1).step_by(2).cloned().collect();
+                    expect.push((b"x".to_vec(), vec![]));
+                    assert_eq!(expect, m.scan(Range::from(..)).collect::<Result<Vec<_>>>()?);
+                }
+            }
+        }
+
+        // Unsorted and duplicate keys, or invalid fill factors, should error.
+        let unsorted = vec![(vec![1], vec![]), (vec![3], vec![]), (vec![2], vec![])];
+        assert!(matches!(Memory::load(8, 1.0, unsorted), Err(Error::Value(_))));
+        let duplicate = vec![(vec![1], vec![]), (vec![1], vec![])];
+        assert!(matches!(Memory::load(8, 1.0, duplicate), Err(Error::Value(_))));
+        assert!(matches!(Memory::load(8, 0.0, vec![]), Err(Error::Config(_))));
+        assert!(matches!(Memory::load(8, 1.5, vec![]), Err(Error::Config(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn order_statistics() -> Result<()> {
+        use rand::Rng;
+        let mut rng: rand::rngs::StdRng = rand::SeedableRng::seed_from_u64(397_427_893);
+
+        let m = Memory::new();
+        assert_eq!(None, m.nth(0)?);
+        assert_eq!(0, m.rank(b"a")?);
+        assert_eq!(0, m.count(Range::from(..))?);
+
+        // Random writes, range deletes and bulk loads should keep the subtree counts correct
+        // through splits, merges and rotations. Keys are even numbers, so odd numbers are
+        // missing keys.
+        for order in 3..=8 {
+            let mut m = Memory::new_with_order(order)?;
+            let mut expect = std::collections::BTreeMap::new();
+            for i in 0..2000 {
+                let key = (rng.gen_range(0..256_u16) * 2).to_be_bytes().to_vec();
+                match rng.gen_range(0..10) {
+                    0..=5 => {
+                        m.set(&key, vec![])?;
+                        expect.insert(key, vec![]);
+                    }
+                    6..=8 => {
+                        m.delete(&key)?;
+                        expect.remove(&key);
+                    }
+                    _ => {
+                        let end = (u16::from_be_bytes([key[0], key[1]]) + 16).to_be_bytes();
+                        let range = Range::from(key..end.to_vec());
+                        expect.retain(|k, _| !range.contains(k));
+                        m.delete_range(range)?;
+                    }
+                }
+                if i % 100 == 0 {
+                    m = Memory::load(order, rng.gen_range(0.5..=1.0), expect.clone())?;
+                }
+            }
+            m.check()?;
+
+            let items: Vec<_> = expect.into_iter().collect();
+            for (n, item) in items.iter().enumerate() {
+                assert_eq!(Some(item.clone()), m.nth(n)?);
+                assert_eq!(n, m.rank(&item.0)?);
+            }
+            assert_eq!(None, m.nth(items.len())?);
+            for k in 0..520_u16 {
+                let key = k.to_be_bytes().to_vec();
+                let rank = items.partition_point(|(ik, _)| ik < &key);
+                assert_eq!(rank, m.rank(&key)?);
+                let end = (k + 37).to_be_bytes().to_vec();
+                let count = items.iter().filter(|(ik, _)| ik >= &key && ik <= &end).count();
+                assert_eq!(count, m.count(Range::from(key.clone()..=end.clone()))?);
+                let count = items.iter().filter(|(ik, _)| ik > &key && ik < &end).count();
+                assert_eq!(
+                    count,
+                    m.count(Range::from((Bound::Excluded(key.clone()), Bound::Excluded(end))))?
+                );
+                assert_eq!(items.len() - rank, m.count(Range::from(key.clone()..))?);
+                assert_eq!(0, m.count(Range::from(key.clone()..key))?);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn scan_snapshot() -> Result<()> {
+        let mut m = Memory::new_with_order(3)?;
+        for i in 0..20_u8 {
+            m.set(&[i * 2], vec![i])?;
+        }
+
+        // Scans run against a snapshot of the tree, so writes between steps aren't visible.
+        let mut iter = m.scan(Range::from(..));
+        assert_eq!(Some((vec![0], vec![0])), iter.next().transpose()?);
+        assert_eq!(Some((vec![38], vec![19])), iter.next_back().transpose()?);
+        m.set(&[1], vec![0xff])?;
+        m.delete(&[2])?;
+        m.set(&[37], vec![0xff])?;
+        for i in 4..18_u8 {
+            m.delete(&[i * 2])?;
+        }
+        let mut batch = WriteBatch::new();
+        batch.set(&[3], vec![0xff]).delete(&[36]);
+        m.write(batch)?;
+        assert_eq!(
+            (1..19_u8).map(|i| (vec![i * 2], vec![i])).collect::<Vec<_>>(),
+            iter.collect::<Result<Vec<_>>>()?
+        );
+
+        // A new scan sees the writes.
+        assert_eq!(
+            vec![
+                (vec![0], vec![0]),
+                (vec![1], vec![0xff]),
+                (vec![3], vec![0xff]),
+                (vec![4], vec![2]),
+                (vec![6], vec![3]),
+                (vec![37], vec![0xff]),
+                (vec![38], vec![19]),
+            ],
+            m.scan(Range::from(..)).collect::<Result<Vec<_>>>()?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn scan_path() -> Result<()> {
+        let mut m = Memory::new_with_order(3)?;
+        for i in 0..50_u8 {
+            m.set(&[i], vec![i])?;
+        }
+        let expect: Vec<_> = (0..50_u8).map(|i| (vec![i], vec![i])).collect();
+
+        // Once a scan has sought to either end of the range, it moves between leaf nodes along its
+        // paths from the root. Replacing its root with an empty one has no effect.
+        let empty = Arc::new(Node::Root(Arc::new(Children::empty())));
+        let mut iter = Iter::new(m.root.read()?.clone(), Range::from(..), m.comparator.clone());
+        let mut items = vec![iter.next().transpose()?.unwrap()];
+        iter.root = empty.clone();
+        items.extend(iter.by_ref().collect::<Result<Vec<_>>>()?);
+        assert_eq!(expect, items);
+
+        let mut iter = Iter::new(m.root.read()?.clone(), Range::from(..), m.comparator.clone());
+        let mut items = vec![iter.next_back().transpose()?.unwrap()];
+        iter.root = empty;
+        items.extend(iter.rev().collect::<Result<Vec<_>>>()?);
+        items.reverse();
+        assert_eq!(expect, items);
+        Ok(())
+    }
+
+    #[test]
+    fn snapshot() -> Result<()> {
+        use rand::Rng;
+        let mut rng: rand::rngs::StdRng = rand::SeedableRng::seed_from_u64(397_427_893);
+        let mut m = Memory::new_with_order(3)?;
+
+        // Take a snapshot after every random write, and check that none of the snapshots change
+        // as later writes are made to the store and to the snapshots themselves.
+        let mut snapshots = Vec::new();
+        for i in 0..300_u64 {
+            let key = rng.gen_range(0..100_u64).to_be_bytes();
+            if rng.gen_bool(0.7) {
+                m.set(&key, i.to_be_bytes().to_vec())?;
+            } else {
+                m.delete(&key)?;
+            }
+            let snapshot = m.snapshot()?;
+            let items = snapshot.scan(Range::from(..)).collect::<Result<Vec<_>>>()?;
+            snapshots.push((snapshot, items));
+        }
+        for (snapshot, items) in snapshots.iter() {
+            assert_eq!(items, &snapshot.scan(Range::from(..)).collect::<Result<Vec<_>>>()?);
+        }
+        for (snapshot, items) in snapshots.iter_mut() {
+            snapshot.set(&[0xff], vec![0xff])?;
+            snapshot.delete(&items[0].0)?;
+        }
+        for (snapshot, items) in snapshots.iter() {
+            let mut expect = items[1..].to_vec();
+            expect.push((vec![0xff], vec![0xff]));
+            assert_eq!(expect, snapshot.scan(Range::from(..)).collect::<Result<Vec<_>>>()?);
+        }
+        assert_eq!(
+            snapshots.last().unwrap().1,
+            m.scan(Range::from(..)).collect::<Result<Vec<_>>>()?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn stats() -> Result<()> {
+        let mut m = Memory::new_with_order(3)?;
+        assert_eq!(
+            Stats { keys: 0, bytes: 0, height: 1, nodes: vec![1], fill: 0.0, utilization: 0.0 },
+            m.stats()?
+        );
+        assert!(m.is_empty());
+
+        // Counts track inserts, updates and deletes.
+        m.set(b"a", vec![0x01])?;
+        m.set(b"b", vec![0x02, 0x03])?;
+        m.set(b"a", vec![])?;
+        m.delete(b"x")?;
+        assert_eq!((2, 4), (m.len(), m.bytes()));
+        m.delete(b"b")?;
+        assert_eq!((1, 1), (m.len(), m.bytes()));
+        m.write({
+            let mut batch = WriteBatch::new();
+            batch.set(b"c", vec![0x03]).set(b"a", vec![0x01]).delete(b"c");
+            batch
+        })?;
+        assert_eq!((1, 2), (m.len(), m.bytes()));
+        m.check()?;
+
+        // A loaded tree with 10 items at order 3 has 4 leaves, of which 2 are full.
+        let items: Vec<_> = (0..10_u8).map(|i| (vec![i], vec![i])).collect();
+        let mut m = Memory::load(3, 1.0, items)?;
+        let stats = m.stats()?;
+        assert_eq!((10, 20, 3, vec![1, 2, 4]), (m.len(), m.bytes(), stats.height, stats.nodes));
+        assert!((stats.fill - 14.0 / 18.0).abs() < 1e-9);
+        assert!((stats.utilization - 10.0 / 12.0).abs() < 1e-9);
+
+        assert_eq!(4, m.delete_range(Range::from(vec![2]..vec![6]))?);
+        assert_eq!((6, 12), (m.len(), m.bytes()));
+        let snapshot = m.snapshot()?;
+        m.delete_range(Range::from(..))?;
+        assert_eq!((0, 0), (m.len(), m.bytes()));
+        assert_eq!((6, 12), (snapshot.len(), snapshot.bytes()));
+        m.check()?;
+        snapshot.check()?;
+        Ok(())
+    }
+
     #[test]
     fn set_split() -> Result<()> {
         // Create a root of order 3
-        let mut root = Node::Root(Children::new(3));
+        let mut root = Node::Root(Arc::new(Children::new(3)));
 
         // A new root should be empty
-        assert_eq!(Node::Root(Children { keys: vec![], nodes: vec![] }), root);
+        assert_eq!(Node::Root(Arc::new(children(vec![], vec![]))), root);
 
         // Setting the first three values should create a leaf node and fill it
-        root.set(b"a", vec![0x01]);
-        root.set(b"b", vec![0x02]);
-        root.set(b"c", vec![0x03]);
+        root.set(&BytewiseComparator, b"a", vec![0x01]);
+        root.set(&BytewiseComparator, b"b", vec![0x02]);
+        root.set(&BytewiseComparator, b"c", vec![0x03]);
 
         assert_eq!(
-            Node::Root(Children {
-                keys: vec![],
-                nodes: vec![Node::Leaf(Values(vec![
+            Node::Root(Arc::new(children(
+                vec![],
+                vec![leaf(vec![
                     (b"a".to_vec(), vec![0x01]),
                     (b"b".to_vec(), vec![0x02]),
                     (b"c".to_vec(), vec![0x03]),
-                ]),)],
-            }),
+                ])],
+            ))),
             root
         );
 
         // Updating a node should not cause splitting
-        root.set(b"b", vec![0x20]);
+        root.set(&BytewiseComparator, b"b", vec![0x20]);
 
         assert_eq!(
-            Node::Root(Children {
-                keys: vec![],
-                nodes: vec![Node::Leaf(Values(vec![
+            Node::Root(Arc::new(children(
+                vec![],
+                vec![leaf(vec![
                     (b"a".to_vec(), vec![0x01]),
                     (b"b".to_vec(), vec![0x20]),
                     (b"c".to_vec(), vec![0x03]),
-                ]))],
-            }),
+                ])],
+            ))),
             root
         );
 
         // Setting an additional value should split the leaf node
-        root.set(b"b", vec![0x02]);
-        root.set(b"d", vec![0x04]);
+        root.set(&BytewiseComparator, b"b", vec![0x02]);
+        root.set(&BytewiseComparator, b"d", vec![0x04]);
 
         assert_eq!(
-            Node::Root(Children {
-                keys: vec![b"c".to_vec()],
-                nodes: vec![
-                    Node::Leaf(Values(vec![
+            Node::Root(Arc::new(children(
+                vec![b"c".to_vec()],
+                vec![
+                    leaf(vec![
                         (b"a".to_vec(), vec![0x01]),
                         (b"b".to_vec(), vec![0x02]),
-                    ])),
-                    Node::Leaf(Values(vec![
+                    ]),
+                    leaf(vec![
                         (b"c".to_vec(), vec![0x03]),
                         (b"d".to_vec(), vec![0x04]),
-                    ])),
+                    ]),
                 ],
-            }),
+            ))),
             root
         );
 
         // Adding two more values at the end should split the second leaf
-        root.set(b"z", vec![0x1a]);
-        root.set(b"y", vec![0x19]);
+        root.set(&BytewiseComparator, b"z", vec![0x1a]);
+        root.set(&BytewiseComparator, b"y", vec![0x19]);
 
         assert_eq!(
-            Node::Root(Children {
-                keys: vec![b"c".to_vec(), b"y".to_vec()],
-                nodes: vec![
-                    Node::Leaf(Values(vec![
+            Node::Root(Arc::new(children(
+                vec![b"c".to_vec(), b"y".to_vec()],
+                vec![
+                    leaf(vec![
                         (b"a".to_vec(), vec![0x01]),
                         (b"b".to_vec(), vec![0x02]),
-                    ])),
-                    Node::Leaf(Values(vec![
+                    ]),
+                    leaf(vec![
                         (b"c".to_vec(), vec![0x03]),
                         (b"d".to_vec(), vec![0x04]),
-                    ])),
-                    Node::Leaf(Values(vec![
+                    ]),
+                    leaf(vec![
                         (b"y".to_vec(), vec![0x19]),
                         (b"z".to_vec(), vec![0x1a]),
-                    ])),
+                    ]),
                 ],
-            }),
+            ))),
             root
         );
 
         // Adding two more values from the end should split the middle leaf. This will cause the
         // root node to overflow and split as well.
-        root.set(b"x", vec![0x18]);
-        root.set(b"w", vec![0x17]);
+        root.set(&BytewiseComparator, b"x", vec![0x18]);
+        root.set(&BytewiseComparator, b"w", vec![0x17]);
 
         assert_eq!(
-            Node::Root(Children {
-                keys: vec![b"w".to_vec()],
-                nodes: vec![
-                    Node::Inner(Children {
-                        keys: vec![b"c".to_vec()],
-                        nodes: vec![
-                            Node::Leaf(Values(vec![
+            Node::Root(Arc::new(children(
+                vec![b"w".to_vec()],
+                vec![
+                    Node::Inner(Arc::new(children(
+                        vec![b"c".to_vec()],
+                        vec![
+                            leaf(vec![
                                 (b"a".to_vec(), vec![0x01]),
                                 (b"b".to_vec(), vec![0x02]),
-                            ])),
-                            Node::Leaf(Values(vec![
+                            ]),
+                            leaf(vec![
                                 (b"c".to_vec(), vec![0x03]),
                                 (b"d".to_vec(), vec![0x04]),
-                            ])),
+                            ]),
                         ],
-                    }),
-                    Node::Inner(Children {
-                        keys: vec![b"y".to_vec()],
-                        nodes: vec![
-                            Node::Leaf(Values(vec![
+                    ))),
+                    Node::Inner(Arc::new(children(
+                        vec![b"y".to_vec()],
+                        vec![
+                            leaf(vec![
                                 (b"w".to_vec(), vec![0x17]),
                                 (b"x".to_vec(), vec![0x18]),
-                            ])),
-                            Node::Leaf(Values(vec![
+                            ]),
+                            leaf(vec![
                                 (b"y".to_vec(), vec![0x19]),
                                 (b"z".to_vec(), vec![0x1a]),
-                            ])),
+                            ]),
                         ],
-                    })
+                    )))
                 ],
-            }),
+            ))),
             root
         );
 
         // Adding further values should cause the first inner node to finally split as well.
-        root.set(b"e", vec![0x05]);
-        root.set(b"f", vec![0x06]);
-        root.set(b"g", vec![0x07]);
-        root.set(b"h", vec![0x08]);
+        root.set(&BytewiseComparator, b"e", vec![0x05]);
+        root.set(&BytewiseComparator, b"f", vec![0x06]);
+        root.set(&BytewiseComparator, b"g", vec![0x07]);
+        root.set(&BytewiseComparator, b"h", vec![0x08]);
 
         assert_eq!(
-            Node::Root(Children {
-                keys: vec![b"e".to_vec(), b"w".to_vec()],
-                nodes: vec![
-                    Node::Inner(Children {
-                        keys: vec![b"c".to_vec()],
-                        nodes: vec![
-                            Node::Leaf(Values(vec![
+            Node::Root(Arc::new(children(
+                vec![b"e".to_vec(), b"w".to_vec()],
+                vec![
+                    Node::Inner(Arc::new(children(
+                        vec![b"c".to_vec()],
+                        vec![
+                            leaf(vec![
                                 (b"a".to_vec(), vec![0x01]),
                                 (b"b".to_vec(), vec![0x02]),
-                            ])),
-                            Node::Leaf(Values(vec![
+                            ]),
+                            leaf(vec![
                                 (b"c".to_vec(), vec![0x03]),
                                 (b"d".to_vec(), vec![0x04]),
-                            ])),
+                            ]),
                         ],
-                    }),
-                    Node::Inner(Children {
-                        keys: vec![b"g".to_vec()],
-                        nodes: vec![
-                            Node::Leaf(Values(vec![
+                    ))),
+                    Node::Inner(Arc::new(children(
+                        vec![b"g".to_vec()],
+                        vec![
+                            leaf(vec![
                                 (b"e".to_vec(), vec![0x05]),
                                 (b"f".to_vec(), vec![0x06]),
-                            ])),
-                            Node::Leaf(Values(vec![
+                            ]),
+                            leaf(vec![
                                 (b"g".to_vec(), vec![0x07]),
                                 (b"h".to_vec(), vec![0x08]),
-                            ])),
+                            ]),
                         ],
-                    }),
-                    Node::Inner(Children {
-                        keys: vec![b"y".to_vec()],
-                        nodes: vec![
-                            Node::Leaf(Values(vec![
+                    ))),
+                    Node::Inner(Arc::new(children(
+                        vec![b"y".to_vec()],
+                        vec![
+                            leaf(vec![
                                 (b"w".to_vec(), vec![0x17]),
                                 (b"x".to_vec(), vec![0x18]),
-                            ])),
-                            Node::Leaf(Values(vec![
+                            ]),
+                            leaf(vec![
                                 (b"y".to_vec(), vec![0x19]),
                                 (b"z".to_vec(), vec![0x1a]),
-                            ])),
+                            ]),
                         ],
-                    })
+                    )))
                 ],
-            }),
+            ))),
             root
         );
 
         // Adding yet more from the back, but in forward order, should cause another root node split.
-        root.set(b"s", vec![0x13]);
-        root.set(b"t", vec![0x14]);
-        root.set(b"u", vec![0x15]);
-        root.set(b"v", vec![0x16]);
+        root.set(&BytewiseComparator, b"s", vec![0x13]);
+        root.set(&BytewiseComparator, b"t", vec![0x14]);
+        root.set(&BytewiseComparator, b"u", vec![0x15]);
+        root.set(&BytewiseComparator, b"v", vec![0x16]);
 
         assert_eq!(
-            Node::Root(Children {
-                keys: vec![b"s".to_vec()],
-                nodes: vec![
-                    Node::Inner(Children {
-                        keys: vec![b"e".to_vec()],
-                        nodes: vec![
-                            Node::Inner(Children {
-                                keys: vec![b"c".to_vec()],
-                                nodes: vec![
-                                    Node::Leaf(Values(vec![
+            Node::Root(Arc::new(children(
+                vec![b"s".to_vec()],
+                vec![
+                    Node::Inner(Arc::new(children(
+                        vec![b"e".to_vec()],
+                        vec![
+                            Node::Inner(Arc::new(children(
+                                vec![b"c".to_vec()],
+                                vec![
+                                    leaf(vec![
                                         (b"a".to_vec(), vec![0x01]),
                                         (b"b".to_vec(), vec![0x02]),
-                                    ])),
-                                    Node::Leaf(Values(vec![
+                                    ]),
+                                    leaf(vec![
                                         (b"c".to_vec(), vec![0x03]),
                                         (b"d".to_vec(), vec![0x04]),
-                                    ]))
+                                    ])
                                 ],
-                            }),
-                            Node::Inner(Children {
-                                keys: vec![b"g".to_vec()],
-                                nodes: vec![
-                                    Node::Leaf(Values(vec![
+                            ))),
+                            Node::Inner(Arc::new(children(
+                                vec![b"g".to_vec()],
+                                vec![
+                                    leaf(vec![
                                         (b"e".to_vec(), vec![0x05]),
                                         (b"f".to_vec(), vec![0x06]),
-                                    ])),
-                                    Node::Leaf(Values(vec![
+                                    ]),
+                                    leaf(vec![
                                         (b"g".to_vec(), vec![0x07]),
                                         (b"h".to_vec(), vec![0x08]),
-                                    ]))
+                                    ])
                                 ],
-                            }),
+                            ))),
                         ],
-                    }),
-                    Node::Inner(Children {
-                        keys: vec![b"w".to_vec()],
-                        nodes: vec![
-                            Node::Inner(Children {
-                                keys: vec![b"u".to_vec()],
-                                nodes: vec![
-                                    Node::Leaf(Values(vec![
+                    ))),
+                    Node::Inner(Arc::new(children(
+                        vec![b"w".to_vec()],
+                        vec![
+                            Node::Inner(Arc::new(children(
+                                vec![b"u".to_vec()],
+                                vec![
+                                    leaf(vec![
                                         (b"s".to_vec(), vec![0x13]),
                                         (b"t".to_vec(), vec![0x14]),
-                                    ])),
-                                    Node::Leaf(Values(vec![
+                                    ]),
+                                    leaf(vec![
                                         (b"u".to_vec(), vec![0x15]),
                                         (b"v".to_vec(), vec![0x16]),
-                                    ]))
+                                    ])
                                 ],
-                            }),
-                            Node::Inner(Children {
-                                keys: vec![b"y".to_vec()],
-                                nodes: vec![
-                                    Node::Leaf(Values(vec![
+                            ))),
+                            Node::Inner(Arc::new(children(
+                                vec![b"y".to_vec()],
+                                vec![
+                                    leaf(vec![
                                         (b"w".to_vec(), vec![0x17]),
                                         (b"x".to_vec(), vec![0x18]),
-                                    ])),
-                                    Node::Leaf(Values(vec![
+                                    ]),
+                                    leaf(vec![
                                         (b"y".to_vec(), vec![0x19]),
                                         (b"z".to_vec(), vec![0x1a]),
-                                    ]))
+                                    ])
                                 ],
-                            })
+                            )))
                         ]
-                    })
+                    )))
                 ],
-            }),
+            ))),
             root
         );
 
@@ -976,367 +2302,367 @@ mod tests {
     #[test]
     fn delete_merge() -> Result<()> {
         // Create a root of order 3, and add a bunch of values to it.
-        let mut root = Node::Root(Children::new(3));
-
-        root.set(b"a", vec![0x01]);
-        root.set(b"b", vec![0x02]);
-        root.set(b"c", vec![0x03]);
-        root.set(b"d", vec![0x04]);
-        root.set(b"e", vec![0x05]);
-        root.set(b"f", vec![0x06]);
-        root.set(b"g", vec![0x07]);
-        root.set(b"h", vec![0x08]);
-        root.set(b"i", vec![0x09]);
-        root.set(b"j", vec![0x0a]);
-        root.set(b"k", vec![0x0b]);
-        root.set(b"l", vec![0x0c]);
-        root.set(b"m", vec![0x0d]);
-        root.set(b"n", vec![0x0e]);
-        root.set(b"o", vec![0x0f]);
-        root.set(b"p", vec![0x10]);
+        let mut root = Node::Root(Arc::new(Children::new(3)));
+
+        root.set(&BytewiseComparator, b"a", vec![0x01]);
+        root.set(&BytewiseComparator, b"b", vec![0x02]);
+        root.set(&BytewiseComparator, b"c", vec![0x03]);
+        root.set(&BytewiseComparator, b"d", vec![0x04]);
+        root.set(&BytewiseComparator, b"e", vec![0x05]);
+        root.set(&BytewiseComparator, b"f", vec![0x06]);
+        root.set(&BytewiseComparator, b"g", vec![0x07]);
+        root.set(&BytewiseComparator, b"h", vec![0x08]);
+        root.set(&BytewiseComparator, b"i", vec![0x09]);
+        root.set(&BytewiseComparator, b"j", vec![0x0a]);
+        root.set(&BytewiseComparator, b"k", vec![0x0b]);
+        root.set(&BytewiseComparator, b"l", vec![0x0c]);
+        root.set(&BytewiseComparator, b"m", vec![0x0d]);
+        root.set(&BytewiseComparator, b"n", vec![0x0e]);
+        root.set(&BytewiseComparator, b"o", vec![0x0f]);
+        root.set(&BytewiseComparator, b"p", vec![0x10]);
 
         assert_eq!(
-            Node::Root(Children {
-                keys: vec![b"i".to_vec()],
-                nodes: vec![
-                    Node::Inner(Children {
-                        keys: vec![b"e".to_vec()],
-                        nodes: vec![
-                            Node::Inner(Children {
-                                keys: vec![b"c".to_vec()],
-                                nodes: vec![
-                                    Node::Leaf(Values(vec![
+            Node::Root(Arc::new(children(
+                vec![b"i".to_vec()],
+                vec![
+                    Node::Inner(Arc::new(children(
+                        vec![b"e".to_vec()],
+                        vec![
+                            Node::Inner(Arc::new(children(
+                                vec![b"c".to_vec()],
+                                vec![
+                                    leaf(vec![
                                         (b"a".to_vec(), vec![0x01]),
                                         (b"b".to_vec(), vec![0x02]),
-                                    ])),
-                                    Node::Leaf(Values(vec![
+                                    ]),
+                                    leaf(vec![
                                         (b"c".to_vec(), vec![0x03]),
                                         (b"d".to_vec(), vec![0x04]),
-                                    ])),
+                                    ]),
                                 ],
-                            }),
-                            Node::Inner(Children {
-                                keys: vec![b"g".to_vec()],
-                                nodes: vec![
-                                    Node::Leaf(Values(vec![
+                            ))),
+                            Node::Inner(Arc::new(children(
+                                vec![b"g".to_vec()],
+                                vec![
+                                    leaf(vec![
                                         (b"e".to_vec(), vec![0x05]),
                                         (b"f".to_vec(), vec![0x06]),
-                                    ])),
-                                    Node::Leaf(Values(vec![
+                                    ]),
+                                    leaf(vec![
                                         (b"g".to_vec(), vec![0x07]),
                                         (b"h".to_vec(), vec![0x08]),
-                                    ])),
+                                    ]),
                                 ],
-                            }),
+                            ))),
                         ],
-                    }),
-                    Node::Inner(Children {
-                        keys: vec![b"m".to_vec()],
-                        nodes: vec![
-                            Node::Inner(Children {
-                                keys: vec![b"k".to_vec()],
-                                nodes: vec![
-                                    Node::Leaf(Values(vec![
+                    ))),
+                    Node::Inner(Arc::new(children(
+                        vec![b"m".to_vec()],
+                        vec![
+                            Node::Inner(Arc::new(children(
+                                vec![b"k".to_vec()],
+                                vec![
+                                    leaf(vec![
                                         (b"i".to_vec(), vec![0x09]),
                                         (b"j".to_vec(), vec![0x0a]),
-                                    ])),
-                                    Node::Leaf(Values(vec![
+                                    ]),
+                                    leaf(vec![
                                         (b"k".to_vec(), vec![0x0b]),
                                         (b"l".to_vec(), vec![0x0c]),
-                                    ])),
+                                    ]),
                                 ],
-                            }),
-                            Node::Inner(Children {
-                                keys: vec![b"o".to_vec()],
-                                nodes: vec![
-                                    Node::Leaf(Values(vec![
+                            ))),
+                            Node::Inner(Arc::new(children(
+                                vec![b"o".to_vec()],
+                                vec![
+                                    leaf(vec![
                                         (b"m".to_vec(), vec![0x0d]),
                                         (b"n".to_vec(), vec![0x0e]),
-                                    ])),
-                                    Node::Leaf(Values(vec![
+                                    ]),
+                                    leaf(vec![
                                         (b"o".to_vec(), vec![0x0f]),
                                         (b"p".to_vec(), vec![0x10]),
-                                    ])),
+                                    ]),
                                 ],
-                            })
+                            )))
                         ]
-                    })
+                    )))
                 ],
-            }),
+            ))),
             root
         );
 
         // Deleting the o node merges two leaf nodes, in turn merging parents.
-        root.delete(b"o");
+        root.delete(&BytewiseComparator, b"o");
 
         assert_eq!(
-            Node::Root(Children {
-                keys: vec![b"e".to_vec(), b"i".to_vec()],
-                nodes: vec![
-                    Node::Inner(Children {
-                        keys: vec![b"c".to_vec()],
-                        nodes: vec![
-                            Node::Leaf(Values(vec![
+            Node::Root(Arc::new(children(
+                vec![b"e".to_vec(), b"i".to_vec()],
+                vec![
+                    Node::Inner(Arc::new(children(
+                        vec![b"c".to_vec()],
+                        vec![
+                            leaf(vec![
                                 (b"a".to_vec(), vec![0x01]),
                                 (b"b".to_vec(), vec![0x02]),
-                            ])),
-                            Node::Leaf(Values(vec![
+                            ]),
+                            leaf(vec![
                                 (b"c".to_vec(), vec![0x03]),
                                 (b"d".to_vec(), vec![0x04]),
-                            ])),
+                            ]),
                         ],
-                    }),
-                    Node::Inner(Children {
-                        keys: vec![b"g".to_vec()],
-                        nodes: vec![
-                            Node::Leaf(Values(vec![
+                    ))),
+                    Node::Inner(Arc::new(children(
+                        vec![b"g".to_vec()],
+                        vec![
+                            leaf(vec![
                                 (b"e".to_vec(), vec![0x05]),
                                 (b"f".to_vec(), vec![0x06]),
-                            ])),
-                            Node::Leaf(Values(vec![
+                            ]),
+                            leaf(vec![
                                 (b"g".to_vec(), vec![0x07]),
                                 (b"h".to_vec(), vec![0x08]),
-                            ])),
+                            ]),
                         ],
-                    }),
-                    Node::Inner(Children {
-                        keys: vec![b"k".to_vec(), b"m".to_vec()],
-                        nodes: vec![
-                            Node::Leaf(Values(vec![
+                    ))),
+                    Node::Inner(Arc::new(children(
+                        vec![b"k".to_vec(), b"m".to_vec()],
+                        vec![
+                            leaf(vec![
                                 (b"i".to_vec(), vec![0x09]),
                                 (b"j".to_vec(), vec![0x0a]),
-                            ])),
-                            Node::Leaf(Values(vec![
+                            ]),
+                            leaf(vec![
                                 (b"k".to_vec(), vec![0x0b]),
                                 (b"l".to_vec(), vec![0x0c]),
-                            ])),
-                            Node::Leaf(Values(vec![
+                            ]),
+                            leaf(vec![
                                 (b"m".to_vec(), vec![0x0d]),
                                 (b"n".to_vec(), vec![0x0e]),
                                 (b"p".to_vec(), vec![0x10]),
-                            ])),
+                            ]),
                         ],
-                    }),
+                    ))),
                 ],
-            }),
+            ))),
             root
         );
 
         // Deleting i causes another leaf merge
-        root.delete(b"i");
+        root.delete(&BytewiseComparator, b"i");
 
         assert_eq!(
-            Node::Root(Children {
-                keys: vec![b"e".to_vec(), b"i".to_vec()],
-                nodes: vec![
-                    Node::Inner(Children {
-                        keys: vec![b"c".to_vec()],
-                        nodes: vec![
-                            Node::Leaf(Values(vec![
+            Node::Root(Arc::new(children(
+                vec![b"e".to_vec(), b"i".to_vec()],
+                vec![
+                    Node::Inner(Arc::new(children(
+                        vec![b"c".to_vec()],
+                        vec![
+                            leaf(vec![
                                 (b"a".to_vec(), vec![0x01]),
                                 (b"b".to_vec(), vec![0x02]),
-                            ])),
-                            Node::Leaf(Values(vec![
+                            ]),
+                            leaf(vec![
                                 (b"c".to_vec(), vec![0x03]),
                                 (b"d".to_vec(), vec![0x04]),
-                            ])),
+                            ]),
                         ],
-                    }),
-                    Node::Inner(Children {
-                        keys: vec![b"g".to_vec()],
-                        nodes: vec![
-                            Node::Leaf(Values(vec![
+                    ))),
+                    Node::Inner(Arc::new(children(
+                        vec![b"g".to_vec()],
+                        vec![
+                            leaf(vec![
                                 (b"e".to_vec(), vec![0x05]),
                                 (b"f".to_vec(), vec![0x06]),
-                            ])),
-                            Node::Leaf(Values(vec![
+                            ]),
+                            leaf(vec![
                                 (b"g".to_vec(), vec![0x07]),
                                 (b"h".to_vec(), vec![0x08]),
-                            ])),
+                            ]),
                         ],
-                    }),
-                    Node::Inner(Children {
-                        keys: vec![b"m".to_vec()],
-                        nodes: vec![
-                            Node::Leaf(Values(vec![
+                    ))),
+                    Node::Inner(Arc::new(children(
+                        vec![b"m".to_vec()],
+                        vec![
+                            leaf(vec![
                                 (b"j".to_vec(), vec![0x0a]),
                                 (b"k".to_vec(), vec![0x0b]),
                                 (b"l".to_vec(), vec![0x0c]),
-                            ])),
-                            Node::Leaf(Values(vec![
+                            ]),
+                            leaf(vec![
                                 (b"m".to_vec(), vec![0x0d]),
                                 (b"n".to_vec(), vec![0x0e]),
                                 (b"p".to_vec(), vec![0x10]),
-                            ])),
+                            ]),
                         ],
-                    }),
+                    ))),
                 ],
-            }),
+            ))),
             root
         );
 
         // Clearing out j,k,l should cause another merge.
-        root.delete(b"j");
-        root.delete(b"l");
-        root.delete(b"k");
+        root.delete(&BytewiseComparator, b"j");
+        root.delete(&BytewiseComparator, b"l");
+        root.delete(&BytewiseComparator, b"k");
 
         assert_eq!(
-            Node::Root(Children {
-                keys: vec![b"e".to_vec()],
-                nodes: vec![
-                    Node::Inner(Children {
-                        keys: vec![b"c".to_vec()],
-                        nodes: vec![
-                            Node::Leaf(Values(vec![
+            Node::Root(Arc::new(children(
+                vec![b"e".to_vec()],
+                vec![
+                    Node::Inner(Arc::new(children(
+                        vec![b"c".to_vec()],
+                        vec![
+                            leaf(vec![
                                 (b"a".to_vec(), vec![0x01]),
                                 (b"b".to_vec(), vec![0x02]),
-                            ])),
-                            Node::Leaf(Values(vec![
+                            ]),
+                            leaf(vec![
                                 (b"c".to_vec(), vec![0x03]),
                                 (b"d".to_vec(), vec![0x04]),
-                            ])),
+                            ]),
                         ],
-                    }),
-                    Node::Inner(Children {
-                        keys: vec![b"g".to_vec(), b"i".to_vec()],
-                        nodes: vec![
-                            Node::Leaf(Values(vec![
+                    ))),
+                    Node::Inner(Arc::new(children(
+                        vec![b"g".to_vec(), b"i".to_vec()],
+                        vec![
+                            leaf(vec![
                                 (b"e".to_vec(), vec![0x05]),
                                 (b"f".to_vec(), vec![0x06]),
-                            ])),
-                            Node::Leaf(Values(vec![
+                            ]),
+                            leaf(vec![
                                 (b"g".to_vec(), vec![0x07]),
                                 (b"h".to_vec(), vec![0x08]),
-                            ])),
-                            Node::Leaf(Values(vec![
+                            ]),
+                            leaf(vec![
                                 (b"m".to_vec(), vec![0x0d]),
                                 (b"n".to_vec(), vec![0x0e]),
                                 (b"p".to_vec(), vec![0x10]),
-                            ])),
+                            ]),
                         ],
-                    }),
+                    ))),
                 ],
-            }),
+            ))),
             root
         );
 
         // Removing a should underflow a leaf node, triggering a rotation to rebalance the
         // underflowing inner node with its sibling.
-        root.delete(b"a");
+        root.delete(&BytewiseComparator, b"a");
 
         assert_eq!(
-            Node::Root(Children {
-                keys: vec![b"g".to_vec()],
-                nodes: vec![
-                    Node::Inner(Children {
-                        keys: vec![b"e".to_vec()],
-                        nodes: vec![
-                            Node::Leaf(Values(vec![
+            Node::Root(Arc::new(children(
+                vec![b"g".to_vec()],
+                vec![
+                    Node::Inner(Arc::new(children(
+                        vec![b"e".to_vec()],
+                        vec![
+                            leaf(vec![
                                 (b"b".to_vec(), vec![0x02]),
                                 (b"c".to_vec(), vec![0x03]),
                                 (b"d".to_vec(), vec![0x04]),
-                            ])),
-                            Node::Leaf(Values(vec![
+                            ]),
+                            leaf(vec![
                                 (b"e".to_vec(), vec![0x05]),
                                 (b"f".to_vec(), vec![0x06]),
-                            ])),
+                            ]),
                         ],
-                    }),
-                    Node::Inner(Children {
-                        keys: vec![b"i".to_vec()],
-                        nodes: vec![
-                            Node::Leaf(Values(vec![
+                    ))),
+                    Node::Inner(Arc::new(children(
+                        vec![b"i".to_vec()],
+                        vec![
+                            leaf(vec![
                                 (b"g".to_vec(), vec![0x07]),
                                 (b"h".to_vec(), vec![0x08]),
-                            ])),
-                            Node::Leaf(Values(vec![
+                            ]),
+                            leaf(vec![
                                 (b"m".to_vec(), vec![0x0d]),
                                 (b"n".to_vec(), vec![0x0e]),
                                 (b"p".to_vec(), vec![0x10]),
-                            ])),
+                            ]),
                         ],
-                    }),
+                    ))),
                 ],
-            }),
+            ))),
             root
         );
 
         // Removing h should rebalance the leaf nodes.
-        root.delete(b"h");
+        root.delete(&BytewiseComparator, b"h");
 
         assert_eq!(
-            Node::Root(Children {
-                keys: vec![b"g".to_vec()],
-                nodes: vec![
-                    Node::Inner(Children {
-                        keys: vec![b"e".to_vec()],
-                        nodes: vec![
-                            Node::Leaf(Values(vec![
+            Node::Root(Arc::new(children(
+                vec![b"g".to_vec()],
+                vec![
+                    Node::Inner(Arc::new(children(
+                        vec![b"e".to_vec()],
+                        vec![
+                            leaf(vec![
                                 (b"b".to_vec(), vec![0x02]),
                                 (b"c".to_vec(), vec![0x03]),
                                 (b"d".to_vec(), vec![0x04]),
-                            ])),
-                            Node::Leaf(Values(vec![
+                            ]),
+                            leaf(vec![
                                 (b"e".to_vec(), vec![0x05]),
                                 (b"f".to_vec(), vec![0x06]),
-                            ])),
+                            ]),
                         ],
-                    }),
-                    Node::Inner(Children {
-                        keys: vec![b"n".to_vec()],
-                        nodes: vec![
-                            Node::Leaf(Values(vec![
+                    ))),
+                    Node::Inner(Arc::new(children(
+                        vec![b"n".to_vec()],
+                        vec![
+                            leaf(vec![
                                 (b"g".to_vec(), vec![0x07]),
                                 (b"m".to_vec(), vec![0x0d]),
-                            ])),
-                            Node::Leaf(Values(vec![
+                            ]),
+                            leaf(vec![
                                 (b"n".to_vec(), vec![0x0e]),
                                 (b"p".to_vec(), vec![0x10]),
-                            ])),
+                            ]),
                         ],
-                    }),
+                    ))),
                 ],
-            }),
+            ))),
             root
         );
 
         // Removing n should rebalance the leaf nodes, and in turn merge the inner nodes.
-        root.delete(b"n");
+        root.delete(&BytewiseComparator, b"n");
 
         assert_eq!(
-            Node::Root(Children {
-                keys: vec![b"e".to_vec(), b"g".to_vec()],
-                nodes: vec![
-                    Node::Leaf(Values(vec![
+            Node::Root(Arc::new(children(
+                vec![b"e".to_vec(), b"g".to_vec()],
+                vec![
+                    leaf(vec![
                         (b"b".to_vec(), vec![0x02]),
                         (b"c".to_vec(), vec![0x03]),
                         (b"d".to_vec(), vec![0x04]),
-                    ])),
-                    Node::Leaf(Values(vec![
+                    ]),
+                    leaf(vec![
                         (b"e".to_vec(), vec![0x05]),
                         (b"f".to_vec(), vec![0x06]),
-                    ])),
-                    Node::Leaf(Values(vec![
+                    ]),
+                    leaf(vec![
                         (b"g".to_vec(), vec![0x07]),
                         (b"m".to_vec(), vec![0x0d]),
                         (b"p".to_vec(), vec![0x10]),
-                    ])),
+                    ]),
                 ],
-            }),
+            ))),
             root
         );
 
         // At this point we can remove the remaining keys, leaving an empty root node.
-        root.delete(b"d");
-        root.delete(b"p");
-        root.delete(b"g");
-        root.delete(b"c");
-        root.delete(b"f");
-        root.delete(b"m");
-        root.delete(b"b");
-        root.delete(b"e");
-
-        assert_eq!(Node::Root(Children { keys: vec![], nodes: vec![] }), root);
+        root.delete(&BytewiseComparator, b"d");
+        root.delete(&BytewiseComparator, b"p");
+        root.delete(&BytewiseComparator, b"g");
+        root.delete(&BytewiseComparator, b"c");
+        root.delete(&BytewiseComparator, b"f");
+        root.delete(&BytewiseComparator, b"m");
+        root.delete(&BytewiseComparator, b"b");
+        root.delete(&BytewiseComparator, b"e");
+
+        assert_eq!(Node::Root(Arc::new(children(vec![], vec![]))), root);
 
         Ok(())
     }
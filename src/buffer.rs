@@ -0,0 +1,561 @@
+use super::pager::{PageId, Pager};
+use crate::error::{Error, Result};
+
+use log::{error, trace};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+
+/// The default buffer pool size, in pages.
+pub const DEFAULT_CACHE_SIZE: usize = 1024;
+
+/// A buffer pool page eviction policy.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Eviction {
+    /// CLOCK (second chance): pages are kept in a circular buffer along with a reference bit that
+    /// is set when the page is accessed. To evict a page, the clock hand sweeps the buffer,
+    /// clearing reference bits, until it finds a page without one. This approximates LRU, but is
+    /// cheaper per access.
+    Clock,
+    /// Least recently used: evicts the page that was accessed least recently.
+    Lru,
+    /// 2Q: new pages enter a FIFO queue, and are evicted from it before pages that have been
+    /// accessed repeatedly. Pages are only promoted to the main LRU queue if they are read again
+    /// shortly after being evicted from the FIFO queue. This keeps one-off accesses such as long
+    /// scans from evicting frequently used pages.
+    TwoQueue,
+}
+
+/// Buffer pool statistics.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CacheStats {
+    /// The number of page reads served from the pool.
+    pub hits: u64,
+    /// The number of page reads that had to read the page from the file.
+    pub misses: u64,
+    /// The number of pages evicted from the pool.
+    pub evictions: u64,
+    /// The number of pages currently in the pool.
+    pub pages: usize,
+    /// The number of modified pages in the pool that have not been written back to the file.
+    pub dirty: usize,
+}
+
+/// A bounded in-memory cache of pages in a paged file. Pages are read into the pool on demand and
+/// pinned while in use, and pages written to the pool are kept as dirty pages until they are
+/// either evicted or flushed, at which point they are written back to the file. When the pool is
+/// full, an unpinned page is picked for eviction by the configured eviction policy.
+///
+/// The pool owns the pager, and the file metadata (i.e. the root page ID) bypasses the pool.
+pub struct BufferPool {
+    /// The underlying pager.
+    pager: Pager,
+    /// The maximum number of pages in the pool.
+    size: usize,
+    /// The cached pages and eviction state, guarded by a mutex to allow reads via a shared
+    /// reference.
+    state: Mutex<State>,
+}
+
+/// The mutable state of a buffer pool.
+struct State {
+    /// The cached pages.
+    frames: HashMap<PageId, Frame>,
+    /// The eviction policy, which tracks all cached pages.
+    policy: Box<dyn Policy>,
+    /// Pool statistics. The page counts are filled in on demand.
+    stats: CacheStats,
+}
+
+/// A cached page.
+struct Frame {
+    /// The page body.
+    data: Arc<Vec<u8>>,
+    /// The number of pins on the page. Pinned pages can't be evicted.
+    pins: usize,
+    /// Whether the page has been modified since it was read from or written to the file.
+    dirty: bool,
+}
+
+impl BufferPool {
+    /// Creates a new buffer pool for the given pager, holding at most size pages.
+    pub fn new(pager: Pager, size: usize, eviction: Eviction) -> Result<Self> {
+        if size == 0 {
+            return Err(Error::Config("Buffer pool must hold at least one page".into()));
+        }
+        let policy: Box<dyn Policy> = match eviction {
+            Eviction::Clock => Box::new(Clock::default()),
+            Eviction::Lru => Box::new(Lru::default()),
+            Eviction::TwoQueue => Box::new(TwoQueue::new(size)),
+        };
+        let state = State { frames: HashMap::new(), policy, stats: CacheStats::default() };
+        Ok(Self { pager, size, state: Mutex::new(state) })
+    }
+
+    /// Allocates a new page, returning its ID. Free pages are reused first.
+    pub fn allocate(&mut self) -> Result<PageId> {
+        self.pager.allocate()
+    }
+
+    /// Returns the maximum size of a page body.
+    pub fn capacity(&self) -> usize {
+        self.pager.capacity()
+    }
+
+    /// Returns true if the file was created in copy-on-write mode.
+    pub fn copy_on_write(&self) -> bool {
+        self.pager.copy_on_write()
+    }
+
+    /// Writes all dirty pages back to the file, and syncs the file to durable storage.
+    pub fn flush(&mut self) -> Result<()> {
+        let mut state = self.state.lock()?;
+        let mut dirty: Vec<_> = state.frames.iter_mut().filter(|(_, f)| f.dirty).collect();
+        dirty.sort_by_key(|(id, _)| **id);
+        for (id, frame) in dirty {
+            self.pager.write(*id, &frame.data)?;
+            frame.dirty = false;
+        }
+        drop(state);
+        self.pager.sync()
+    }
+
+    /// Frees a page, such that it can be reused by allocate(). If the page is cached, it is no
+    /// longer written back to the file, and its contents are replaced when it is reused.
+    pub fn free(&mut self, id: PageId) -> Result<()> {
+        if let Some(frame) = self.state.lock()?.frames.get_mut(&id) {
+            frame.dirty = false;
+        }
+        self.pager.free(id)
+    }
+
+    /// Returns the number of free pages in the file.
+    pub fn free_count(&self) -> u64 {
+        self.pager.free_count()
+    }
+
+    /// Returns the number of pages in the file, including the metadata page.
+    pub fn page_count(&self) -> u64 {
+        self.pager.page_count()
+    }
+
+    /// Reads the body of a page, reading it into the pool if necessary. The page is pinned in the
+    /// pool until the returned page is dropped. Errors if the page must be read into the pool but
+    /// all pages in the pool are pinned.
+    pub fn read(&self, id: PageId) -> Result<Page<'_>> {
+        let mut guard = self.state.lock()?;
+        let state = &mut *guard;
+        let data = match state.frames.get_mut(&id) {
+            Some(frame) => {
+                frame.pins += 1;
+                state.stats.hits += 1;
+                state.policy.access(id);
+                frame.data.clone()
+            }
+            None => {
+                state.stats.misses += 1;
+                let data = Arc::new(self.pager.read(id)?);
+                self.insert(state, id, Frame { data: data.clone(), pins: 1, dirty: false })?;
+                data
+            }
+        };
+        Ok(Page { pool: self, id, data })
+    }
+
+    /// Returns the root page ID.
+    pub fn root(&self) -> PageId {
+        self.pager.root()
+    }
+
+    /// Sets the copy-on-write mode flag. It is written to the file on the next flush.
+    pub fn set_copy_on_write(&mut self, copy_on_write: bool) {
+        self.pager.set_copy_on_write(copy_on_write)
+    }
+
+    /// Sets the root page ID. It is written to the file on the next flush.
+    pub fn set_root(&mut self, root: PageId) {
+        self.pager.set_root(root)
+    }
+
+    /// Returns buffer pool statistics.
+    pub fn stats(&self) -> Result<CacheStats> {
+        let state = self.state.lock()?;
+        Ok(CacheStats {
+            pages: state.frames.len(),
+            dirty: state.frames.values().filter(|f| f.dirty).count(),
+            ..state.stats
+        })
+    }
+
+    /// Writes the body of a page into the pool, marking it as dirty. It is written back to the
+    /// file when it is evicted or flushed.
+    pub fn write(&mut self, id: PageId, body: &[u8]) -> Result<()> {
+        self.pager.check_write(id, body.len())?;
+        let mut guard = self.state.lock()?;
+        let state = &mut *guard;
+        let data = Arc::new(body.to_vec());
+        match state.frames.get_mut(&id) {
+            Some(frame) => {
+                frame.data = data;
+                frame.dirty = true;
+                state.policy.access(id);
+                Ok(())
+            }
+            None => self.insert(state, id, Frame { data, pins: 0, dirty: true }),
+        }
+    }
+
+    /// Inserts a page into the pool, evicting pages as necessary to make room for it.
+    fn insert(&self, state: &mut State, id: PageId, frame: Frame) -> Result<()> {
+        while state.frames.len() >= self.size {
+            let frames = &state.frames;
+            let victim =
+                state.policy.evict(&|id| frames.get(&id).is_some_and(|f| f.pins > 0)).ok_or_else(
+                    || Error::Internal(format!("All {} buffer pool pages are pinned", self.size)),
+                )?;
+            if let Some(frame) = state.frames.get(&victim).filter(|f| f.dirty) {
+                self.pager.write(victim, &frame.data)?;
+            }
+            state.frames.remove(&victim);
+            state.stats.evictions += 1;
+            trace!("Evicted page {} from buffer pool", victim);
+        }
+        state.frames.insert(id, frame);
+        state.policy.insert(id);
+        Ok(())
+    }
+
+    /// Unpins a page.
+    fn unpin(&self, id: PageId) {
+        let mut state = self.state.lock().unwrap_or_else(|err| err.into_inner());
+        if let Some(frame) = state.frames.get_mut(&id) {
+            frame.pins -= 1;
+        }
+    }
+}
+
+impl Drop for BufferPool {
+    /// Writes back dirty pages when the pool is dropped, since there is no way to return errors.
+    fn drop(&mut self) {
+        if let Err(err) = self.flush() {
+            error!("Failed to flush buffer pool: {}", err);
+        }
+    }
+}
+
+/// A page pinned in a buffer pool. The page is unpinned when dropped.
+pub struct Page<'a> {
+    /// The buffer pool.
+    pool: &'a BufferPool,
+    /// The page ID.
+    id: PageId,
+    /// The page body.
+    data: Arc<Vec<u8>>,
+}
+
+impl Deref for Page<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl Drop for Page<'_> {
+    fn drop(&mut self) {
+        self.pool.unpin(self.id)
+    }
+}
+
+/// A page eviction policy, which tracks the pages in a buffer pool and picks pages to evict.
+trait Policy: Send {
+    /// Records an access to a page in the pool.
+    fn access(&mut self, id: PageId);
+
+    /// Picks an unpinned page to evict, and stops tracking it. Returns None if all pages are
+    /// pinned.
+    fn evict(&mut self, pinned: &dyn Fn(PageId) -> bool) -> Option<PageId>;
+
+    /// Records a new page in the pool.
+    fn insert(&mut self, id: PageId);
+}
+
+/// The LRU eviction policy. Pages are ordered by a logical access timestamp.
+#[derive(Default)]
+struct Lru {
+    /// The current logical time, incremented on every access.
+    clock: u64,
+    /// The last access time of each page.
+    accessed: HashMap<PageId, u64>,
+    /// Pages by last access time.
+    order: BTreeMap<u64, PageId>,
+}
+
+impl Lru {
+    /// Returns the number of tracked pages.
+    fn len(&self) -> usize {
+        self.order.len()
+    }
+}
+
+impl Policy for Lru {
+    fn access(&mut self, id: PageId) {
+        if let Some(time) = self.accessed.get_mut(&id) {
+            self.order.remove(time);
+            self.clock += 1;
+            *time = self.clock;
+            self.order.insert(self.clock, id);
+        }
+    }
+
+    fn evict(&mut self, pinned: &dyn Fn(PageId) -> bool) -> Option<PageId> {
+        let (&time, &id) = self.order.iter().find(|(_, id)| !pinned(**id))?;
+        self.order.remove(&time);
+        self.accessed.remove(&id);
+        Some(id)
+    }
+
+    fn insert(&mut self, id: PageId) {
+        self.clock += 1;
+        self.accessed.insert(id, self.clock);
+        self.order.insert(self.clock, id);
+    }
+}
+
+/// The CLOCK eviction policy.
+#[derive(Default)]
+struct Clock {
+    /// The circular buffer of pages and their reference bits. Evicted pages leave empty slots,
+    /// which are reused by new pages.
+    ring: Vec<Option<(PageId, bool)>>,
+    /// The ring slot of each page.
+    slots: HashMap<PageId, usize>,
+    /// Empty ring slots.
+    free: Vec<usize>,
+    /// The clock hand, i.e. the next ring slot to consider for eviction.
+    hand: usize,
+}
+
+impl Policy for Clock {
+    fn access(&mut self, id: PageId) {
+        if let Some(&slot) = self.slots.get(&id) {
+            self.ring[slot] = Some((id, true));
+        }
+    }
+
+    fn evict(&mut self, pinned: &dyn Fn(PageId) -> bool) -> Option<PageId> {
+        // Two sweeps are enough to clear all reference bits and then find an unpinned page.
+        for _ in 0..2 * self.ring.len() {
+            let slot = self.hand;
+            self.hand = (self.hand + 1) % self.ring.len();
+            if let Some((id, referenced)) = self.ring[slot].as_mut() {
+                if pinned(*id) {
+                    continue;
+                } else if *referenced {
+                    *referenced = false;
+                    continue;
+                }
+                let id = *id;
+                self.ring[slot] = None;
+                self.slots.remove(&id);
+                self.free.push(slot);
+                return Some(id);
+            }
+        }
+        None
+    }
+
+    fn insert(&mut self, id: PageId) {
+        let slot = match self.free.pop() {
+            Some(slot) => slot,
+            None => {
+                self.ring.push(None);
+                self.ring.len() - 1
+            }
+        };
+        self.ring[slot] = Some((id, false));
+        self.slots.insert(id, slot);
+    }
+}
+
+/// The 2Q eviction policy, as described by Johnson and Shasha (1994).
+struct TwoQueue {
+    /// New pages in FIFO order (A1in).
+    recent: VecDeque<PageId>,
+    /// The number of new pages beyond which they are evicted before frequently used pages.
+    recent_size: usize,
+    /// Frequently used pages in LRU order (Am).
+    frequent: Lru,
+    /// Recently evicted new pages in FIFO order (A1out). Pages in this queue are promoted to the
+    /// frequent queue when read again.
+    ghosts: VecDeque<PageId>,
+    /// The set of pages in the ghost queue.
+    ghost_set: HashSet<PageId>,
+    /// The maximum size of the ghost queue.
+    ghost_size: usize,
+}
+
+impl TwoQueue {
+    /// Creates a new 2Q policy for a buffer pool of the given size. Uses the queue sizes
+    /// recommended by the paper: a quarter of the pool for new pages, and ghost entries for half.
+    fn new(size: usize) -> Self {
+        Self {
+            recent: VecDeque::new(),
+            recent_size: std::cmp::max(size / 4, 1),
+            frequent: Lru::default(),
+            ghosts: VecDeque::new(),
+            ghost_set: HashSet::new(),
+            ghost_size: std::cmp::max(size / 2, 1),
+        }
+    }
+
+    /// Evicts the oldest unpinned new page, remembering it in the ghost queue.
+    fn evict_recent(&mut self, pinned: &dyn Fn(PageId) -> bool) -> Option<PageId> {
+        let index = self.recent.iter().position(|id| !pinned(*id))?;
+        let id = self.recent.remove(index)?;
+        self.ghosts.push_back(id);
+        self.ghost_set.insert(id);
+        while self.ghosts.len() > self.ghost_size {
+            if let Some(ghost) = self.ghosts.pop_front() {
+                self.ghost_set.remove(&ghost);
+            }
+        }
+        Some(id)
+    }
+}
+
+impl Policy for TwoQueue {
+    fn access(&mut self, id: PageId) {
+        // Accesses to new pages are assumed to be correlated, and don't affect their order.
+        self.frequent.access(id)
+    }
+
+    fn evict(&mut self, pinned: &dyn Fn(PageId) -> bool) -> Option<PageId> {
+        if self.recent.len() > self.recent_size || self.frequent.len() == 0 {
+            if let Some(id) = self.evict_recent(pinned) {
+                return Some(id);
+            }
+        }
+        self.frequent.evict(pinned).or_else(|| self.evict_recent(pinned))
+    }
+
+    fn insert(&mut self, id: PageId) {
+        if self.ghost_set.remove(&id) {
+            self.ghosts.retain(|ghost| *ghost != id);
+            self.frequent.insert(id);
+        } else {
+            self.recent.push_back(id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    /// Creates a buffer pool of the given size over a new file with pages 1 to 8, each containing
+    /// its page ID.
+    fn setup(dir: &tempdir::TempDir, size: usize, eviction: Eviction) -> Result<BufferPool> {
+        let mut pager = Pager::open(&dir.path().join("kupier"), 512)?;
+        for id in 1..=8 {
+            assert_eq!(id, pager.allocate()?);
+            pager.write(id, &[id as u8])?;
+        }
+        BufferPool::new(pager, size, eviction)
+    }
+
+    /// Reads the given pages, checking their contents.
+    fn read(pool: &BufferPool, ids: &[PageId]) -> Result<()> {
+        for &id in ids {
+            assert_eq!(vec![id as u8], pool.read(id)?.to_vec());
+        }
+        Ok(())
+    }
+
+    /// Returns the IDs of the pages in the pool.
+    fn cached(pool: &BufferPool) -> Vec<PageId> {
+        let mut ids: Vec<_> = pool.state.lock().unwrap().frames.keys().copied().collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    #[test]
+    fn eviction() -> Result<()> {
+        #[allow(clippy::type_complexity)]
+        let cases: Vec<(Eviction, [Vec<PageId>; 3], (u64, u64))> = vec![
+            (Eviction::Lru, [vec![1, 3, 4, 5], vec![1, 6, 7, 8], vec![2, 6, 7, 8]], (2, 9)),
+            (Eviction::Clock, [vec![1, 3, 4, 5], vec![1, 6, 7, 8], vec![1, 2, 7, 8]], (2, 9)),
+            // The first page is evicted from the FIFO queue despite the second access, but is
+            // promoted to the LRU queue when read again, which keeps it cached during the scan.
+            (Eviction::TwoQueue, [vec![2, 3, 4, 5], vec![1, 6, 7, 8], vec![1, 2, 7, 8]], (1, 10)),
+        ];
+        for (eviction, expect, (hits, misses)) in cases {
+            let dir = tempdir::TempDir::new("kupier")?;
+            let pool = setup(&dir, 4, eviction)?;
+            read(&pool, &[1, 2, 3, 4, 1, 5])?;
+            assert_eq!(expect[0], cached(&pool), "{:?}", eviction);
+            read(&pool, &[1, 6, 7, 8])?;
+            assert_eq!(expect[1], cached(&pool), "{:?}", eviction);
+            read(&pool, &[2])?;
+            assert_eq!(expect[2], cached(&pool), "{:?}", eviction);
+            assert_eq!(
+                CacheStats { hits, misses, evictions: misses - 4, pages: 4, dirty: 0 },
+                pool.stats()?,
+                "{:?}",
+                eviction
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn pin() -> Result<()> {
+        let dir = tempdir::TempDir::new("kupier")?;
+        let pool = setup(&dir, 2, Eviction::Lru)?;
+
+        // Pinned pages can't be evicted, even if they are the least recently used.
+        let page = pool.read(1)?;
+        read(&pool, &[2, 3, 4])?;
+        assert_eq!(vec![1, 4], cached(&pool));
+        assert_eq!(vec![1], page.to_vec());
+
+        // If all pages are pinned, reads of other pages fail until a page is unpinned.
+        let other = pool.read(4)?;
+        assert!(matches!(pool.read(5), Err(Error::Internal(_))));
+        drop(page);
+        read(&pool, &[5])?;
+        assert_eq!(vec![4, 5], cached(&pool));
+        drop(other);
+        Ok(())
+    }
+
+    #[test]
+    fn write() -> Result<()> {
+        let dir = tempdir::TempDir::new("kupier")?;
+        let mut pool = setup(&dir, 2, Eviction::Lru)?;
+
+        // Writes are cached as dirty pages, and written back to the file when evicted.
+        pool.write(1, &[0x0a])?;
+        pool.write(2, &[0x0b])?;
+        assert_eq!(vec![0x0a], pool.read(1)?.to_vec());
+        assert_eq!(vec![1], pool.pager.read(1)?);
+        assert_eq!(2, pool.stats()?.dirty);
+
+        read(&pool, &[3])?;
+        assert_eq!(vec![1, 3], cached(&pool));
+        assert_eq!(vec![0x0b], pool.pager.read(2)?);
+        assert_eq!(vec![1], pool.pager.read(1)?);
+        assert_eq!(1, pool.stats()?.dirty);
+
+        // Flushing writes back all dirty pages, but keeps them cached.
+        pool.flush()?;
+        assert_eq!(vec![0x0a], pool.pager.read(1)?);
+        assert_eq!(vec![1, 3], cached(&pool));
+        assert_eq!(0, pool.stats()?.dirty);
+
+        // Writes are checked against the page capacity up front.
+        assert!(matches!(pool.write(1, &[0; 512]), Err(Error::Internal(_))));
+        Ok(())
+    }
+}
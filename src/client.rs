@@ -0,0 +1,185 @@
+use super::server::{read_frame, write_frame, Request, Response};
+use super::Range;
+use crate::error::{Error, Result};
+
+use futures::Stream;
+use tokio::io::{AsyncWriteExt, BufStream};
+use tokio::net::{TcpStream, ToSocketAddrs};
+
+/// A client for a key/value store served by Server. The client executes one request at a time,
+/// waiting for its response, while scan results are streamed from the server as they are read.
+pub struct Client {
+    /// The server connection.
+    conn: BufStream<TcpStream>,
+    /// Whether a scan response is still being received, i.e. a scan stream was dropped before
+    /// reaching the end. The remaining results are skipped before the next request.
+    scanning: bool,
+}
+
+impl Client {
+    /// Connects to a server at the given address.
+    pub async fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        Ok(Self { conn: BufStream::new(TcpStream::connect(addr).await?), scanning: false })
+    }
+
+    /// Deletes a key, or does nothing if it does not exist.
+    pub async fn delete(&mut self, key: &[u8]) -> Result<()> {
+        match self.call(Request::Delete(key.to_vec())).await? {
+            Response::Done => Ok(()),
+            response => Err(Self::unexpected(response)),
+        }
+    }
+
+    /// Flushes any buffered data in the server's store to the underlying storage medium.
+    pub async fn flush(&mut self) -> Result<()> {
+        match self.call(Request::Flush).await? {
+            Response::Done => Ok(()),
+            response => Err(Self::unexpected(response)),
+        }
+    }
+
+    /// Gets a value for a key, if it exists.
+    pub async fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.call(Request::Get(key.to_vec())).await? {
+            Response::Value(value) => Ok(value),
+            response => Err(Self::unexpected(response)),
+        }
+    }
+
+    /// Streams an ordered range of key/value pairs from the server. The stream borrows the client,
+    /// and if it is dropped early the remaining results are skipped before the next request.
+    pub async fn scan(
+        &mut self,
+        range: Range,
+    ) -> Result<impl Stream<Item = Result<(Vec<u8>, Vec<u8>)>> + '_> {
+        self.send(Request::Scan(range)).await?;
+        self.scanning = true;
+        Ok(futures::stream::try_unfold(self, |client| async move {
+            if !client.scanning {
+                return Ok(None);
+            }
+            match client.receive().await {
+                Ok(Response::ScanItem(key, value)) => Ok(Some(((key, value), client))),
+                Ok(Response::ScanEnd) => {
+                    client.scanning = false;
+                    Ok(None)
+                }
+                Ok(response) => {
+                    client.scanning = false;
+                    Err(Self::unexpected(response))
+                }
+                Err(err) => {
+                    client.scanning = false;
+                    Err(err)
+                }
+            }
+        }))
+    }
+
+    /// Sets a value for a key, replacing the existing value if any.
+    pub async fn set(&mut self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        match self.call(Request::Set(key.to_vec(), value)).await? {
+            Response::Done => Ok(()),
+            response => Err(Self::unexpected(response)),
+        }
+    }
+
+    /// Sends a request and receives its response.
+    async fn call(&mut self, request: Request) -> Result<Response> {
+        self.send(request).await?;
+        self.receive().await
+    }
+
+    /// Sends a request, after skipping the remaining results of any unfinished scan.
+    async fn send(&mut self, request: Request) -> Result<()> {
+        while self.scanning {
+            match self.receive().await {
+                Ok(Response::ScanItem(..)) => {}
+                Ok(_) | Err(_) => self.scanning = false,
+            }
+        }
+        write_frame(&mut self.conn, &request).await?;
+        self.conn.flush().await?;
+        Ok(())
+    }
+
+    /// Receives a response, converting error responses into errors.
+    async fn receive(&mut self) -> Result<Response> {
+        match read_frame::<_, Response>(&mut self.conn).await? {
+            Some(response) => response.into_result(),
+            None => Err(Error::Internal("Server closed the connection".into())),
+        }
+    }
+
+    /// Returns an error for an unexpected response.
+    fn unexpected(response: Response) -> Error {
+        Error::Internal(format!("Unexpected response {:?}", response))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{AsyncAdapter, AsyncStore, Memory, Paged, Server};
+    use super::*;
+    use futures::{StreamExt, TryStreamExt};
+    use pretty_assertions::assert_eq;
+    use tokio::net::TcpListener;
+
+    /// Starts a server for the given store on a random localhost port, returning its address.
+    async fn serve<S: AsyncStore + 'static>(store: S) -> Result<std::net::SocketAddr> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(Server::new(store).serve(listener));
+        Ok(addr)
+    }
+
+    #[tokio::test]
+    async fn client() -> Result<()> {
+        let addr = serve(AsyncAdapter::new(Memory::new())).await?;
+        let mut c = Client::connect(addr).await?;
+
+        assert_eq!(None, c.get(b"a").await?);
+        c.set(b"a", vec![0x01]).await?;
+        c.set(b"b", vec![0x02]).await?;
+        c.set(b"c", vec![]).await?;
+        c.delete(b"b").await?;
+        c.delete(b"x").await?;
+        c.flush().await?;
+        assert_eq!(Some(vec![0x01]), c.get(b"a").await?);
+        assert_eq!(None, c.get(b"b").await?);
+        assert_eq!(
+            vec![(b"a".to_vec(), vec![0x01]), (b"c".to_vec(), vec![])],
+            c.scan(Range::from(..)).await?.try_collect::<Vec<_>>().await?
+        );
+
+        // Other clients see the same store.
+        let mut other = Client::connect(addr).await?;
+        assert_eq!(Some(vec![]), other.get(b"c").await?);
+
+        // Scan results are streamed, and the rest are skipped if the stream is dropped early.
+        for i in 0..1000_u16 {
+            c.set(&i.to_be_bytes(), i.to_be_bytes().to_vec()).await?;
+        }
+        let range = Range::from(10_u16.to_be_bytes().to_vec()..20_u16.to_be_bytes().to_vec());
+        let scan: Vec<_> = c.scan(range).await?.try_collect().await?;
+        assert_eq!(10, scan.len());
+        let first: Vec<_> = c.scan(Range::from(..)).await?.take(3).try_collect().await?;
+        assert_eq!(0_u16.to_be_bytes().to_vec(), first[0].0);
+        assert_eq!(3, first.len());
+        assert_eq!(Some(vec![0x01]), c.get(b"a").await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn errors() -> Result<()> {
+        let dir = tempdir::TempDir::new("kupier")?;
+        let addr = serve(Paged::new_with_page_size(&dir.path().join("kupier"), 512)?).await?;
+        let mut c = Client::connect(addr).await?;
+
+        // Errors are returned as the original error variant, and the connection remains usable.
+        assert!(matches!(c.set(b"a", vec![0; 512]).await, Err(Error::Value(_))));
+        c.set(b"a", vec![0x01]).await?;
+        assert_eq!(Some(vec![0x01]), c.get(b"a").await?);
+        Ok(())
+    }
+}
@@ -9,6 +9,7 @@ pub type Result<T> = std::result::Result<T, Error>;
 pub enum Error {
     Abort,
     Config(String),
+    Corruption(u64, String),
     Internal(String),
     Parse(String),
     ReadOnly,
@@ -24,6 +25,7 @@ impl Display for Error {
             Error::Config(s) | Error::Internal(s) | Error::Parse(s) | Error::Value(s) => {
                 write!(f, "{}", s)
             }
+            Error::Corruption(page, s) => write!(f, "Page {} is corrupt: {}", page, s),
             Error::Abort => write!(f, "Operation aborted"),
             Error::Serialization => write!(f, "Serialization failure, retry transaction"),
             Error::ReadOnly => write!(f, "Read-only transaction"),
@@ -89,4 +91,10 @@ impl<T> From<std::sync::PoisonError<T>> for Error {
     fn from(err: std::sync::PoisonError<T>) -> Self {
         Error::Internal(err.to_string())
     }
-}
\ No newline at end of file
+}
+
+impl From<tokio::task::JoinError> for Error {
+    fn from(err: tokio::task::JoinError) -> Self {
+        Error::Internal(err.to_string())
+    }
+}
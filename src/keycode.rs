@@ -0,0 +1,381 @@
+//! Order-preserving key encoding, such that the byte-wise order of encoded keys matches the
+//! logical order of the original values. Encoded keys can be decoded back into the original values,
+//! and since the encodings are self-delimiting, values can be combined into composite keys such as
+//! tuples. This allows using typed and composite keys with any store, e.g. via Store::scan() and
+//! range(), without a custom key comparator.
+
+use super::Range;
+use crate::error::{Error, Result};
+
+use std::ops::{Bound, RangeBounds};
+
+/// Encodes a byte vector. 0x00 is used as an escape character, and encoded as 0x00 0xff, and the
+/// value is terminated by 0x00 0x00. This allows byte vectors to be used as prefixes of composite
+/// keys, since shorter vectors sort before longer vectors with the same prefix.
+pub fn encode_bytes(bytes: &[u8]) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(bytes.len() + 2);
+    for b in bytes {
+        match b {
+            0x00 => encoded.extend_from_slice(&[0x00, 0xff]),
+            b => encoded.push(*b),
+        }
+    }
+    encoded.extend_from_slice(&[0x00, 0x00]);
+    encoded
+}
+
+/// Decodes a byte vector encoded with encode_bytes(), advancing the slice.
+pub fn take_bytes(bytes: &mut &[u8]) -> Result<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(bytes.len() / 2);
+    let mut iter = bytes.iter().enumerate();
+    let taken = loop {
+        match iter.next().map(|(_, b)| b) {
+            Some(0x00) => match iter.next() {
+                Some((i, 0x00)) => break i + 1,
+                Some((_, 0xff)) => decoded.push(0x00),
+                Some((_, b)) => {
+                    return Err(Error::Internal(format!("Invalid byte escape {:x?}", b)))
+                }
+                None => return Err(Error::Internal("Unexpected end of bytes".into())),
+            },
+            Some(b) => decoded.push(*b),
+            None => return Err(Error::Internal("Unexpected end of bytes".into())),
+        }
+    };
+    *bytes = &bytes[taken..];
+    Ok(decoded)
+}
+
+/// A key type with an order-preserving byte encoding, such that the byte-wise order of encoded
+/// keys matches the natural order of the keys. Encodings are self-delimiting, so keys can be
+/// combined into composite keys (tuples) which are ordered by their first element, then their
+/// second, and so on.
+///
+/// Integers are encoded in big-endian byte order, with the sign bit flipped for signed integers.
+/// Floats are ordered like total_cmp(), i.e. -NaN < -∞ < -0.0 < +0.0 < +∞ < NaN. Strings and
+/// byte vectors are escaped and terminated as in encode_bytes(), so shorter values sort first.
+/// Options are prefixed by a marker byte, such that None sorts before Some.
+pub trait Key: Sized {
+    /// Encodes the key, appending it to the given buffer.
+    fn encode_into(&self, buf: &mut Vec<u8>);
+
+    /// Decodes a key from the start of the given bytes, advancing the slice past it.
+    fn decode_from(bytes: &mut &[u8]) -> Result<Self>;
+
+    /// Encodes the key into a byte vector.
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.encode_into(&mut buf);
+        buf
+    }
+
+    /// Decodes a key from a byte slice, which must contain exactly one key.
+    fn decode(mut bytes: &[u8]) -> Result<Self> {
+        let key = Self::decode_from(&mut bytes)?;
+        if !bytes.is_empty() {
+            return Err(Error::Internal("Unexpected data remaining at end of key".into()));
+        }
+        Ok(key)
+    }
+}
+
+/// Decodes a fixed-size byte array, advancing the slice.
+fn take_array<const N: usize>(bytes: &mut &[u8]) -> Result<[u8; N]> {
+    if bytes.len() < N {
+        return Err(Error::Internal(format!("Unable to decode {} bytes from {}", N, bytes.len())));
+    }
+    let array = bytes[..N].try_into()?;
+    *bytes = &bytes[N..];
+    Ok(array)
+}
+
+impl Key for bool {
+    fn encode_into(&self, buf: &mut Vec<u8>) {
+        buf.push(u8::from(*self))
+    }
+
+    fn decode_from(bytes: &mut &[u8]) -> Result<Self> {
+        match take_array(bytes)? {
+            [0x00] => Ok(false),
+            [0x01] => Ok(true),
+            [b] => Err(Error::Internal(format!("Invalid boolean value {:x?}", b))),
+        }
+    }
+}
+
+impl Key for String {
+    fn encode_into(&self, buf: &mut Vec<u8>) {
+        buf.extend(encode_bytes(self.as_bytes()))
+    }
+
+    fn decode_from(bytes: &mut &[u8]) -> Result<Self> {
+        String::from_utf8(take_bytes(bytes)?).map_err(|err| Error::Internal(err.to_string()))
+    }
+}
+
+impl Key for Vec<u8> {
+    fn encode_into(&self, buf: &mut Vec<u8>) {
+        buf.extend(encode_bytes(self))
+    }
+
+    fn decode_from(bytes: &mut &[u8]) -> Result<Self> {
+        take_bytes(bytes)
+    }
+}
+
+impl<T: Key> Key for Option<T> {
+    fn encode_into(&self, buf: &mut Vec<u8>) {
+        match self {
+            None => buf.push(0x00),
+            Some(value) => {
+                buf.push(0x01);
+                value.encode_into(buf);
+            }
+        }
+    }
+
+    fn decode_from(bytes: &mut &[u8]) -> Result<Self> {
+        match take_array(bytes)? {
+            [0x00] => Ok(None),
+            [0x01] => Ok(Some(T::decode_from(bytes)?)),
+            [b] => Err(Error::Internal(format!("Invalid option marker {:x?}", b))),
+        }
+    }
+}
+
+/// Implements Key for unsigned integers, using big-endian byte order.
+macro_rules! impl_key_unsigned {
+    ($($type:ty),*) => {$(
+        impl Key for $type {
+            fn encode_into(&self, buf: &mut Vec<u8>) {
+                buf.extend_from_slice(&self.to_be_bytes())
+            }
+
+            fn decode_from(bytes: &mut &[u8]) -> Result<Self> {
+                Ok(Self::from_be_bytes(take_array(bytes)?))
+            }
+        }
+    )*};
+}
+
+impl_key_unsigned!(u8, u16, u32, u64, u128);
+
+/// Implements Key for signed integers, by flipping the sign bit of the two's complement such that
+/// negative numbers sort before positive numbers, and then using big-endian byte order.
+macro_rules! impl_key_signed {
+    ($($type:ty => $unsigned:ty),*) => {$(
+        impl Key for $type {
+            fn encode_into(&self, buf: &mut Vec<u8>) {
+                let n = *self as $unsigned ^ (1 << (<$unsigned>::BITS - 1));
+                buf.extend_from_slice(&n.to_be_bytes())
+            }
+
+            fn decode_from(bytes: &mut &[u8]) -> Result<Self> {
+                let n = <$unsigned>::from_be_bytes(take_array(bytes)?);
+                Ok((n ^ (1 << (<$unsigned>::BITS - 1))) as $type)
+            }
+        }
+    )*};
+}
+
+impl_key_signed!(i8 => u8, i16 => u16, i32 => u32, i64 => u64, i128 => u128);
+
+/// Implements Key for floats. Positive floats have their sign bit flipped such that they sort
+/// after negative floats, while negative floats have all bits flipped such that larger magnitudes
+/// sort first. The result is then encoded in big-endian byte order.
+macro_rules! impl_key_float {
+    ($($type:ty => $unsigned:ty),*) => {$(
+        impl Key for $type {
+            fn encode_into(&self, buf: &mut Vec<u8>) {
+                let sign = 1 << (<$unsigned>::BITS - 1);
+                let bits = self.to_bits();
+                let n = if bits & sign == 0 { bits ^ sign } else { !bits };
+                buf.extend_from_slice(&n.to_be_bytes())
+            }
+
+            fn decode_from(bytes: &mut &[u8]) -> Result<Self> {
+                let sign = 1 << (<$unsigned>::BITS - 1);
+                let n = <$unsigned>::from_be_bytes(take_array(bytes)?);
+                Ok(Self::from_bits(if n & sign != 0 { n ^ sign } else { !n }))
+            }
+        }
+    )*};
+}
+
+impl_key_float!(f32 => u32, f64 => u64);
+
+/// Implements Key for tuples, by concatenating the encoded elements.
+macro_rules! impl_key_tuple {
+    ($($name:ident),+) => {
+        impl<$($name: Key),+> Key for ($($name,)+) {
+            #[allow(non_snake_case)]
+            fn encode_into(&self, buf: &mut Vec<u8>) {
+                let ($($name,)+) = self;
+                $($name.encode_into(buf);)+
+            }
+
+            fn decode_from(bytes: &mut &[u8]) -> Result<Self> {
+                Ok(($($name::decode_from(bytes)?,)+))
+            }
+        }
+    };
+}
+
+impl_key_tuple!(A);
+impl_key_tuple!(A, B);
+impl_key_tuple!(A, B, C);
+impl_key_tuple!(A, B, C, D);
+impl_key_tuple!(A, B, C, D, E);
+
+/// Creates a store range from a range of keys, by encoding its bounds. For composite keys, use
+/// Range::prefix() with an encoded tuple prefix to scan all keys starting with those elements.
+pub fn range<K: Key, R: RangeBounds<K>>(range: R) -> Range {
+    let encode = |bound: Bound<&K>| match bound {
+        Bound::Included(k) => Bound::Included(k.encode()),
+        Bound::Excluded(k) => Bound::Excluded(k.encode()),
+        Bound::Unbounded => Bound::Unbounded,
+    };
+    Range::from((encode(range.start_bound()), encode(range.end_bound())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Memory, Store};
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::fmt::Debug;
+
+    /// Checks that the given keys, in their natural order, roundtrip through the encoding and
+    /// that their encodings have the same order.
+    fn assert_order<K: Key + Debug + PartialEq>(keys: Vec<K>) -> Result<()> {
+        let encoded: Vec<_> = keys.iter().map(Key::encode).collect();
+        for (key, bytes) in keys.iter().zip(&encoded) {
+            assert_eq!(key, &K::decode(bytes)?);
+        }
+        for pair in encoded.windows(2) {
+            assert!(pair[0] < pair[1], "{:x?} not before {:x?}", pair[0], pair[1]);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn key() -> Result<()> {
+        assert_order(vec![false, true])?;
+        assert_order(vec![0_u8, 1, 0x7f, 0x80, u8::MAX])?;
+        assert_order(vec![0_u64, 1, 255, 256, u64::MAX - 1, u64::MAX])?;
+        assert_order(vec![i8::MIN, -1, 0, 1, i8::MAX])?;
+        assert_order(vec![i64::MIN, i64::MIN + 1, -256, -255, -1, 0, 1, 255, 256, i64::MAX])?;
+        assert_order(vec![i128::MIN, -1, 0, 1, i128::MAX])?;
+        assert_order(vec![
+            f64::NEG_INFINITY,
+            f64::MIN,
+            -1.5,
+            -f64::MIN_POSITIVE,
+            -0.0,
+            0.0,
+            f64::MIN_POSITIVE,
+            1.5,
+            f64::MAX,
+            f64::INFINITY,
+        ])?;
+        assert_order(vec![f32::NEG_INFINITY, -1.0, -0.0, 0.0, 1.0, f32::INFINITY])?;
+        assert_order(vec![
+            String::new(),
+            "\0".to_string(),
+            "\0\0".to_string(),
+            "a".to_string(),
+            "a\0".to_string(),
+            "a\0b".to_string(),
+            "ab".to_string(),
+            "b".to_string(),
+        ])?;
+        assert_order(vec![vec![], vec![0x00], vec![0x00, 0xff], vec![0x01], vec![0xff]])?;
+        assert_order(vec![
+            ("a".to_string(), -1_i64),
+            ("a".to_string(), 0),
+            ("a\0".to_string(), -1),
+            ("b".to_string(), i64::MIN),
+        ])?;
+        assert_order(vec![
+            (1_u8, false, -0.5),
+            (1, true, -1.0_f64),
+            (1, true, 1.0),
+            (2, false, 0.0),
+        ])?;
+
+        let nan = f64::NAN.encode();
+        assert!(f64::decode(&nan)?.is_nan());
+        assert!(f64::INFINITY.encode() < nan);
+
+        assert!(u64::decode(&[0x01; 7]).is_err());
+        assert!(u32::decode(&[0x01; 5]).is_err());
+        assert!(bool::decode(&[0x02]).is_err());
+        assert!(String::decode(&[0xff, 0x00, 0x00]).is_err());
+        assert!(<(u8, String)>::decode(&[0x01, b'a', 0x00]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn bytes() -> Result<()> {
+        let cases: Vec<(&[u8], &[u8])> = vec![
+            (&[], &[0x00, 0x00]),
+            (&[0x01, 0x02, 0x03], &[0x01, 0x02, 0x03, 0x00, 0x00]),
+            (&[0x00, 0x01, 0x00], &[0x00, 0xff, 0x01, 0x00, 0xff, 0x00, 0x00]),
+            (&[0xff, 0x00], &[0xff, 0x00, 0xff, 0x00, 0x00]),
+        ];
+        for (input, expect) in cases {
+            let encoded = encode_bytes(input);
+            assert_eq!(expect, &encoded[..]);
+            let mut bytes = &[&encoded[..], &[0x07]].concat()[..];
+            assert_eq!(input, &take_bytes(&mut bytes)?[..]);
+            assert_eq!(&[0x07], bytes);
+        }
+
+        // Shorter values must sort before longer values with the same prefix.
+        assert!(encode_bytes(&[0x01]) < encode_bytes(&[0x01, 0x00]));
+        assert!(encode_bytes(&[0x01, 0x00]) < encode_bytes(&[0x01, 0x00, 0x00]));
+        assert!(take_bytes(&mut &[0x01, 0x00][..]).is_err());
+        assert!(take_bytes(&mut &[0x01, 0x00, 0x01][..]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn option() -> Result<()> {
+        assert_order(vec![None, Some(i64::MIN), Some(0), Some(i64::MAX)])?;
+        assert_order(vec![
+            (None, None),
+            (None, Some("".to_string())),
+            (Some(false), None),
+            (Some(false), Some("a".to_string())),
+            (Some(true), None),
+        ])?;
+        assert!(Option::<u8>::decode(&[0x02, 0x00]).is_err());
+        assert!(Option::<u8>::decode(&[0x01]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn scan() -> Result<()> {
+        // Composite keys can be used with a byte store directly, both for ranges and prefixes.
+        let mut s = Memory::new();
+        let mut keys = Vec::new();
+        for name in ["", "a", "a\0", "b"] {
+            for n in [i64::MIN, -1, 0, 1, i64::MAX] {
+                let key = (name.to_string(), n, n > 0);
+                s.set(&key.encode(), vec![])?;
+                keys.push(key);
+            }
+        }
+        let scan = |range: Range| -> Result<Vec<(String, i64, bool)>> {
+            s.scan(range).map(|r| r.and_then(|(k, _)| Key::decode(&k))).collect()
+        };
+        assert_eq!(keys, scan(Range::from(..))?);
+        assert_eq!(
+            keys[6..11].to_vec(),
+            scan(range(("a".to_string(), -1, false)..=("a\0".to_string(), i64::MIN, false)))?
+        );
+        assert_eq!(keys[5..10].to_vec(), scan(Range::prefix(&("a".to_string(),).encode()))?);
+        assert_eq!(keys[8..9].to_vec(), scan(Range::prefix(&("a".to_string(), 1_i64).encode()))?);
+        Ok(())
+    }
+}
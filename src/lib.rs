@@ -1,15 +1,36 @@
 #[cfg(test)]
 mod test;
+mod async_store;
 mod error;
 mod btree;
+mod buffer;
+mod client;
+pub mod keycode;
+mod logging;
+mod mvcc;
+mod paged;
+mod pager;
+mod server;
+mod typed;
+mod wal;
 
-pub use btree::Memory;
-// pub use mvcc::MVCC;
+pub use async_store::{AsyncAdapter, AsyncScan, AsyncStore};
+pub use btree::{Memory, Stats};
+pub use buffer::{CacheStats, Eviction};
+pub use client::Client;
+pub use error::{Error, Result};
+pub use logging::init_logging;
+pub use mvcc::{Mode, Transaction, MVCC};
+pub use paged::{Paged, PagedSnapshot, PagedStats, Verification};
+pub use server::Server;
+pub use typed::TypedStore;
+pub use wal::Wal;
 // pub use std_memory::StdMemory;
 #[cfg(test)]
 pub use test::Test;
 
-use crate::error::Result;
+use serde_derive::{Deserialize, Serialize};
+use std::cmp::Ordering;
 use std::fmt::Display;
 use std::ops::{Bound, RangeBounds};
 
@@ -18,6 +39,29 @@ pub trait Store: Display + Send + Sync {
     /// Deletes a key, or does nothing if it does not exist.
     fn delete(&mut self, key: &[u8]) -> Result<()>;
 
+    /// Deletes all keys in the given range, returning the number of keys deleted.
+    ///
+    /// The default implementation scans the range and then deletes the keys one by one. Stores
+    /// should override it if they can delete a range more efficiently.
+    fn delete_range(&mut self, range: Range) -> Result<usize> {
+        let keys =
+            self.scan(range).map(|item| item.map(|(key, _)| key)).collect::<Result<Vec<_>>>()?;
+        for key in &keys {
+            self.delete(key)?;
+        }
+        Ok(keys.len())
+    }
+
+    /// Returns a cursor over the store's key/value pairs. The cursor is initially unpositioned,
+    /// i.e. invalid, until it is positioned with one of its seek methods.
+    ///
+    /// The default implementation reads a scan of the whole store into memory when the cursor is
+    /// first positioned, so it doesn't see later writes, and assumes keys are in bytewise order.
+    /// Stores should override it with a cursor that seeks within the store.
+    fn cursor(&self) -> Box<dyn Cursor> {
+        Box::new(ScanCursor::new(self.scan(Range::from(..))))
+    }
+
     /// Flushes any buffered data to the underlying storage medium.
     fn flush(&mut self) -> Result<()>;
 
@@ -27,11 +71,228 @@ pub trait Store: Display + Send + Sync {
     /// Iterates over an ordered range of key/value pairs.
     fn scan(&self, range: Range) -> Scan;
 
+    /// Iterates over all key/value pairs whose keys start with the given prefix.
+    fn scan_prefix(&self, prefix: &[u8]) -> Scan {
+        self.scan(Range::prefix(prefix))
+    }
+
     /// Sets a value for a key, replacing the existing value if any.
     fn set(&mut self, key: &[u8], value: Vec<u8>) -> Result<()>;
+
+    /// Applies a batch of writes atomically, in order. Readers and scans observe either none or
+    /// all of the writes, and if the batch is invalid (e.g. a key/value pair is too large) none of
+    /// the writes are applied. However, if the batch fails partway through, e.g. due to an I/O
+    /// error, stores which modify their data in place may have applied some of the writes.
+    ///
+    /// The default implementation applies the writes one by one with set() and delete(), so it is
+    /// not atomic. Stores should override it.
+    fn write(&mut self, batch: WriteBatch) -> Result<()> {
+        for (key, value) in batch {
+            match value {
+                Some(value) => self.set(&key, value)?,
+                None => self.delete(&key)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A batch of sets and deletes, applied atomically by Store::write().
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct WriteBatch {
+    /// The writes, in order, as key/value pairs where a None value is a delete.
+    writes: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+}
+
+impl WriteBatch {
+    /// Creates a new, empty write batch.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a delete of a key to the batch.
+    pub fn delete(&mut self, key: &[u8]) -> &mut Self {
+        self.writes.push((key.to_vec(), None));
+        self
+    }
+
+    /// Checks if the batch is empty.
+    pub fn is_empty(&self) -> bool {
+        self.writes.is_empty()
+    }
+
+    /// Returns the number of writes in the batch.
+    pub fn len(&self) -> usize {
+        self.writes.len()
+    }
+
+    /// Adds a set of a key to a value to the batch.
+    pub fn set(&mut self, key: &[u8], value: Vec<u8>) -> &mut Self {
+        self.writes.push((key.to_vec(), Some(value)));
+        self
+    }
+}
+
+impl IntoIterator for WriteBatch {
+    type Item = (Vec<u8>, Option<Vec<u8>>);
+    type IntoIter = std::vec::IntoIter<Self::Item>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.writes.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a WriteBatch {
+    type Item = &'a (Vec<u8>, Option<Vec<u8>>);
+    type IntoIter = std::slice::Iter<'a, (Vec<u8>, Option<Vec<u8>>)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.writes.iter()
+    }
+}
+
+/// A cursor over the ordered key/value pairs of a store, which can be freely repositioned and
+/// moved in either direction. Unlike a scan, a cursor is not restricted to a range, and can jump
+/// to a different key without being recreated.
+///
+/// The cursor is positioned at a single key/value pair while it is valid. Moving past either end
+/// of the store, or seeking to a key with no matching pair, makes it invalid, and moving an invalid
+/// cursor does nothing. If the store is modified while the cursor is open, the cursor keeps its
+/// current key/value pair and moves relative to it. Seeks always observe the current state of the
+/// store, but stores which read from snapshots may not observe writes made since the last seek
+/// when moving the cursor.
+pub trait Cursor: Send {
+    /// Positions the cursor at the first key at or after the given key.
+    fn seek(&mut self, key: &[u8]) -> Result<()>;
+
+    /// Positions the cursor at the last key at or before the given key.
+    fn seek_for_prev(&mut self, key: &[u8]) -> Result<()>;
+
+    /// Positions the cursor at the first key in the store.
+    fn seek_to_first(&mut self) -> Result<()>;
+
+    /// Positions the cursor at the last key in the store.
+    fn seek_to_last(&mut self) -> Result<()>;
+
+    /// Moves the cursor to the next key.
+    fn next(&mut self) -> Result<()>;
+
+    /// Moves the cursor to the previous key.
+    fn prev(&mut self) -> Result<()>;
+
+    /// Returns true if the cursor is positioned at a key/value pair.
+    fn valid(&self) -> bool;
+
+    /// Returns the key at the cursor position, if valid.
+    fn key(&self) -> Option<&[u8]>;
+
+    /// Returns the value at the cursor position, if valid.
+    fn value(&self) -> Option<&[u8]>;
+}
+
+/// A cursor over the key/value pairs of a scan, used by the default Store::cursor(). The scan is
+/// read into memory when the cursor is first positioned, and keys are assumed to be in bytewise
+/// order.
+struct ScanCursor {
+    /// The scan, until it has been read.
+    scan: Option<Scan>,
+    /// The key/value pairs read from the scan.
+    items: Vec<(Vec<u8>, Vec<u8>)>,
+    /// The index of the cursor position, if valid.
+    index: Option<usize>,
+}
+
+impl ScanCursor {
+    /// Creates a new, unpositioned cursor over the given scan.
+    fn new(scan: Scan) -> Self {
+        Self { scan: Some(scan), items: Vec::new(), index: None }
+    }
+
+    /// Returns the key/value pairs, reading the scan into memory if it hasn't been read yet.
+    fn items(&mut self) -> Result<&[(Vec<u8>, Vec<u8>)]> {
+        if let Some(scan) = self.scan.take() {
+            self.items = scan.collect::<Result<_>>()?;
+        }
+        Ok(&self.items)
+    }
+}
+
+impl Cursor for ScanCursor {
+    fn seek(&mut self, key: &[u8]) -> Result<()> {
+        let index = self.items()?.partition_point(|(k, _)| k.as_slice() < key);
+        self.index = Some(index).filter(|i| *i < self.items.len());
+        Ok(())
+    }
+
+    fn seek_for_prev(&mut self, key: &[u8]) -> Result<()> {
+        let index = self.items()?.partition_point(|(k, _)| k.as_slice() <= key);
+        self.index = index.checked_sub(1);
+        Ok(())
+    }
+
+    fn seek_to_first(&mut self) -> Result<()> {
+        self.items()?;
+        self.index = Some(0).filter(|_| !self.items.is_empty());
+        Ok(())
+    }
+
+    fn seek_to_last(&mut self) -> Result<()> {
+        self.index = self.items()?.len().checked_sub(1);
+        Ok(())
+    }
+
+    fn next(&mut self) -> Result<()> {
+        self.index = self.index.map(|i| i + 1).filter(|i| *i < self.items.len());
+        Ok(())
+    }
+
+    fn prev(&mut self) -> Result<()> {
+        self.index = self.index.and_then(|i| i.checked_sub(1));
+        Ok(())
+    }
+
+    fn valid(&self) -> bool {
+        self.index.is_some()
+    }
+
+    fn key(&self) -> Option<&[u8]> {
+        self.index.map(|i| self.items[i].0.as_slice())
+    }
+
+    fn value(&self) -> Option<&[u8]> {
+        self.index.map(|i| self.items[i].1.as_slice())
+    }
+}
+
+/// Determines the order of keys in a store. Stores order keys by their raw bytes by default, but
+/// composite or numeric keys may need a different order. The comparator must be a total order, and
+/// keys that compare as equal are considered the same key.
+pub trait KeyComparator: Send + Sync {
+    /// Compares two keys.
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering;
+}
+
+impl<F> KeyComparator for F
+where
+    F: Fn(&[u8], &[u8]) -> Ordering + Send + Sync,
+{
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        self(a, b)
+    }
+}
+
+/// The default key comparator, which orders keys lexicographically by their raw bytes.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct BytewiseComparator;
+
+impl KeyComparator for BytewiseComparator {
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        a.cmp(b)
+    }
 }
 
 /// A scan range.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Range {
     start: Bound<Vec<u8>>,
     end: Bound<Vec<u8>>,
@@ -56,15 +317,34 @@ impl Range {
         }
     }
 
+    /// Creates a new range containing all keys that start with the given prefix. The end bound is
+    /// the prefix with its last byte incremented, after stripping trailing 0xff bytes since these
+    /// can't be incremented. If the prefix is empty or all 0xff bytes, the range is unbounded.
+    pub fn prefix(prefix: &[u8]) -> Self {
+        let mut end = prefix.to_vec();
+        while let Some(last) = end.pop() {
+            if last < 0xff {
+                end.push(last + 1);
+                return Self { start: Bound::Included(prefix.to_vec()), end: Bound::Excluded(end) };
+            }
+        }
+        Self { start: Bound::Included(prefix.to_vec()), end: Bound::Unbounded }
+    }
+
     /// Checks if the given value is contained in the range.
     fn contains(&self, v: &[u8]) -> bool {
+        self.contains_by(&BytewiseComparator, v)
+    }
+
+    /// Checks if the given value is contained in the range, ordering keys by the given comparator.
+    fn contains_by(&self, cmp: &dyn KeyComparator, v: &[u8]) -> bool {
         (match &self.start {
-            Bound::Included(start) => &**start <= v,
-            Bound::Excluded(start) => &**start < v,
+            Bound::Included(start) => cmp.compare(start, v) != Ordering::Greater,
+            Bound::Excluded(start) => cmp.compare(start, v) == Ordering::Less,
             Bound::Unbounded => true,
         }) && (match &self.end {
-            Bound::Included(end) => v <= &**end,
-            Bound::Excluded(end) => v < &**end,
+            Bound::Included(end) => cmp.compare(v, end) != Ordering::Greater,
+            Bound::Excluded(end) => cmp.compare(v, end) == Ordering::Less,
             Bound::Unbounded => true,
         })
     }
@@ -93,19 +373,91 @@ pub type Scan = Box<dyn DoubleEndedIterator<Item = Result<(Vec<u8>, Vec<u8>)>> +
 
 #[cfg(test)]
 trait TestSuite<S: Store> {
-    fn setup() -> Result<S>;
+    /// Sets up an empty store. File-backed stores also return the temporary directory containing
+    /// their files, which is removed when dropped and must be kept alive as long as the store.
+    fn setup() -> Result<(S, Option<tempdir::TempDir>)>;
 
     fn test() -> Result<()> {
+        Self::test_cursor()?;
         Self::test_delete()?;
+        Self::test_delete_range()?;
         Self::test_get()?;
         Self::test_scan()?;
         Self::test_set()?;
+        Self::test_write()?;
         Self::test_random()?;
         Ok(())
     }
 
+    fn test_cursor() -> Result<()> {
+        let (mut s, _dir) = Self::setup()?;
+        let mut c = s.cursor();
+        assert!(!c.valid());
+        c.seek_to_first()?;
+        assert!(!c.valid());
+        c.seek(b"a")?;
+        assert_eq!((None, None), (c.key(), c.value()));
+
+        s.set(b"a", vec![0x01])?;
+        s.set(b"b", vec![0x02])?;
+        s.set(b"ba", vec![0x02, 0x01])?;
+        s.set(b"bb", vec![0x02, 0x02])?;
+        s.set(b"c", vec![0x03])?;
+
+        // Seeks position the cursor at or around the given key.
+        let mut c = s.cursor();
+        c.seek(b"b")?;
+        assert_eq!((Some(&b"b"[..]), Some(&[0x02][..])), (c.key(), c.value()));
+        c.seek(b"b\x00")?;
+        assert_eq!(Some(&b"ba"[..]), c.key());
+        c.seek(b"d")?;
+        assert!(!c.valid());
+        c.seek_for_prev(b"bz")?;
+        assert_eq!(Some(&b"bb"[..]), c.key());
+        c.seek_for_prev(b"c")?;
+        assert_eq!(Some(&b"c"[..]), c.key());
+        c.seek_for_prev(b"")?;
+        assert!(!c.valid());
+        c.seek_to_first()?;
+        assert_eq!(Some(&b"a"[..]), c.key());
+        c.seek_to_last()?;
+        assert_eq!(Some(&b"c"[..]), c.key());
+
+        // Moving in either direction, off either end.
+        c.seek(b"ba")?;
+        c.next()?;
+        assert_eq!(Some(&b"bb"[..]), c.key());
+        c.prev()?;
+        c.prev()?;
+        assert_eq!(Some(&b"b"[..]), c.key());
+        c.prev()?;
+        c.prev()?;
+        assert!(!c.valid());
+        c.next()?;
+        assert!(!c.valid());
+        c.seek_to_last()?;
+        c.next()?;
+        assert!(!c.valid());
+
+        // Writes while the cursor is open don't affect its current key/value pair, and are seen by
+        // later seeks.
+        c.seek(b"b")?;
+        s.set(b"b", vec![0xff])?;
+        s.set(b"b0", vec![0xb0])?;
+        s.delete(b"a")?;
+        assert_eq!((Some(&b"b"[..]), Some(&[0x02][..])), (c.key(), c.value()));
+        c.seek(b"b")?;
+        assert_eq!((Some(&b"b"[..]), Some(&[0xff][..])), (c.key(), c.value()));
+        c.next()?;
+        assert_eq!((Some(&b"b0"[..]), Some(&[0xb0][..])), (c.key(), c.value()));
+        c.prev()?;
+        c.prev()?;
+        assert!(!c.valid());
+        Ok(())
+    }
+
     fn test_get() -> Result<()> {
-        let mut s = Self::setup()?;
+        let (mut s, _dir) = Self::setup()?;
         s.set(b"a", vec![0x01])?;
         assert_eq!(Some(vec![0x01]), s.get(b"a")?);
         assert_eq!(None, s.get(b"b")?);
@@ -113,7 +465,7 @@ trait TestSuite<S: Store> {
     }
 
     fn test_delete() -> Result<()> {
-        let mut s = Self::setup()?;
+        let (mut s, _dir) = Self::setup()?;
         s.set(b"a", vec![0x01])?;
         assert_eq!(Some(vec![0x01]), s.get(b"a")?);
         s.delete(b"a")?;
@@ -122,9 +474,36 @@ trait TestSuite<S: Store> {
         Ok(())
     }
 
+    fn test_delete_range() -> Result<()> {
+        let (mut s, _dir) = Self::setup()?;
+        assert_eq!(0, s.delete_range(Range::from(..))?);
+
+        let keys: Vec<Vec<u8>> = (0..100_u8).map(|i| vec![i]).collect();
+        for key in &keys {
+            s.set(key, key.clone())?;
+        }
+        assert_eq!(0, s.delete_range(Range::from(vec![200]..))?);
+        assert_eq!(10, s.delete_range(Range::from(vec![10]..vec![20]))?);
+        assert_eq!(0, s.delete_range(Range::from(vec![10]..vec![20]))?);
+        assert_eq!(31, s.delete_range(Range::from(vec![5]..=vec![45]))?);
+        assert_eq!(5, s.delete_range(Range::from(..vec![5]))?);
+        assert_eq!(10, s.delete_range(Range::from(vec![90]..))?);
+        assert_eq!(
+            keys[46..90].iter().map(|k| (k.clone(), k.clone())).collect::<Vec<_>>(),
+            s.scan(Range::from(..)).collect::<Result<Vec<_>>>()?
+        );
+
+        // Deleting everything should leave an empty, but usable, store.
+        assert_eq!(44, s.delete_range(Range::from(..))?);
+        assert_eq!(0, s.scan(Range::from(..)).count());
+        s.set(b"a", vec![0x01])?;
+        assert_eq!(Some(vec![0x01]), s.get(b"a")?);
+        Ok(())
+    }
+
     fn test_random() -> Result<()> {
         use rand::Rng;
-        let mut s = Self::setup()?;
+        let (mut s, _dir) = Self::setup()?;
         let mut rng: rand::rngs::StdRng = rand::SeedableRng::seed_from_u64(397_427_893);
 
         // Create a bunch of random items and insert them
@@ -157,7 +536,7 @@ trait TestSuite<S: Store> {
     }
 
     fn test_scan() -> Result<()> {
-        let mut s = Self::setup()?;
+        let (mut s, _dir) = Self::setup()?;
         s.set(b"a", vec![0x01])?;
         s.set(b"b", vec![0x02])?;
         s.set(b"ba", vec![0x02, 0x01])?;
@@ -217,11 +596,70 @@ trait TestSuite<S: Store> {
             ],
             s.scan(Range::from(..)).collect::<Result<Vec<_>>>()?
         );
+
+        // Prefix ranges, including prefixes ending in 0xff and the empty prefix
+        s.set(b"b\xff", vec![0x02, 0xff])?;
+        s.set(b"b\xff\xff", vec![0x02, 0xff, 0xff])?;
+        s.set(b"\xff", vec![0xff])?;
+        s.set(b"\xff\x00", vec![0xff, 0x00])?;
+        assert_eq!(
+            vec![
+                (b"b".to_vec(), vec![0x02]),
+                (b"ba".to_vec(), vec![0x02, 0x01]),
+                (b"bb".to_vec(), vec![0x02, 0x02]),
+                (b"b\xff".to_vec(), vec![0x02, 0xff]),
+                (b"b\xff\xff".to_vec(), vec![0x02, 0xff, 0xff]),
+            ],
+            s.scan_prefix(b"b").collect::<Result<Vec<_>>>()?
+        );
+        assert_eq!(
+            vec![
+                (b"b\xff\xff".to_vec(), vec![0x02, 0xff, 0xff]),
+                (b"b\xff".to_vec(), vec![0x02, 0xff]),
+            ],
+            s.scan(Range::prefix(b"b\xff")).rev().collect::<Result<Vec<_>>>()?
+        );
+        assert_eq!(
+            vec![(b"b\xff\xff".to_vec(), vec![0x02, 0xff, 0xff])],
+            s.scan_prefix(b"b\xff\xff").collect::<Result<Vec<_>>>()?
+        );
+        assert_eq!(
+            vec![(b"\xff".to_vec(), vec![0xff]), (b"\xff\x00".to_vec(), vec![0xff, 0x00])],
+            s.scan_prefix(b"\xff").collect::<Result<Vec<_>>>()?
+        );
+        assert_eq!(0, s.scan_prefix(b"\xff\xff").count());
+        assert_eq!(0, s.scan_prefix(b"bc").count());
+        assert_eq!(9, s.scan_prefix(b"").count());
+        Ok(())
+    }
+
+    fn test_write() -> Result<()> {
+        let (mut s, _dir) = Self::setup()?;
+        s.set(b"a", vec![0x01])?;
+        s.set(b"b", vec![0x02])?;
+
+        let mut batch = WriteBatch::new();
+        batch.set(b"c", vec![0x03]).delete(b"a").set(b"b", vec![0x04]).set(b"a", vec![0x05]);
+        batch.delete(b"c").set(b"d", vec![0x06]);
+        assert_eq!(6, batch.len());
+        s.write(batch)?;
+        assert_eq!(
+            vec![
+                (b"a".to_vec(), vec![0x05]),
+                (b"b".to_vec(), vec![0x04]),
+                (b"d".to_vec(), vec![0x06]),
+            ],
+            s.scan(Range::from(..)).collect::<Result<Vec<_>>>()?
+        );
+
+        // Empty batches do nothing.
+        s.write(WriteBatch::new())?;
+        assert_eq!(3, s.scan(Range::from(..)).count());
         Ok(())
     }
 
     fn test_set() -> Result<()> {
-        let mut s = Self::setup()?;
+        let (mut s, _dir) = Self::setup()?;
         s.set(b"a", vec![0x01])?;
         assert_eq!(Some(vec![0x01]), s.get(b"a")?);
         s.set(b"a", vec![0x02])?;
@@ -0,0 +1,50 @@
+use crate::error::{Error, Result};
+
+use log::LevelFilter;
+use log4rs::append::console::{ConsoleAppender, Target};
+use log4rs::config::{Appender, Config, Root};
+use log4rs::encode::pattern::PatternEncoder;
+use std::path::Path;
+
+/// The log pattern used when no config file is given.
+const DEFAULT_PATTERN: &str = "{d(%Y-%m-%d %H:%M:%S%.3f)} {h({l:5})} [{M}] {m}{n}";
+
+/// Initializes logging for the process. If a config file is given, it is loaded as a log4rs
+/// config file (e.g. YAML), and the level is ignored. Otherwise, log records at or above the given
+/// level (e.g. "info" or "debug") are written to stderr. This can only be called once.
+///
+/// The crate logs tree structure changes such as node splits and merges at trace level, root
+/// height changes and flushes at debug level, and store recovery and server connections at info
+/// level.
+pub fn init_logging(config: Option<&Path>, level: &str) -> Result<()> {
+    if let Some(path) = config {
+        return log4rs::init_file(path, Default::default()).map_err(|err| {
+            Error::Config(format!("Failed to load log config {:?}: {}", path, err))
+        });
+    }
+    let level: LevelFilter = level.parse()?;
+    let stderr = ConsoleAppender::builder()
+        .target(Target::Stderr)
+        .encoder(Box::new(PatternEncoder::new(DEFAULT_PATTERN)))
+        .build();
+    let config = Config::builder()
+        .appender(Appender::builder().build("stderr", Box::new(stderr)))
+        .build(Root::builder().appender("stderr").build(level))
+        .map_err(|err| Error::Config(err.to_string()))?;
+    log4rs::init_config(config)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn init() -> Result<()> {
+        // The logger can only be initialized once per process, so we only check config errors.
+        assert!(matches!(init_logging(None, "bogus"), Err(Error::Config(_))));
+        let path = Path::new("/nonexistent/log4rs.yaml");
+        assert!(matches!(init_logging(Some(path), "info"), Err(Error::Config(_))));
+        Ok(())
+    }
+}
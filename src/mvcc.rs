@@ -0,0 +1,959 @@
+use super::keycode::{encode_bytes, take_bytes, Key as _};
+use super::{Range, Store};
+use crate::error::{Error, Result};
+
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::iter::Peekable;
+use std::ops::{Bound, RangeBounds};
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// An MVCC-based transactional key-value store, on top of any other key-value store. Each write
+/// creates a new version of a key, tagged with the writing transaction's ID, and transactions only
+/// see versions that were committed when they began, giving snapshot isolation. Concurrent writes
+/// to the same key are detected as conflicts, and return Error::Serialization to the latter writer
+/// which should retry its transaction.
+///
+/// All transaction state is stored in the underlying store, alongside the versioned keys, using a
+/// key encoding which keeps versions of the same key adjacent and ordered by version.
+#[allow(clippy::upper_case_acronyms)]
+pub struct MVCC {
+    /// The underlying store. It is protected by a lock so it can be shared between transactions.
+    store: Arc<RwLock<Box<dyn Store>>>,
+}
+
+impl Clone for MVCC {
+    fn clone(&self) -> Self {
+        MVCC { store: self.store.clone() }
+    }
+}
+
+impl MVCC {
+    /// Creates a new MVCC key-value store with the given key-value store for storage.
+    pub fn new(store: Box<dyn Store>) -> Self {
+        Self { store: Arc::new(RwLock::new(store)) }
+    }
+
+    /// Begins a new transaction in read-write mode.
+    pub fn begin(&self) -> Result<Transaction> {
+        Transaction::begin(self.store.clone(), Mode::ReadWrite)
+    }
+
+    /// Begins a new transaction in the given mode.
+    pub fn begin_with_mode(&self, mode: Mode) -> Result<Transaction> {
+        Transaction::begin(self.store.clone(), mode)
+    }
+
+    /// Resumes a transaction with the given ID.
+    pub fn resume(&self, id: u64) -> Result<Transaction> {
+        Transaction::resume(self.store.clone(), id)
+    }
+}
+
+/// Serializes MVCC metadata and values.
+fn serialize<V: Serialize>(value: &V) -> Result<Vec<u8>> {
+    Ok(bincode::serialize(value)?)
+}
+
+/// Deserializes MVCC metadata and values.
+fn deserialize<'a, V: Deserialize<'a>>(bytes: &'a [u8]) -> Result<V> {
+    Ok(bincode::deserialize(bytes)?)
+}
+
+/// An MVCC transaction.
+pub struct Transaction {
+    /// The underlying store for the transaction. Shared between transactions using a lock.
+    store: Arc<RwLock<Box<dyn Store>>>,
+    /// The unique transaction ID.
+    id: u64,
+    /// The transaction mode.
+    mode: Mode,
+    /// The snapshot that the transaction is running in.
+    snapshot: Snapshot,
+}
+
+impl Transaction {
+    /// Begins a new transaction in the given mode.
+    fn begin(store: Arc<RwLock<Box<dyn Store>>>, mode: Mode) -> Result<Self> {
+        let mut session = store.write()?;
+
+        let id = match session.get(&Key::TxnNext.encode())? {
+            Some(ref v) => deserialize(v)?,
+            None => 1,
+        };
+        session.set(&Key::TxnNext.encode(), serialize(&(id + 1))?)?;
+        session.set(&Key::TxnActive(id).encode(), serialize(&mode)?)?;
+
+        // We always take a new snapshot, even for snapshot transactions, because all transactions
+        // increment the transaction ID and we need to properly record currently active
+        // transactions for any future snapshot transactions looking at this one.
+        let mut snapshot = Snapshot::take(&mut session, id)?;
+        drop(session);
+        if let Mode::Snapshot { version } = &mode {
+            snapshot = Snapshot::restore(&store.read()?, *version)?
+        }
+
+        Ok(Self { store, id, mode, snapshot })
+    }
+
+    /// Resumes an active transaction with the given ID. Errors if the transaction is not active.
+    fn resume(store: Arc<RwLock<Box<dyn Store>>>, id: u64) -> Result<Self> {
+        let session = store.read()?;
+        let mode = match session.get(&Key::TxnActive(id).encode())? {
+            Some(v) => deserialize(&v)?,
+            None => return Err(Error::Value(format!("No active transaction {}", id))),
+        };
+        let snapshot = match &mode {
+            Mode::Snapshot { version } => Snapshot::restore(&session, *version)?,
+            _ => Snapshot::restore(&session, id)?,
+        };
+        drop(session);
+        Ok(Self { store, id, mode, snapshot })
+    }
+
+    /// Returns the transaction ID.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Returns the transaction mode.
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Commits the transaction, by removing the transaction from the active set.
+    pub fn commit(self) -> Result<()> {
+        let mut session = self.store.write()?;
+        session.delete(&Key::TxnActive(self.id).encode())?;
+        session.flush()
+    }
+
+    /// Rolls back the transaction, by removing all updated entries.
+    pub fn rollback(self) -> Result<()> {
+        let mut session = self.store.write()?;
+        if self.mode.mutable() {
+            let mut rollback = Vec::new();
+            let mut scan = session.scan(Range::from(
+                Key::TxnUpdate(self.id, vec![].into()).encode()
+                    ..Key::TxnUpdate(self.id + 1, vec![].into()).encode(),
+            ));
+            while let Some((key, _)) = scan.next().transpose()? {
+                match Key::decode(&key)? {
+                    Key::TxnUpdate(_, updated_key) => rollback.push(updated_key.into_owned()),
+                    k => return Err(Error::Internal(format!("Expected TxnUpdate, got {:?}", k))),
+                };
+                rollback.push(key);
+            }
+            drop(scan);
+            for key in rollback.into_iter() {
+                session.delete(&key)?;
+            }
+        }
+        session.delete(&Key::TxnActive(self.id).encode())
+    }
+
+    /// Deletes a key.
+    pub fn delete(&mut self, key: &[u8]) -> Result<()> {
+        self.write(key, None)
+    }
+
+    /// Fetches a key.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let session = self.store.read()?;
+        let mut scan = session
+            .scan(Range::from(
+                Key::Record(key.into(), 0).encode()..=Key::Record(key.into(), self.id).encode(),
+            ))
+            .rev();
+        while let Some((k, v)) = scan.next().transpose()? {
+            match Key::decode(&k)? {
+                Key::Record(_, version) => {
+                    if self.snapshot.is_visible(version) {
+                        return deserialize(&v);
+                    }
+                }
+                k => return Err(Error::Internal(format!("Expected Record, got {:?}", k))),
+            };
+        }
+        Ok(None)
+    }
+
+    /// Scans a key range.
+    pub fn scan(&self, range: impl RangeBounds<Vec<u8>>) -> Result<super::Scan> {
+        let start = match range.start_bound() {
+            Bound::Excluded(k) => Bound::Excluded(Key::Record(k.into(), u64::MAX).encode()),
+            Bound::Included(k) => Bound::Included(Key::Record(k.into(), 0).encode()),
+            Bound::Unbounded => Bound::Included(Key::Record(vec![].into(), 0).encode()),
+        };
+        let end = match range.end_bound() {
+            Bound::Excluded(k) => Bound::Excluded(Key::Record(k.into(), 0).encode()),
+            Bound::Included(k) => Bound::Included(Key::Record(k.into(), u64::MAX).encode()),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let scan = self.store.read()?.scan(Range::from((start, end)));
+        Ok(Box::new(Scan::new(scan, self.snapshot.clone())))
+    }
+
+    /// Scans keys under a given prefix. The empty prefix scans all keys.
+    pub fn scan_prefix(&self, prefix: &[u8]) -> Result<super::Scan> {
+        self.scan(Range::prefix(prefix))
+    }
+
+    /// Sets a key.
+    pub fn set(&mut self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        self.write(key, Some(value))
+    }
+
+    /// Writes a value for a key. None is used for deletion.
+    fn write(&self, key: &[u8], value: Option<Vec<u8>>) -> Result<()> {
+        if !self.mode.mutable() {
+            return Err(Error::ReadOnly);
+        }
+        let mut session = self.store.write()?;
+
+        // Check if the key is dirty, i.e. if it has any uncommitted changes, by scanning for any
+        // versions that aren't visible to us.
+        let min = self.snapshot.invisible.iter().min().cloned().unwrap_or(self.id + 1);
+        let mut scan = session
+            .scan(Range::from(
+                Key::Record(key.into(), min).encode()..=Key::Record(key.into(), u64::MAX).encode(),
+            ))
+            .rev();
+        while let Some((k, _)) = scan.next().transpose()? {
+            match Key::decode(&k)? {
+                Key::Record(_, version) => {
+                    if !self.snapshot.is_visible(version) {
+                        return Err(Error::Serialization);
+                    }
+                }
+                k => return Err(Error::Internal(format!("Expected Record, got {:?}", k))),
+            };
+        }
+        drop(scan);
+
+        // Write the key and its update record.
+        let key = Key::Record(key.into(), self.id).encode();
+        let update = Key::TxnUpdate(self.id, (&key).into()).encode();
+        session.set(&update, vec![])?;
+        session.set(&key, serialize(&value)?)
+    }
+}
+
+/// An MVCC transaction mode.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Mode {
+    /// A read-write transaction.
+    ReadWrite,
+    /// A read-only transaction.
+    ReadOnly,
+    /// A read-only transaction running in a snapshot of a given version.
+    ///
+    /// The version must refer to a committed transaction ID. Any changes visible to the original
+    /// transaction will be visible in the snapshot (i.e. transactions that had not committed
+    /// before the snapshot transaction started will not be visible, even though they have a lower
+    /// version).
+    Snapshot { version: u64 },
+}
+
+impl Mode {
+    /// Checks whether the transaction mode can mutate data.
+    pub fn mutable(&self) -> bool {
+        match self {
+            Self::ReadWrite => true,
+            Self::ReadOnly => false,
+            Self::Snapshot { .. } => false,
+        }
+    }
+}
+
+/// A versioned snapshot, containing visibility information about concurrent transactions.
+#[derive(Clone)]
+struct Snapshot {
+    /// The version (i.e. transaction ID) that the snapshot belongs to.
+    version: u64,
+    /// The set of transaction IDs that were active at the start of the transactions, and thus
+    /// should be invisible to the snapshot.
+    invisible: HashSet<u64>,
+}
+
+impl Snapshot {
+    /// Takes a new snapshot, persisting it as `Key::TxnSnapshot(version)`.
+    fn take(session: &mut RwLockWriteGuard<Box<dyn Store>>, version: u64) -> Result<Self> {
+        let mut snapshot = Self { version, invisible: HashSet::new() };
+        let mut scan =
+            session.scan(Range::from(Key::TxnActive(0).encode()..Key::TxnActive(version).encode()));
+        while let Some((key, _)) = scan.next().transpose()? {
+            match Key::decode(&key)? {
+                Key::TxnActive(id) => snapshot.invisible.insert(id),
+                k => return Err(Error::Internal(format!("Expected TxnActive, got {:?}", k))),
+            };
+        }
+        drop(scan);
+        session.set(&Key::TxnSnapshot(version).encode(), serialize(&snapshot.invisible)?)?;
+        Ok(snapshot)
+    }
+
+    /// Restores an existing snapshot from `Key::TxnSnapshot(version)`, or errors if not found.
+    fn restore(session: &RwLockReadGuard<Box<dyn Store>>, version: u64) -> Result<Self> {
+        match session.get(&Key::TxnSnapshot(version).encode())? {
+            Some(ref v) => Ok(Self { version, invisible: deserialize(v)? }),
+            None => Err(Error::Value(format!("Snapshot not found for version {}", version))),
+        }
+    }
+
+    /// Checks whether the given version is visible in this snapshot.
+    fn is_visible(&self, version: u64) -> bool {
+        version <= self.version && !self.invisible.contains(&version)
+    }
+}
+
+/// MVCC keys. The encoding preserves the grouping and ordering of keys. Uses a Cow since we want
+/// to take borrows when encoding and return owned when decoding.
+#[derive(Debug)]
+enum Key<'a> {
+    /// The next available transaction ID. Used when starting new transactions.
+    TxnNext,
+    /// Active transaction markers, containing the mode. Used to detect concurrent transactions,
+    /// and to resume.
+    TxnActive(u64),
+    /// Transaction snapshot, containing concurrent active transactions at start of transaction.
+    TxnSnapshot(u64),
+    /// Update marker for a transaction ID and key, used for rollback.
+    TxnUpdate(u64, Cow<'a, [u8]>),
+    /// A record for a key/version pair.
+    Record(Cow<'a, [u8]>, u64),
+}
+
+impl<'a> Key<'a> {
+    /// Encodes a key into a byte vector.
+    fn encode(self) -> Vec<u8> {
+        match self {
+            Self::TxnNext => vec![0x01],
+            Self::TxnActive(id) => [&[0x02][..], &id.encode()].concat(),
+            Self::TxnSnapshot(version) => [&[0x03][..], &version.encode()].concat(),
+            Self::TxnUpdate(id, key) => [&[0x04][..], &id.encode(), &encode_bytes(&key)].concat(),
+            Self::Record(key, version) => {
+                [&[0xff][..], &encode_bytes(&key), &version.encode()].concat()
+            }
+        }
+    }
+
+    /// Decodes a key from a byte representation.
+    fn decode(mut bytes: &[u8]) -> Result<Self> {
+        let bytes = &mut bytes;
+        let key = match u8::decode_from(bytes)? {
+            0x01 => Self::TxnNext,
+            0x02 => Self::TxnActive(u64::decode_from(bytes)?),
+            0x03 => Self::TxnSnapshot(u64::decode_from(bytes)?),
+            0x04 => Self::TxnUpdate(u64::decode_from(bytes)?, take_bytes(bytes)?.into()),
+            0xff => Self::Record(take_bytes(bytes)?.into(), u64::decode_from(bytes)?),
+            b => return Err(Error::Internal(format!("Unknown MVCC key prefix {:x?}", b))),
+        };
+        if !bytes.is_empty() {
+            return Err(Error::Internal("Unexpected data remaining at end of key".into()));
+        }
+        Ok(key)
+    }
+}
+
+/// A key range scan.
+pub struct Scan {
+    /// The augmented store iterator, with key (decoded) and value. Note that we don't retain the
+    /// decoded version, so there will be multiple keys (for each version). We want the last.
+    scan: Peekable<super::Scan>,
+    /// Keeps track of next_back() seen key, whose previous versions should be ignored.
+    next_back_seen: Option<Vec<u8>>,
+}
+
+impl Scan {
+    /// Creates a new scan.
+    fn new(mut scan: super::Scan, snapshot: Snapshot) -> Self {
+        // Augment the underlying scan to decode the key and filter invisible versions. We don't
+        // return the version, since we don't need it, but beware that all versions of the key
+        // will still be returned - we usually only need the last, which is what the next() and
+        // next_back() methods need to handle. We also don't decode the value, since we only need
+        // to decode the last version.
+        scan = Box::new(scan.filter_map(move |r| {
+            r.and_then(|(k, v)| match Key::decode(&k)? {
+                Key::Record(_, version) if !snapshot.is_visible(version) => Ok(None),
+                Key::Record(key, _) => Ok(Some((key.into_owned(), v))),
+                k => Err(Error::Internal(format!("Expected Record, got {:?}", k))),
+            })
+            .transpose()
+        }));
+        Self { scan: scan.peekable(), next_back_seen: None }
+    }
+
+    // next() with error handling.
+    fn try_next(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        while let Some((key, value)) = self.scan.next().transpose()? {
+            // Only return the item if it is the last version of the key.
+            if match self.scan.peek() {
+                Some(Ok((peek_key, _))) if *peek_key != key => true,
+                Some(Ok(_)) => false,
+                Some(Err(err)) => return Err(err.clone()),
+                None => true,
+            } {
+                // Only return non-deleted items.
+                if let Some(value) = deserialize(&value)? {
+                    return Ok(Some((key, value)));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// next_back() with error handling.
+    fn try_next_back(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        while let Some((key, value)) = self.scan.next_back().transpose()? {
+            // Only return the last version of the key (so skip if seen).
+            if match &self.next_back_seen {
+                Some(seen_key) if *seen_key != key => true,
+                Some(_) => false,
+                None => true,
+            } {
+                self.next_back_seen = Some(key.clone());
+                // Only return non-deleted items.
+                if let Some(value) = deserialize(&value)? {
+                    return Ok(Some((key, value)));
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Iterator for Scan {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.try_next().transpose()
+    }
+}
+
+impl DoubleEndedIterator for Scan {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.try_next_back().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::Memory;
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn setup() -> MVCC {
+        MVCC::new(Box::new(Memory::new()))
+    }
+
+    #[test]
+    fn begin() -> Result<()> {
+        let mvcc = setup();
+
+        let txn = mvcc.begin()?;
+        assert_eq!(1, txn.id());
+        assert_eq!(Mode::ReadWrite, txn.mode());
+        txn.commit()?;
+
+        let txn = mvcc.begin()?;
+        assert_eq!(2, txn.id());
+        txn.rollback()?;
+
+        let txn = mvcc.begin()?;
+        assert_eq!(3, txn.id());
+        txn.commit()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn begin_read_only() -> Result<()> {
+        let mvcc = setup();
+        let mut txn = mvcc.begin_with_mode(Mode::ReadOnly)?;
+        assert_eq!(1, txn.id());
+        assert_eq!(Mode::ReadOnly, txn.mode());
+
+        assert_eq!(Err(Error::ReadOnly), txn.set(b"foo", vec![0x01]));
+        assert_eq!(Err(Error::ReadOnly), txn.delete(b"foo"));
+
+        txn.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn begin_snapshot() -> Result<()> {
+        let mvcc = setup();
+
+        // Write a version 1 (a=1, b=1), a concurrent uncommitted version 2 (a=2), and a committed
+        // version 3 (b=3). A snapshot at version 3 should see b=3, but not the uncommitted a=2.
+        let mut txn = mvcc.begin()?;
+        txn.set(b"a", vec![0x01])?;
+        txn.set(b"b", vec![0x01])?;
+        txn.commit()?;
+
+        let mut txn2 = mvcc.begin()?;
+        txn2.set(b"a", vec![0x02])?;
+
+        let mut txn3 = mvcc.begin()?;
+        txn3.set(b"b", vec![0x03])?;
+        txn3.commit()?;
+
+        txn2.commit()?;
+
+        let mut txn = mvcc.begin_with_mode(Mode::Snapshot { version: 3 })?;
+        assert_eq!(4, txn.id());
+        assert_eq!(Mode::Snapshot { version: 3 }, txn.mode());
+        assert_eq!(Some(vec![0x01]), txn.get(b"a")?);
+        assert_eq!(Some(vec![0x03]), txn.get(b"b")?);
+        assert_eq!(Err(Error::ReadOnly), txn.set(b"a", vec![0x04]));
+        txn.commit()?;
+
+        // A snapshot at version 1 should only see the initial writes.
+        let txn = mvcc.begin_with_mode(Mode::Snapshot { version: 1 })?;
+        assert_eq!(
+            vec![(b"a".to_vec(), vec![0x01]), (b"b".to_vec(), vec![0x01])],
+            txn.scan(..)?.collect::<Result<Vec<_>>>()?
+        );
+        txn.commit()?;
+
+        // Snapshots of unknown versions should error.
+        assert!(matches!(
+            mvcc.begin_with_mode(Mode::Snapshot { version: 9 }),
+            Err(Error::Value(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn resume() -> Result<()> {
+        let mvcc = setup();
+
+        // We first write a set of values that should be visible.
+        let mut t1 = mvcc.begin()?;
+        t1.set(b"a", b"t1".to_vec())?;
+        t1.set(b"b", b"t1".to_vec())?;
+        t1.commit()?;
+
+        // We then start three transactions, of which we will resume t3. We commit t2 and t4's
+        // changes, which should not be visible, and write a change for t3 which should be visible.
+        let mut t2 = mvcc.begin()?;
+        let mut t3 = mvcc.begin()?;
+        let mut t4 = mvcc.begin()?;
+
+        t2.set(b"a", b"t2".to_vec())?;
+        t3.set(b"b", b"t3".to_vec())?;
+        t4.set(b"c", b"t4".to_vec())?;
+
+        t2.commit()?;
+        t4.commit()?;
+
+        // We now resume t3, who should see it's own changes but none of the others'.
+        let id = t3.id();
+        drop(t3);
+        let tr = mvcc.resume(id)?;
+        assert_eq!(3, tr.id());
+        assert_eq!(Mode::ReadWrite, tr.mode());
+
+        assert_eq!(Some(b"t1".to_vec()), tr.get(b"a")?);
+        assert_eq!(Some(b"t3".to_vec()), tr.get(b"b")?);
+        assert_eq!(None, tr.get(b"c")?);
+
+        // Once tr commits, a separate transaction should see t3's changes.
+        tr.commit()?;
+
+        let t = mvcc.begin()?;
+        assert_eq!(Some(b"t2".to_vec()), t.get(b"a")?);
+        assert_eq!(Some(b"t3".to_vec()), t.get(b"b")?);
+        assert_eq!(Some(b"t4".to_vec()), t.get(b"c")?);
+        t.rollback()?;
+
+        // It should also be possible to start a snapshot transaction and resume it.
+        let ts = mvcc.begin_with_mode(Mode::Snapshot { version: 1 })?;
+        assert_eq!(6, ts.id());
+        assert_eq!(Some(b"t1".to_vec()), ts.get(b"b")?);
+
+        let id = ts.id();
+        drop(ts);
+        let ts = mvcc.resume(id)?;
+        assert_eq!(6, ts.id());
+        assert_eq!(Mode::Snapshot { version: 1 }, ts.mode());
+        assert_eq!(Some(b"t1".to_vec()), ts.get(b"b")?);
+        ts.commit()?;
+
+        // Resuming an inactive transaction should error.
+        assert_eq!(Err(Error::Value("No active transaction 6".into())), mvcc.resume(6).map(|_| ()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn txn_delete_conflict() -> Result<()> {
+        let mvcc = setup();
+
+        let mut txn = mvcc.begin()?;
+        txn.set(b"key", vec![0x00])?;
+        txn.commit()?;
+
+        let mut t1 = mvcc.begin()?;
+        let mut t2 = mvcc.begin()?;
+        let mut t3 = mvcc.begin()?;
+
+        t2.delete(b"key")?;
+        assert_eq!(Err(Error::Serialization), t1.delete(b"key"));
+        assert_eq!(Err(Error::Serialization), t3.delete(b"key"));
+        t2.commit()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn txn_get() -> Result<()> {
+        let mvcc = setup();
+
+        let mut txn = mvcc.begin()?;
+        assert_eq!(None, txn.get(b"a")?);
+        txn.set(b"a", vec![0x01])?;
+        assert_eq!(Some(vec![0x01]), txn.get(b"a")?);
+        txn.set(b"a", vec![0x02])?;
+        assert_eq!(Some(vec![0x02]), txn.get(b"a")?);
+        txn.commit()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn txn_get_deleted() -> Result<()> {
+        let mvcc = setup();
+        let mut txn = mvcc.begin()?;
+        txn.set(b"a", vec![0x01])?;
+        txn.commit()?;
+
+        let mut txn = mvcc.begin()?;
+        txn.delete(b"a")?;
+        txn.commit()?;
+
+        let txn = mvcc.begin()?;
+        assert_eq!(None, txn.get(b"a")?);
+        txn.commit()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn txn_get_hides_newer() -> Result<()> {
+        let mvcc = setup();
+
+        let mut t1 = mvcc.begin()?;
+        let mut t2 = mvcc.begin()?;
+        let mut t3 = mvcc.begin()?;
+
+        t1.set(b"a", vec![0x01])?;
+        t1.commit()?;
+        t3.set(b"c", vec![0x03])?;
+        t3.commit()?;
+
+        assert_eq!(None, t2.get(b"a")?);
+        assert_eq!(None, t2.get(b"c")?);
+        t2.set(b"b", vec![0x02])?;
+        t2.commit()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn txn_get_hides_uncommitted() -> Result<()> {
+        let mvcc = setup();
+
+        let mut t1 = mvcc.begin()?;
+        t1.set(b"a", vec![0x01])?;
+        let t2 = mvcc.begin()?;
+        let mut t3 = mvcc.begin()?;
+        t3.set(b"c", vec![0x03])?;
+
+        assert_eq!(None, t2.get(b"a")?);
+        assert_eq!(None, t2.get(b"c")?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn txn_get_readonly_historical() -> Result<()> {
+        let mvcc = setup();
+
+        let mut txn = mvcc.begin()?;
+        txn.set(b"a", vec![0x01])?;
+        txn.commit()?;
+
+        let mut txn = mvcc.begin()?;
+        txn.set(b"b", vec![0x02])?;
+        txn.commit()?;
+
+        let mut txn = mvcc.begin()?;
+        txn.set(b"c", vec![0x03])?;
+        txn.commit()?;
+
+        let tr = mvcc.begin_with_mode(Mode::Snapshot { version: 2 })?;
+        assert_eq!(Some(vec![0x01]), tr.get(b"a")?);
+        assert_eq!(Some(vec![0x02]), tr.get(b"b")?);
+        assert_eq!(None, tr.get(b"c")?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn txn_rollback() -> Result<()> {
+        let mvcc = setup();
+
+        let mut txn = mvcc.begin()?;
+        txn.set(b"a", vec![0x00])?;
+        txn.set(b"b", vec![0x00])?;
+        txn.set(b"c", vec![0x00])?;
+        txn.set(b"d", vec![0x00])?;
+        txn.commit()?;
+
+        let mut t1 = mvcc.begin()?;
+        let mut t2 = mvcc.begin()?;
+        let mut t3 = mvcc.begin()?;
+
+        t1.set(b"a", vec![0x01])?;
+        t2.set(b"b", vec![0x02])?;
+        t2.delete(b"c")?;
+        t3.set(b"d", vec![0x03])?;
+
+        // t2 is rolled back, while t1 and t3 commit.
+        t2.rollback()?;
+        t1.commit()?;
+        t3.commit()?;
+
+        let txn = mvcc.begin()?;
+        assert_eq!(
+            vec![
+                (b"a".to_vec(), vec![0x01]),
+                (b"b".to_vec(), vec![0x00]),
+                (b"c".to_vec(), vec![0x00]),
+                (b"d".to_vec(), vec![0x03]),
+            ],
+            txn.scan(..)?.collect::<Result<Vec<_>>>()?
+        );
+
+        // Writing to b and c should no longer conflict.
+        let mut txn = mvcc.begin()?;
+        txn.set(b"b", vec![0x04])?;
+        txn.delete(b"c")?;
+        txn.commit()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn txn_scan() -> Result<()> {
+        let mvcc = setup();
+        let mut txn = mvcc.begin()?;
+
+        txn.set(b"a", vec![0x01])?;
+
+        txn.delete(b"b")?;
+
+        txn.set(b"c", vec![0x01])?;
+        txn.set(b"c", vec![0x02])?;
+        txn.delete(b"c")?;
+        txn.set(b"c", vec![0x03])?;
+
+        txn.set(b"d", vec![0x01])?;
+        txn.set(b"d", vec![0x02])?;
+        txn.set(b"d", vec![0x03])?;
+        txn.set(b"d", vec![0x04])?;
+        txn.delete(b"d")?;
+
+        txn.set(b"e", vec![0x01])?;
+        txn.set(b"e", vec![0x02])?;
+        txn.set(b"e", vec![0x03])?;
+        txn.delete(b"e")?;
+        txn.set(b"e", vec![0x04])?;
+        txn.set(b"e", vec![0x05])?;
+        txn.commit()?;
+
+        // Forward scan
+        let txn = mvcc.begin()?;
+        assert_eq!(
+            vec![
+                (b"a".to_vec(), vec![0x01]),
+                (b"c".to_vec(), vec![0x03]),
+                (b"e".to_vec(), vec![0x05]),
+            ],
+            txn.scan(..)?.collect::<Result<Vec<_>>>()?
+        );
+
+        // Reverse scan
+        assert_eq!(
+            vec![
+                (b"e".to_vec(), vec![0x05]),
+                (b"c".to_vec(), vec![0x03]),
+                (b"a".to_vec(), vec![0x01]),
+            ],
+            txn.scan(..)?.rev().collect::<Result<Vec<_>>>()?
+        );
+
+        // Alternate forward/backward scan
+        let mut scan = txn.scan(..)?;
+        assert_eq!(Some((b"a".to_vec(), vec![0x01])), scan.next().transpose()?);
+        assert_eq!(Some((b"e".to_vec(), vec![0x05])), scan.next_back().transpose()?);
+        assert_eq!(Some((b"c".to_vec(), vec![0x03])), scan.next_back().transpose()?);
+        assert_eq!(None, scan.next().transpose()?);
+        drop(scan);
+
+        // Bounded scans
+        assert_eq!(
+            vec![(b"c".to_vec(), vec![0x03])],
+            txn.scan(b"b".to_vec()..b"e".to_vec())?.collect::<Result<Vec<_>>>()?
+        );
+        assert_eq!(
+            vec![(b"c".to_vec(), vec![0x03]), (b"e".to_vec(), vec![0x05])],
+            txn.scan(b"c".to_vec()..=b"e".to_vec())?.collect::<Result<Vec<_>>>()?
+        );
+        txn.commit()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn txn_scan_prefix() -> Result<()> {
+        let mvcc = setup();
+        let mut txn = mvcc.begin()?;
+
+        txn.set(b"a", vec![0x01])?;
+        txn.set(b"az", vec![0x01, 0x1a])?;
+        txn.set(b"b", vec![0x02])?;
+        txn.set(b"ba", vec![0x02, 0x01])?;
+        txn.set(b"bb", vec![0x02, 0x02])?;
+        txn.set(b"bc", vec![0x02, 0x03])?;
+        txn.set(b"c", vec![0x03])?;
+        txn.commit()?;
+
+        let txn = mvcc.begin()?;
+        assert_eq!(
+            vec![
+                (b"b".to_vec(), vec![0x02]),
+                (b"ba".to_vec(), vec![0x02, 0x01]),
+                (b"bb".to_vec(), vec![0x02, 0x02]),
+                (b"bc".to_vec(), vec![0x02, 0x03]),
+            ],
+            txn.scan_prefix(b"b")?.collect::<Result<Vec<_>>>()?
+        );
+        assert_eq!(
+            vec![(b"bb".to_vec(), vec![0x02, 0x02])],
+            txn.scan_prefix(b"bb")?.collect::<Result<Vec<_>>>()?
+        );
+        assert_eq!(7, txn.scan_prefix(b"")?.count());
+        txn.commit()?;
+
+        // Prefixes ending in 0xff bytes should not include the following keys.
+        let mut txn = mvcc.begin()?;
+        txn.set(b"a\xff", vec![0x01, 0xff])?;
+        txn.set(b"\xff\xff", vec![0xff, 0xff])?;
+        assert_eq!(
+            vec![(b"a\xff".to_vec(), vec![0x01, 0xff])],
+            txn.scan_prefix(b"a\xff")?.collect::<Result<Vec<_>>>()?
+        );
+        assert_eq!(
+            vec![(b"\xff\xff".to_vec(), vec![0xff, 0xff])],
+            txn.scan_prefix(b"\xff")?.collect::<Result<Vec<_>>>()?
+        );
+        txn.commit()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn txn_set_conflict() -> Result<()> {
+        let mvcc = setup();
+
+        let mut t1 = mvcc.begin()?;
+        let mut t2 = mvcc.begin()?;
+        let mut t3 = mvcc.begin()?;
+
+        t2.set(b"key", vec![0x02])?;
+        assert_eq!(Err(Error::Serialization), t1.set(b"key", vec![0x01]));
+        assert_eq!(Err(Error::Serialization), t3.set(b"key", vec![0x03]));
+        t2.commit()?;
+
+        // Once t2 commits, t3 still can't write the key, since it began before t2 committed.
+        assert_eq!(Err(Error::Serialization), t3.set(b"key", vec![0x03]));
+
+        // A new transaction can, however.
+        let mut t4 = mvcc.begin()?;
+        t4.set(b"key", vec![0x04])?;
+        t4.commit()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn txn_anomaly_lost_update() -> Result<()> {
+        let mvcc = setup();
+
+        let mut t1 = mvcc.begin()?;
+        let mut t2 = mvcc.begin()?;
+
+        t1.get(b"key")?;
+        t2.get(b"key")?;
+
+        t1.set(b"key", b"t1".to_vec())?;
+        assert_eq!(Err(Error::Serialization), t2.set(b"key", b"t2".to_vec()));
+        t1.commit()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn txn_anomaly_read_skew() -> Result<()> {
+        let mvcc = setup();
+
+        let mut t0 = mvcc.begin()?;
+        t0.set(b"a", b"t0".to_vec())?;
+        t0.set(b"b", b"t0".to_vec())?;
+        t0.commit()?;
+
+        let t1 = mvcc.begin()?;
+        let mut t2 = mvcc.begin()?;
+
+        assert_eq!(Some(b"t0".to_vec()), t1.get(b"a")?);
+        t2.set(b"a", b"t2".to_vec())?;
+        t2.set(b"b", b"t2".to_vec())?;
+        t2.commit()?;
+        assert_eq!(Some(b"t0".to_vec()), t1.get(b"b")?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn txn_anomaly_phantom_read() -> Result<()> {
+        let mvcc = setup();
+
+        let mut t0 = mvcc.begin()?;
+        t0.set(b"a", b"true".to_vec())?;
+        t0.set(b"b", b"false".to_vec())?;
+        t0.commit()?;
+
+        let t1 = mvcc.begin()?;
+        let mut t2 = mvcc.begin()?;
+
+        let mut result = t1.scan(..)?;
+        assert_eq!(Some((b"a".to_vec(), b"true".to_vec())), result.next().transpose()?);
+        assert_eq!(Some((b"b".to_vec(), b"false".to_vec())), result.next().transpose()?);
+        assert_eq!(None, result.next().transpose()?);
+        drop(result);
+
+        t2.set(b"b", b"true".to_vec())?;
+        t2.commit()?;
+
+        let mut result = t1.scan(..)?;
+        assert_eq!(Some((b"a".to_vec(), b"true".to_vec())), result.next().transpose()?);
+        assert_eq!(Some((b"b".to_vec(), b"false".to_vec())), result.next().transpose()?);
+        assert_eq!(None, result.next().transpose()?);
+
+        Ok(())
+    }
+}
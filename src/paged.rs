@@ -0,0 +1,1909 @@
+use super::async_store::{blocking, stream, AsyncScan};
+use super::buffer::{BufferPool, CacheStats, Eviction, DEFAULT_CACHE_SIZE};
+use super::pager::{PageId, Pager, DEFAULT_PAGE_SIZE, META_PAGE};
+use super::{Range, Scan, Store, WriteBatch};
+use crate::error::{Error, Result};
+
+use async_trait::async_trait;
+use log::{debug, error, info, trace, warn};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fmt::Display;
+use std::ops::{Bound, RangeBounds};
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+/// File-backed key-value store using a B+tree laid out in fixed-size pages, one node per page.
+/// Pages are read on demand through a bounded buffer pool, which keeps modified pages in memory
+/// until they are evicted, while flush() writes back all modified pages and syncs the file to
+/// durable storage. The buffer pool size bounds the memory used by the tree.
+///
+/// Unlike the in-memory B+tree, nodes hold variable-sized keys and values, so node capacity is
+/// measured in bytes rather than items: a node is split when its encoded size exceeds the page
+/// capacity, and rebalanced with a sibling when it drops below a quarter of the page capacity. To
+/// guarantee that split nodes fit in a page, key/value pairs can use at most a quarter of a page.
+/// Pages freed by merges and root collapses are kept in a free list in the file, and reused by
+/// later splits before the file is extended.
+///
+/// Leaf nodes link to their sibling leaf nodes, which iterators use to step between leaves without
+/// looking them up from the root node. Stores in copy-on-write mode are the exception, see
+/// new_copy_on_write().
+pub struct Paged {
+    /// The B+tree, guarded by an RwLock to support multiple iterators across it.
+    tree: Arc<RwLock<Tree>>,
+}
+
+impl Display for Paged {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "paged")
+    }
+}
+
+impl Paged {
+    /// Opens a paged store in the given file using the default page size, or creates it if it
+    /// does not exist.
+    pub fn new(path: &Path) -> Result<Self> {
+        Self::new_with_page_size(path, DEFAULT_PAGE_SIZE)
+    }
+
+    /// Opens a paged store in the given file using the given page size, or creates it if it does
+    /// not exist. The page size must match the page size the file was created with.
+    pub fn new_with_page_size(path: &Path, page_size: usize) -> Result<Self> {
+        Self::new_with_cache(path, page_size, DEFAULT_CACHE_SIZE, Eviction::Lru)
+    }
+
+    /// Opens a paged store in the given file using the given page size, or creates it if it does
+    /// not exist, with a buffer pool of cache_size pages using the given eviction policy. Existing
+    /// files created in copy-on-write mode are opened in copy-on-write mode.
+    pub fn new_with_cache(
+        path: &Path,
+        page_size: usize,
+        cache_size: usize,
+        eviction: Eviction,
+    ) -> Result<Self> {
+        Self::open(path, page_size, cache_size, eviction, false)
+    }
+
+    /// Opens a paged store in copy-on-write mode in the given file using the given page size, or
+    /// creates it if it does not exist. Existing files must have been created in copy-on-write
+    /// mode, since the mode can't be changed later.
+    ///
+    /// In copy-on-write mode, pages that have been flushed are never modified. Writes copy the
+    /// modified nodes and their ancestors up to the root node into new pages, and only install the
+    /// new root page once they succeed, so a failed write or batch leaves the store unchanged.
+    /// flush() commits the new root page by writing it to the file metadata once all other pages
+    /// are durable, while unflushed writes are discarded when the store is dropped. The store
+    /// therefore recovers to its state as of the last flush after a crash, without needing a
+    /// write-ahead log, and the flushed tree can be read as a consistent snapshot while it is being
+    /// modified, see snapshot(). Leaf nodes aren't linked to their siblings, since relinking them
+    /// would require copying the siblings as well, so iterators look up sibling leaves from the
+    /// root node instead. Pages of old trees are freed for reuse once a new root has been flushed,
+    /// unless snapshots exist, in which case they are freed on a later flush without snapshots.
+    pub fn new_copy_on_write(path: &Path, page_size: usize) -> Result<Self> {
+        Self::open(path, page_size, DEFAULT_CACHE_SIZE, Eviction::Lru, true)
+    }
+
+    /// Opens a paged store, see new_with_cache(). If copy_on_write is true, new files are created
+    /// in copy-on-write mode, and existing files must be in copy-on-write mode.
+    fn open(
+        path: &Path,
+        page_size: usize,
+        cache_size: usize,
+        eviction: Eviction,
+        copy_on_write: bool,
+    ) -> Result<Self> {
+        let mut pool = BufferPool::new(Pager::open(path, page_size)?, cache_size, eviction)?;
+        if pool.root() == 0 {
+            pool.set_copy_on_write(copy_on_write);
+            let root = pool.allocate()?;
+            pool.write(root, &bincode::serialize(&Node::Leaf(Leaf::default()))?)?;
+            pool.set_root(root);
+            pool.flush()?;
+            info!("Created paged store {:?} with page size {}", path, page_size);
+        } else if copy_on_write && !pool.copy_on_write() {
+            return Err(Error::Config(format!("{:?} is not a copy-on-write store", path)));
+        } else {
+            info!("Opened paged store {:?} with root page {}", path, pool.root());
+        }
+        let tree = Tree {
+            copy_on_write: pool.copy_on_write(),
+            fresh: HashSet::new(),
+            allocated: HashSet::new(),
+            released: Vec::new(),
+            retired: Vec::new(),
+            flushed_root: pool.root(),
+            snapshots: Arc::new(()),
+            pool,
+            version: 0,
+        };
+        Ok(Self { tree: Arc::new(RwLock::new(tree)) })
+    }
+
+    /// Returns buffer pool statistics.
+    pub fn cache_stats(&self) -> Result<CacheStats> {
+        self.tree.read()?.pool.stats()
+    }
+
+    /// Returns a read-only snapshot of the store as of the last flush, which is unaffected by later
+    /// writes. Only stores in copy-on-write mode support snapshots.
+    pub fn snapshot(&self) -> Result<PagedSnapshot> {
+        let tree = self.tree.read()?;
+        if !tree.copy_on_write {
+            return Err(Error::Config("Snapshots require a copy-on-write store".into()));
+        }
+        Ok(PagedSnapshot {
+            tree: self.tree.clone(),
+            root: tree.flushed_root,
+            _pages: tree.snapshots.clone(),
+        })
+    }
+
+    /// Returns statistics about the store's file and buffer pool.
+    pub fn stats(&self) -> Result<PagedStats> {
+        let tree = self.tree.read()?;
+        Ok(PagedStats {
+            pages: tree.pool.page_count(),
+            free_pages: tree.pool.free_count(),
+            cache: tree.pool.stats()?,
+        })
+    }
+
+    /// Verifies the integrity of a paged store file, without modifying it. Every page is read and
+    /// its checksum verified, and the tree is walked from the root node, checking key order,
+    /// separator keys, node sizes, leaf depths and leaf sibling links. Problems are collected in
+    /// the returned verification, and an error is only returned if the file can't be opened. The
+    /// file should not be open as a store while it is verified.
+    pub fn verify(path: &Path) -> Result<Verification> {
+        let pager = Pager::open_read_only(path)?;
+        Ok(Verifier::new(&pager).verify())
+    }
+
+    /// Salvages all readable key/value pairs from a paged store file into a new paged store in the
+    /// target file, returning the number of key/value pairs salvaged. Leaf nodes are found both by
+    /// walking the tree from the root node and by following leaf sibling links, skipping pages
+    /// that can't be read, such that leaves below a corrupt inner node can still be recovered.
+    /// If any pages can't be read, all other pages that aren't free are also scanned for leaf
+    /// nodes, since leaves aren't linked in copy-on-write mode. These may be stale leaves of older
+    /// trees, so their keys are only salvaged if they weren't found otherwise. The source file is
+    /// not modified.
+    pub fn salvage(path: &Path, target: &Path) -> Result<u64> {
+        if target.exists() {
+            return Err(Error::Config(format!("Salvage target {:?} already exists", target)));
+        }
+        let pager = Pager::open_read_only(path)?;
+        let mut store = Self::new_with_page_size(target, pager.page_size())?;
+        let (mut count, mut visited, mut stack) = (0, HashSet::new(), vec![pager.root()]);
+        let mut unreadable = false;
+        while let Some(id) = stack.pop() {
+            if id == META_PAGE || id >= pager.page_count() || !visited.insert(id) {
+                continue;
+            }
+            match pager.read(id).and_then(|body| Ok(bincode::deserialize::<Node>(&body)?)) {
+                Ok(Node::Inner(inner)) => stack.extend(inner.children),
+                Ok(Node::Leaf(leaf)) => {
+                    stack.extend(leaf.prev.into_iter().chain(leaf.next));
+                    for (key, value) in leaf.items {
+                        store.set(&key, value)?;
+                        count += 1;
+                    }
+                }
+                Err(err) => {
+                    warn!("Skipping unreadable page {}: {}", id, err);
+                    unreadable = true;
+                }
+            }
+        }
+        if unreadable {
+            let free = match pager.read_free_list() {
+                Ok((lists, free)) => lists.into_iter().chain(free).collect(),
+                Err(err) => {
+                    warn!("Scanning free pages, since the free list is unreadable: {}", err);
+                    HashSet::new()
+                }
+            };
+            for id in META_PAGE + 1..pager.page_count() {
+                if visited.contains(&id) || free.contains(&id) {
+                    continue;
+                }
+                if let Ok(Node::Leaf(leaf)) =
+                    pager.read(id).and_then(|body| Ok(bincode::deserialize::<Node>(&body)?))
+                {
+                    for (key, value) in leaf.items {
+                        if store.get(&key)?.is_none() {
+                            store.set(&key, value)?;
+                            count += 1;
+                        }
+                    }
+                }
+            }
+        }
+        store.flush()?;
+        info!("Salvaged {} key/value pairs from {:?} into {:?}", count, path, target);
+        Ok(count)
+    }
+}
+
+impl Store for Paged {
+    fn delete(&mut self, key: &[u8]) -> Result<()> {
+        self.tree.write()?.delete(key)
+    }
+
+    fn delete_range(&mut self, range: Range) -> Result<usize> {
+        self.tree.write()?.delete_range(&range)
+    }
+
+    fn cursor(&self) -> Box<dyn super::Cursor> {
+        Box::new(Cursor::new(self.tree.clone(), None))
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.tree.write()?.flush()
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let tree = self.tree.read()?;
+        tree.get(tree.pool.root(), key)
+    }
+
+    fn scan(&self, range: Range) -> Scan {
+        Box::new(Iter::new(self.tree.clone(), None, range))
+    }
+
+    fn set(&mut self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        self.tree.write()?.set(key, value)
+    }
+
+    fn write(&mut self, batch: WriteBatch) -> Result<()> {
+        self.tree.write()?.apply(batch)
+    }
+}
+
+/// Paged implements AsyncStore directly, but its I/O is not natively asynchronous: like
+/// AsyncAdapter, it runs each operation on tokio's blocking thread pool, where it takes the tree
+/// lock and does synchronous file I/O. Unlike AsyncAdapter, it doesn't need an outer lock around
+/// the store, so reads run concurrently with each other.
+#[async_trait]
+impl super::AsyncStore for Paged {
+    async fn delete(&self, key: &[u8]) -> Result<()> {
+        let (tree, key) = (self.tree.clone(), key.to_vec());
+        blocking(move || tree.write()?.delete(&key)).await
+    }
+
+    async fn flush(&self) -> Result<()> {
+        let tree = self.tree.clone();
+        blocking(move || tree.write()?.flush()).await
+    }
+
+    async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let (tree, key) = (self.tree.clone(), key.to_vec());
+        blocking(move || {
+            let tree = tree.read()?;
+            tree.get(tree.pool.root(), &key)
+        })
+        .await
+    }
+
+    fn scan(&self, range: Range) -> AsyncScan {
+        let tree = self.tree.clone();
+        stream(move || Box::new(Iter::new(tree, None, range)))
+    }
+
+    async fn set(&self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        let (tree, key) = (self.tree.clone(), key.to_vec());
+        blocking(move || tree.write()?.set(&key, value)).await
+    }
+
+    async fn write(&self, batch: WriteBatch) -> Result<()> {
+        let tree = self.tree.clone();
+        blocking(move || tree.write()?.apply(batch)).await
+    }
+}
+
+/// A read-only snapshot of a paged store in copy-on-write mode, as of the last flush before it was
+/// taken. Flushed pages are never modified in copy-on-write mode, so the snapshot reads a
+/// consistent tree from the flushed root page regardless of later writes to the store. Writes
+/// return Error::ReadOnly.
+pub struct PagedSnapshot {
+    /// The store's B+tree.
+    tree: Arc<RwLock<Tree>>,
+    /// The root page of the snapshot.
+    root: PageId,
+    /// Keeps the store from freeing pages of old trees while the snapshot exists.
+    _pages: Arc<()>,
+}
+
+impl Display for PagedSnapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "paged snapshot")
+    }
+}
+
+impl Store for PagedSnapshot {
+    fn delete(&mut self, _: &[u8]) -> Result<()> {
+        Err(Error::ReadOnly)
+    }
+
+    fn delete_range(&mut self, _: Range) -> Result<usize> {
+        Err(Error::ReadOnly)
+    }
+
+    fn cursor(&self) -> Box<dyn super::Cursor> {
+        Box::new(Cursor::new(self.tree.clone(), Some(self.root)))
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.tree.read()?.get(self.root, key)
+    }
+
+    fn scan(&self, range: Range) -> Scan {
+        Box::new(Iter::new(self.tree.clone(), Some(self.root), range))
+    }
+
+    fn set(&mut self, _: &[u8], _: Vec<u8>) -> Result<()> {
+        Err(Error::ReadOnly)
+    }
+
+    fn write(&mut self, _: WriteBatch) -> Result<()> {
+        Err(Error::ReadOnly)
+    }
+}
+
+/// Paged store statistics.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PagedStats {
+    /// The number of pages in the file, including the metadata page.
+    pub pages: u64,
+    /// The number of free pages, which are reused before the file is extended.
+    pub free_pages: u64,
+    /// Buffer pool statistics.
+    pub cache: CacheStats,
+}
+
+/// The result of verifying a paged store file with Paged::verify().
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Verification {
+    /// The number of pages in the file, including the metadata page.
+    pub pages: u64,
+    /// The number of keys in readable leaf nodes reachable from the root node.
+    pub keys: u64,
+    /// The number of node levels, including the root node.
+    pub height: usize,
+    /// Integrity problems, as Error::Corruption errors naming the affected page.
+    pub errors: Vec<Error>,
+    /// Nodes below the minimum node size. This is not an error, since replacing values with
+    /// smaller ones does not rebalance the tree.
+    pub warnings: Vec<Error>,
+    /// The number of free pages in the free list.
+    pub free: u64,
+    /// Pages that are neither reachable from the root node nor free. These are left behind by a
+    /// crash, or in copy-on-write mode by pages of older trees that were still used by snapshots
+    /// on the last flush, when the store is closed without flushing again.
+    pub orphans: Vec<u64>,
+}
+
+impl Verification {
+    /// Returns true if no integrity problems were found.
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+/// Verifies a paged store file, see Paged::verify().
+struct Verifier<'a> {
+    /// The pager for the file.
+    pager: &'a Pager,
+    /// The verification result.
+    report: Verification,
+    /// Pages reachable from the root node.
+    visited: HashSet<PageId>,
+    /// Leaf nodes in key order, as their page ID and sibling links.
+    leaves: Vec<(PageId, Option<PageId>, Option<PageId>)>,
+}
+
+impl<'a> Verifier<'a> {
+    /// Creates a new verifier for the given pager.
+    fn new(pager: &'a Pager) -> Self {
+        let report = Verification { pages: pager.page_count(), ..Default::default() };
+        Self { pager, report, visited: HashSet::new(), leaves: Vec::new() }
+    }
+
+    /// Verifies the file, returning the result.
+    fn verify(mut self) -> Verification {
+        let root = self.pager.root();
+        if root == META_PAGE || root >= self.pager.page_count() {
+            self.error(META_PAGE, format!("root page {} is out of bounds", root));
+        } else {
+            self.node(root, 1, None, None);
+        }
+        self.links();
+        self.free_list();
+
+        // Pages that aren't reachable are still read, to verify their checksums.
+        for id in META_PAGE + 1..self.pager.page_count() {
+            if !self.visited.contains(&id) {
+                if let Err(err) = self.pager.read(id) {
+                    self.report.errors.push(err);
+                }
+                self.report.orphans.push(id);
+            }
+        }
+        self.report
+    }
+
+    /// Records an integrity problem for a page.
+    fn error(&mut self, id: PageId, message: String) {
+        self.report.errors.push(Error::Corruption(id, message))
+    }
+
+    /// Verifies that the free list pages and the free pages they list aren't reachable from the
+    /// root node, and marks them as visited.
+    fn free_list(&mut self) {
+        let (lists, free) = match self.pager.read_free_list() {
+            Ok(free_list) => free_list,
+            Err(err) => return self.report.errors.push(err),
+        };
+        self.report.free = free.len() as u64;
+        for id in lists.into_iter().chain(free) {
+            if !self.visited.insert(id) {
+                self.error(id, "free page is in use".into());
+            }
+        }
+    }
+
+    /// Verifies that the leaf sibling links link all leaf nodes in key order, or that leaf nodes
+    /// aren't linked in copy-on-write mode.
+    fn links(&mut self) {
+        let linked = !self.pager.copy_on_write();
+        for i in 0..self.leaves.len() {
+            let (id, prev, next) = self.leaves[i];
+            let expect_prev = i.checked_sub(1).filter(|_| linked).map(|i| self.leaves[i].0);
+            let expect_next = self.leaves.get(i + 1).filter(|_| linked).map(|(id, _, _)| *id);
+            if prev != expect_prev {
+                self.error(id, format!("previous leaf is {:?}, expected {:?}", prev, expect_prev));
+            }
+            if next != expect_next {
+                self.error(id, format!("next leaf is {:?}, expected {:?}", next, expect_next));
+            }
+        }
+    }
+
+    /// Verifies the subtree at the given page and depth, whose keys must be within the given
+    /// lower (inclusive) and upper (exclusive) bounds.
+    fn node(&mut self, id: PageId, depth: usize, lower: Option<&[u8]>, upper: Option<&[u8]>) {
+        if !self.visited.insert(id) {
+            return self.error(id, "page is referenced more than once".into());
+        }
+        let node = match self.read(id) {
+            Ok(node) => node,
+            Err(err) => return self.report.errors.push(err),
+        };
+        let size = match &node {
+            Node::Inner(inner) => inner.size(),
+            Node::Leaf(leaf) => leaf.size(),
+        };
+        let capacity = self.pager.capacity();
+        if size > capacity {
+            self.error(id, format!("node size {} exceeds page capacity {}", size, capacity));
+        } else if depth > 1 && size < capacity / 4 {
+            let message = format!("node size {} is below minimum size {}", size, capacity / 4);
+            self.report.warnings.push(Error::Corruption(id, message));
+        }
+        let in_bounds = |key: &[u8]| {
+            lower.is_none_or(|lower| key >= lower) && upper.is_none_or(|upper| key < upper)
+        };
+
+        match node {
+            Node::Leaf(leaf) => {
+                if self.report.height == 0 {
+                    self.report.height = depth;
+                } else if self.report.height != depth {
+                    let expect = self.report.height;
+                    self.error(id, format!("leaf at depth {}, expected {}", depth, expect));
+                }
+                for (i, (key, _)) in leaf.items.iter().enumerate() {
+                    if i > 0 && leaf.items[i - 1].0 >= *key {
+                        self.error(id, format!("key {:x?} is out of order", key));
+                    }
+                    if !in_bounds(key) {
+                        self.error(id, format!("key {:x?} is outside separator bounds", key));
+                    }
+                }
+                self.report.keys += leaf.items.len() as u64;
+                self.leaves.push((id, leaf.prev, leaf.next));
+            }
+
+            Node::Inner(inner) => {
+                if inner.keys.is_empty() || inner.children.len() != inner.keys.len() + 1 {
+                    let (keys, children) = (inner.keys.len(), inner.children.len());
+                    let message = format!("{} separator keys for {} children", keys, children);
+                    return self.error(id, message);
+                }
+                for (i, key) in inner.keys.iter().enumerate() {
+                    if i > 0 && inner.keys[i - 1] >= *key {
+                        self.error(id, format!("separator key {:x?} is out of order", key));
+                    }
+                    if !in_bounds(key) {
+                        self.error(id, format!("separator key {:x?} is outside bounds", key));
+                    }
+                }
+                for (i, &child) in inner.children.iter().enumerate() {
+                    if child == META_PAGE || child >= self.pager.page_count() {
+                        self.error(id, format!("child page {} is out of bounds", child));
+                        continue;
+                    }
+                    let lower = if i == 0 { lower } else { Some(inner.keys[i - 1].as_slice()) };
+                    let upper = inner.keys.get(i).map(|key| key.as_slice()).or(upper);
+                    self.node(child, depth + 1, lower, upper);
+                }
+            }
+        }
+    }
+
+    /// Reads a node from a page. Undecodable nodes are returned as corruption errors.
+    fn read(&self, id: PageId) -> Result<Node> {
+        let body = self.pager.read(id)?;
+        bincode::deserialize(&body).map_err(|err| Error::Corruption(id, format!("{}", err)))
+    }
+}
+
+/// The encoded size of a key/value pair in a leaf node.
+fn item_size(key: &[u8], value: &[u8]) -> usize {
+    16 + key.len() + value.len()
+}
+
+/// Returns the index at which to split a sequence of items with the given sizes, such that both
+/// halves have roughly the same size and contain at least one item.
+fn split_point(sizes: impl ExactSizeIterator<Item = usize> + Clone) -> usize {
+    let len = sizes.len();
+    let total: usize = sizes.clone().sum();
+    let mut size = 0;
+    let mut at = len;
+    for (i, s) in sizes.enumerate() {
+        size += s;
+        if size >= total / 2 {
+            at = i + 1;
+            break;
+        }
+    }
+    at.clamp(1, len.max(2) - 1)
+}
+
+/// A B+tree node, stored in a page.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+enum Node {
+    Inner(Inner),
+    Leaf(Leaf),
+}
+
+/// An inner node. There is always one key less than children, where the key at index i (and all
+/// keys up to the one at i+1) is contained within the child at index i+1, as for the in-memory
+/// B+tree.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+struct Inner {
+    keys: Vec<Vec<u8>>,
+    children: Vec<PageId>,
+}
+
+impl Inner {
+    /// Looks up the index of the child responsible for the given key.
+    fn lookup(&self, key: &[u8]) -> usize {
+        self.keys.partition_point(|k| &**k <= key)
+    }
+
+    /// Returns the maximum encoded size of the node.
+    fn size(&self) -> usize {
+        20 + self.keys.iter().map(|k| 8 + k.len()).sum::<usize>() + 8 * self.children.len()
+    }
+
+    /// Splits the node in the middle by size, returning the split key and the right node. The
+    /// split key is removed from the node, and must be inserted into the parent.
+    fn split(&mut self) -> (Vec<u8>, Inner) {
+        // Make sure the left node retains at least one key after popping the split key.
+        let at = split_point(self.keys.iter().map(|k| 16 + k.len())).max(2);
+        let keys = self.keys.split_off(at);
+        let children = self.children.split_off(at);
+        let split_key = self.keys.pop().expect("split left node without keys");
+        (split_key, Inner { keys, children })
+    }
+}
+
+/// A leaf node, with key/value pairs ordered by key and links to its sibling leaf nodes.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+struct Leaf {
+    items: Vec<(Vec<u8>, Vec<u8>)>,
+    prev: Option<PageId>,
+    next: Option<PageId>,
+}
+
+impl Leaf {
+    /// Searches for the given key, returning its index if found, or otherwise the index where it
+    /// would be inserted.
+    fn search(&self, key: &[u8]) -> std::result::Result<usize, usize> {
+        self.items.binary_search_by(|(k, _)| (**k).cmp(key))
+    }
+
+    /// Returns the maximum encoded size of the node.
+    fn size(&self) -> usize {
+        30 + self.items.iter().map(|(k, v)| item_size(k, v)).sum::<usize>()
+    }
+
+    /// Splits the items in the middle by size, returning the right items.
+    fn split(&mut self) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let at = split_point(self.items.iter().map(|(k, v)| item_size(k, v)));
+        self.items.split_off(at)
+    }
+}
+
+/// A node split, as the split key and the page ID of the new (right) node.
+type Split = (Vec<u8>, PageId);
+
+/// The B+tree itself, stored in a paged file.
+struct Tree {
+    /// The buffer pool for the paged file.
+    pool: BufferPool,
+    /// The tree version, incremented on every write. Iterators use this to detect whether their
+    /// cached leaf nodes are still valid.
+    version: u64,
+    /// Whether the tree is in copy-on-write mode, see Paged::new_copy_on_write().
+    copy_on_write: bool,
+    /// In copy-on-write mode, the pages allocated by successful writes since the last flush. These
+    /// are not part of the flushed tree, so they are freed rather than retired when released.
+    fresh: HashSet<PageId>,
+    /// In copy-on-write mode, the pages allocated by the current write. Only these are modified in
+    /// place, such that a failed write leaves the tree unchanged.
+    allocated: HashSet<PageId>,
+    /// In copy-on-write mode, the pages released by the current write. They are freed or retired
+    /// once the write succeeds.
+    released: Vec<PageId>,
+    /// In copy-on-write mode, pages of flushed trees that are no longer used by the current tree.
+    /// They are freed on flush, once no snapshots exist.
+    retired: Vec<PageId>,
+    /// The root page as of the last flush, which snapshots read from.
+    flushed_root: PageId,
+    /// Held by every snapshot, to track whether any snapshots exist.
+    snapshots: Arc<()>,
+}
+
+impl Tree {
+    /// Allocates a new page. In copy-on-write mode, it is tracked as allocated by the current write.
+    fn allocate(&mut self) -> Result<PageId> {
+        let id = self.pool.allocate()?;
+        if self.copy_on_write {
+            self.allocated.insert(id);
+        }
+        Ok(id)
+    }
+
+    /// Applies a batch of writes, in order. In copy-on-write mode, the batch is applied as a single
+    /// write, so a failed batch leaves the tree unchanged.
+    fn apply(&mut self, batch: WriteBatch) -> Result<()> {
+        // Check all items before applying any writes, so that an invalid batch has no effect.
+        for (key, value) in &batch {
+            if let Some(value) = value {
+                self.check_size(key, value)?;
+            }
+        }
+        self.update(|tree, mut root| {
+            for (key, value) in batch {
+                root = match value {
+                    Some(value) => tree.set_at(root, &key, value)?,
+                    None => tree.delete_at(root, &key)?,
+                };
+            }
+            Ok((root, ()))
+        })
+    }
+
+    /// Deletes a key from the tree, if it exists.
+    fn delete(&mut self, key: &[u8]) -> Result<()> {
+        self.update(|tree, root| Ok((tree.delete_at(root, key)?, ())))
+    }
+
+    /// Deletes a key from the tree at the given root page, returning the new root page.
+    fn delete_at(&mut self, root: PageId, key: &[u8]) -> Result<PageId> {
+        let (mut root, _) = self.remove(root, key)?;
+        // If the root is an inner node with a single child, pull the child up as the new root.
+        if let Node::Inner(inner) = self.read(root)? {
+            if inner.children.len() == 1 {
+                debug!("Collapsed root page {} into child page {}", root, inner.children[0]);
+                self.release(root)?;
+                root = inner.children[0];
+            }
+        }
+        Ok(root)
+    }
+
+    /// Deletes all keys in the given range, returning the number of keys deleted. The keys are
+    /// collected first and then deleted one by one, rebalancing the tree as usual.
+    fn delete_range(&mut self, range: &Range) -> Result<usize> {
+        self.update(|tree, mut root| {
+            let mut keys = Vec::new();
+            let mut position = tree.seek(root, range.start_bound())?;
+            while let Some(p) = position {
+                if !range.contains(&p.get().0) {
+                    break;
+                }
+                keys.push(p.get().0.clone());
+                position = p.next(tree, root)?;
+            }
+            for key in &keys {
+                root = tree.delete_at(root, key)?;
+            }
+            Ok((root, keys.len()))
+        })
+    }
+
+    /// Flushes all modified pages to the file and syncs it. In copy-on-write mode, this atomically
+    /// commits the current root page, and the pages written since the last flush become immutable.
+    fn flush(&mut self) -> Result<()> {
+        self.pool.flush()?;
+        self.fresh.clear();
+        self.flushed_root = self.pool.root();
+        // Retired pages aren't used by the flushed tree, but may be used by snapshots of older
+        // trees. Once freed, the pool is flushed again to write the free list.
+        if Arc::strong_count(&self.snapshots) == 1 && !self.retired.is_empty() {
+            for id in std::mem::take(&mut self.retired) {
+                self.pool.free(id)?;
+            }
+            self.pool.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Fetches a value for a key in the tree at the given root page, if it exists.
+    fn get(&self, root: PageId, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let (leaf, _) = self.leaf(root, key)?;
+        Ok(leaf.search(key).ok().map(|i| leaf.items[i].1.clone()))
+    }
+
+    /// Inserts a key/value pair into the subtree at the given page. Returns the page ID of the
+    /// subtree's node, which changes when it is copied in copy-on-write mode, and if the node
+    /// splits, the split key and the page ID of the new (right) node.
+    fn insert(
+        &mut self,
+        id: PageId,
+        key: &[u8],
+        value: Vec<u8>,
+    ) -> Result<(PageId, Option<Split>)> {
+        match self.read(id)? {
+            Node::Leaf(mut leaf) => {
+                match leaf.search(key) {
+                    Ok(i) => leaf.items[i].1 = value,
+                    Err(i) => leaf.items.insert(i, (key.to_vec(), value)),
+                }
+                if leaf.size() <= self.pool.capacity() {
+                    return Ok((self.write(id, Node::Leaf(leaf))?, None));
+                }
+
+                // Split the leaf, and link the right node in between the leaf and its next sibling
+                // unless in copy-on-write mode, where leaves aren't linked.
+                let right_id = self.allocate()?;
+                let mut right = Leaf { items: leaf.split(), prev: None, next: None };
+                if !self.copy_on_write {
+                    (right.prev, right.next) = (Some(id), leaf.next);
+                    if let Some(next) = leaf.next {
+                        self.relink(next, |next| next.prev = Some(right_id))?;
+                    }
+                    leaf.next = Some(right_id);
+                }
+                let split_key = right.items[0].0.clone();
+                trace!("Split leaf page {} at key {:x?} into page {}", id, split_key, right_id);
+                self.write(right_id, Node::Leaf(right))?;
+                let id = self.write(id, Node::Leaf(leaf))?;
+                Ok((id, Some((split_key, right_id))))
+            }
+
+            Node::Inner(mut inner) => {
+                let i = inner.lookup(key);
+                let (child, split) = self.insert(inner.children[i], key, value)?;
+                if child == inner.children[i] && split.is_none() {
+                    return Ok((id, None));
+                }
+                inner.children[i] = child;
+                if let Some((split_key, split_id)) = split {
+                    inner.keys.insert(i, split_key);
+                    inner.children.insert(i + 1, split_id);
+                }
+                if inner.size() <= self.pool.capacity() {
+                    return Ok((self.write(id, Node::Inner(inner))?, None));
+                }
+
+                let right_id = self.allocate()?;
+                let (split_key, right) = inner.split();
+                trace!("Split inner page {} at key {:x?} into page {}", id, split_key, right_id);
+                self.write(right_id, Node::Inner(right))?;
+                let id = self.write(id, Node::Inner(inner))?;
+                Ok((id, Some((split_key, right_id))))
+            }
+        }
+    }
+
+    /// Finds the leaf node responsible for the given key below the given root page.
+    fn leaf(&self, root: PageId, key: &[u8]) -> Result<(Leaf, Bounds)> {
+        self.descend(root, |inner| inner.lookup(key))
+    }
+
+    /// Finds the first leaf node below the given root page.
+    fn leaf_first(&self, root: PageId) -> Result<(Leaf, Bounds)> {
+        self.descend(root, |_| 0)
+    }
+
+    /// Finds the last leaf node below the given root page.
+    fn leaf_last(&self, root: PageId) -> Result<(Leaf, Bounds)> {
+        self.descend(root, |inner| inner.children.len() - 1)
+    }
+
+    /// Descends from the given root page to a leaf node, using the given function to pick the
+    /// index of the child node to descend into. In copy-on-write mode, the leaf's bounds are
+    /// collected from the separator keys along the way.
+    fn descend(&self, root: PageId, pick: impl Fn(&Inner) -> usize) -> Result<(Leaf, Bounds)> {
+        let (mut id, mut bounds) = (root, Bounds::default());
+        loop {
+            match self.read(id)? {
+                Node::Inner(mut inner) => {
+                    let i = pick(&inner);
+                    if self.copy_on_write && i > 0 {
+                        bounds.lower = Some(std::mem::take(&mut inner.keys[i - 1]));
+                    }
+                    if self.copy_on_write && i < inner.keys.len() {
+                        bounds.upper = Some(std::mem::take(&mut inner.keys[i]));
+                    }
+                    id = inner.children[i];
+                }
+                Node::Leaf(leaf) => return Ok((leaf, bounds)),
+            }
+        }
+    }
+
+    /// Reads a node from a page.
+    fn read(&self, id: PageId) -> Result<Node> {
+        Ok(bincode::deserialize(&self.pool.read(id)?)?)
+    }
+
+    /// Reads a leaf node from a page.
+    fn read_leaf(&self, id: PageId) -> Result<Leaf> {
+        match self.read(id)? {
+            Node::Leaf(leaf) => Ok(leaf),
+            Node::Inner(_) => Err(Error::Internal(format!("Page {} is not a leaf node", id))),
+        }
+    }
+
+    /// Rebalances the child at index i of the given parent node with one of its siblings, after it
+    /// has underflowed. The children are merged if they fit in a single page, otherwise their items
+    /// are redistributed evenly between them. The caller must write the parent node, whose child
+    /// page IDs change when the children are copied in copy-on-write mode.
+    fn rebalance(&mut self, parent: &mut Inner, i: usize) -> Result<()> {
+        let l = if i > 0 { i - 1 } else { i };
+        let (left_id, right_id) = (parent.children[l], parent.children[l + 1]);
+        match (self.read(left_id)?, self.read(right_id)?) {
+            (Node::Leaf(mut left), Node::Leaf(mut right)) => {
+                left.items.append(&mut right.items);
+                if left.size() <= self.pool.capacity() {
+                    left.next = right.next;
+                    if let Some(next) = right.next {
+                        self.relink(next, |next| next.prev = Some(left_id))?;
+                    }
+                    trace!("Merged leaf page {} into page {}", right_id, left_id);
+                    parent.keys.remove(l);
+                    parent.children.remove(l + 1);
+                    self.release(right_id)?;
+                } else {
+                    trace!("Redistributed items between leaf pages {} and {}", left_id, right_id);
+                    right.items = left.split();
+                    parent.keys[l] = right.items[0].0.clone();
+                    parent.children[l + 1] = self.write(right_id, Node::Leaf(right))?;
+                }
+                parent.children[l] = self.write(left_id, Node::Leaf(left))?;
+                Ok(())
+            }
+
+            (Node::Inner(mut left), Node::Inner(mut right)) => {
+                left.keys.push(parent.keys[l].clone());
+                left.keys.append(&mut right.keys);
+                left.children.append(&mut right.children);
+                if left.size() <= self.pool.capacity() {
+                    trace!("Merged inner page {} into page {}", right_id, left_id);
+                    parent.keys.remove(l);
+                    parent.children.remove(l + 1);
+                    self.release(right_id)?;
+                } else {
+                    trace!("Redistributed keys between inner pages {} and {}", left_id, right_id);
+                    let (split_key, split) = left.split();
+                    parent.keys[l] = split_key;
+                    parent.children[l + 1] = self.write(right_id, Node::Inner(split))?;
+                }
+                parent.children[l] = self.write(left_id, Node::Inner(left))?;
+                Ok(())
+            }
+
+            (left, right) => Err(Error::Internal(format!(
+                "Can't rebalance sibling pages {} and {} of different types: {:?} and {:?}",
+                left_id, right_id, left, right
+            ))),
+        }
+    }
+
+    /// Releases a page that is no longer used by the tree, freeing it for reuse. In copy-on-write
+    /// mode, the page is only freed once the current write succeeds, and pages of the flushed tree
+    /// are retired instead, to be freed on a later flush.
+    fn release(&mut self, id: PageId) -> Result<()> {
+        if self.copy_on_write {
+            self.released.push(id);
+            return Ok(());
+        }
+        self.pool.free(id)
+    }
+
+    /// Updates the sibling links of the leaf node at the given page. Leaves are only linked when
+    /// not in copy-on-write mode, so the page is modified in place.
+    fn relink(&mut self, id: PageId, f: impl FnOnce(&mut Leaf)) -> Result<()> {
+        let mut leaf = self.read_leaf(id)?;
+        f(&mut leaf);
+        self.write(id, Node::Leaf(leaf))?;
+        Ok(())
+    }
+
+    /// Removes a key from the subtree at the given page, if it exists. Returns the page ID of the
+    /// subtree's node, which changes when it is copied in copy-on-write mode, and true if the node
+    /// underflowed and should be rebalanced by its parent.
+    fn remove(&mut self, id: PageId, key: &[u8]) -> Result<(PageId, bool)> {
+        let min_size = self.pool.capacity() / 4;
+        match self.read(id)? {
+            Node::Leaf(mut leaf) => match leaf.search(key) {
+                Ok(i) => {
+                    leaf.items.remove(i);
+                    let underflow = leaf.size() < min_size;
+                    Ok((self.write(id, Node::Leaf(leaf))?, underflow))
+                }
+                Err(_) => Ok((id, false)),
+            },
+
+            Node::Inner(mut inner) => {
+                let i = inner.lookup(key);
+                let (child, underflow) = self.remove(inner.children[i], key)?;
+                let rebalance = underflow && inner.children.len() >= 2;
+                if child == inner.children[i] && !rebalance {
+                    return Ok((id, false));
+                }
+                inner.children[i] = child;
+                let mut underflow = false;
+                if rebalance {
+                    self.rebalance(&mut inner, i)?;
+                    underflow = inner.size() < min_size;
+                }
+                Ok((self.write(id, Node::Inner(inner))?, underflow))
+            }
+        }
+    }
+
+    /// Finds the position of the first key/value pair after the given lower bound in the tree at
+    /// the given root page, if any.
+    fn seek(&self, root: PageId, bound: Bound<&Vec<u8>>) -> Result<Option<Position>> {
+        let (leaf, bounds) = match bound {
+            Bound::Included(k) | Bound::Excluded(k) => self.leaf(root, k)?,
+            Bound::Unbounded => self.leaf_first(root)?,
+        };
+        let index = match bound {
+            Bound::Included(k) => leaf.items.partition_point(|(ik, _)| ik < k),
+            Bound::Excluded(k) => leaf.items.partition_point(|(ik, _)| ik <= k),
+            Bound::Unbounded => 0,
+        };
+        if index < leaf.items.len() {
+            Ok(Some(Position { leaf, index, bounds }))
+        } else {
+            self.seek_from(root, leaf, bounds)
+        }
+    }
+
+    /// Finds the position of the last key/value pair before the given upper bound in the tree at
+    /// the given root page, if any.
+    fn seek_back(&self, root: PageId, bound: Bound<&Vec<u8>>) -> Result<Option<Position>> {
+        let (leaf, bounds) = match bound {
+            Bound::Included(k) | Bound::Excluded(k) => self.leaf(root, k)?,
+            Bound::Unbounded => self.leaf_last(root)?,
+        };
+        let index = match bound {
+            Bound::Included(k) => leaf.items.partition_point(|(ik, _)| ik <= k),
+            Bound::Excluded(k) => leaf.items.partition_point(|(ik, _)| ik < k),
+            Bound::Unbounded => leaf.items.len(),
+        };
+        if index > 0 {
+            Ok(Some(Position { leaf, index: index - 1, bounds }))
+        } else {
+            self.seek_back_from(root, leaf, bounds)
+        }
+    }
+
+    /// Finds the first position in the leaf nodes after the given leaf node, if any. The next leaf
+    /// is found via the sibling link, or in copy-on-write mode by looking up the leaf's upper bound
+    /// from the root page.
+    fn seek_from(&self, root: PageId, leaf: Leaf, bounds: Bounds) -> Result<Option<Position>> {
+        let (mut leaf, mut bounds) = (leaf, bounds);
+        loop {
+            (leaf, bounds) = match (leaf.next, bounds.upper) {
+                (Some(next), _) => (self.read_leaf(next)?, Bounds::default()),
+                (None, Some(upper)) => self.descend(root, |inner| inner.lookup(&upper))?,
+                (None, None) => return Ok(None),
+            };
+            if !leaf.items.is_empty() {
+                return Ok(Some(Position { leaf, index: 0, bounds }));
+            }
+        }
+    }
+
+    /// Finds the last position in the leaf nodes before the given leaf node, if any. The previous
+    /// leaf is found via the sibling link, or in copy-on-write mode by looking up the leaf just
+    /// below the leaf's lower bound from the root page.
+    fn seek_back_from(&self, root: PageId, leaf: Leaf, bounds: Bounds) -> Result<Option<Position>> {
+        let (mut leaf, mut bounds) = (leaf, bounds);
+        loop {
+            (leaf, bounds) = match (leaf.prev, bounds.lower) {
+                (Some(prev), _) => (self.read_leaf(prev)?, Bounds::default()),
+                (None, Some(lower)) => {
+                    self.descend(root, |inner| inner.keys.partition_point(|k| *k < lower))?
+                }
+                (None, None) => return Ok(None),
+            };
+            if !leaf.items.is_empty() {
+                let index = leaf.items.len() - 1;
+                return Ok(Some(Position { leaf, index, bounds }));
+            }
+        }
+    }
+
+    /// Checks that a key/value pair does not exceed the maximum item size.
+    fn check_size(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        let max_size = self.pool.capacity() / 4;
+        if item_size(key, value) > max_size {
+            return Err(Error::Value(format!(
+                "Key/value pair of size {} exceeds maximum size {}",
+                item_size(key, value),
+                max_size
+            )));
+        }
+        Ok(())
+    }
+
+    /// Sets a key to a value, inserting or updating it.
+    fn set(&mut self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        self.check_size(key, &value)?;
+        self.update(|tree, root| Ok((tree.set_at(root, key, value)?, ())))
+    }
+
+    /// Sets a key to a value in the tree at the given root page, returning the new root page.
+    fn set_at(&mut self, root: PageId, key: &[u8], value: Vec<u8>) -> Result<PageId> {
+        // If the root node splits, create a new root node with the two split nodes as children.
+        let (mut root, split) = self.insert(root, key, value)?;
+        if let Some((split_key, split_id)) = split {
+            let new_root = self.allocate()?;
+            let inner = Inner { keys: vec![split_key], children: vec![root, split_id] };
+            self.write(new_root, Node::Inner(inner))?;
+            debug!("Split root page {}, new root page {}", root, new_root);
+            root = new_root;
+        }
+        Ok(root)
+    }
+
+    /// Runs a write against the current root page, which returns the new root page along with
+    /// its result, and installs the new root page if it succeeds. In copy-on-write mode, the write
+    /// doesn't modify the pages of the current tree, so if it fails, the pages it allocated are
+    /// freed and the tree is left unchanged.
+    fn update<T>(&mut self, f: impl FnOnce(&mut Self, PageId) -> Result<(PageId, T)>) -> Result<T> {
+        let root = self.pool.root();
+        self.version += 1;
+        match f(self, root) {
+            Ok((root, result)) => {
+                self.pool.set_root(root);
+                self.fresh.extend(self.allocated.drain());
+                for id in std::mem::take(&mut self.released) {
+                    match self.fresh.remove(&id) {
+                        true => self.pool.free(id)?,
+                        false => self.retired.push(id),
+                    }
+                }
+                Ok(result)
+            }
+            Err(err) => {
+                self.released.clear();
+                for id in std::mem::take(&mut self.allocated) {
+                    self.pool.free(id)?;
+                }
+                Err(err)
+            }
+        }
+    }
+
+    /// Writes a node to a page, returning the page ID it was written to. In copy-on-write mode,
+    /// only pages allocated by the current write are modified, so the node is otherwise written to
+    /// a new page and the caller must update the parent node to refer to it.
+    fn write(&mut self, mut id: PageId, node: Node) -> Result<PageId> {
+        if self.copy_on_write && !self.allocated.contains(&id) {
+            self.release(id)?;
+            id = self.allocate()?;
+        }
+        self.pool.write(id, &bincode::serialize(&node)?)?;
+        Ok(id)
+    }
+}
+
+impl Drop for Tree {
+    /// In copy-on-write mode, discards unflushed writes when the tree is dropped, by resetting the
+    /// root page to the flushed root before the buffer pool writes back its pages, and freeing the
+    /// pages allocated since the last flush. Retired pages are only freed by flush().
+    fn drop(&mut self) {
+        if self.copy_on_write {
+            self.pool.set_root(self.flushed_root);
+            for id in std::mem::take(&mut self.fresh) {
+                if let Err(err) = self.pool.free(id) {
+                    error!("Failed to free page {}: {}", id, err);
+                }
+            }
+        }
+    }
+}
+
+/// The key range of a leaf node, given by the nearest separator keys above it. The lower bound is
+/// inclusive and the upper bound exclusive, and they are None at the edges of the tree. Bounds are
+/// only tracked in copy-on-write mode, where leaf nodes aren't linked, and are used to look up the
+/// sibling leaf nodes from the root page instead.
+#[derive(Default)]
+struct Bounds {
+    lower: Option<Vec<u8>>,
+    upper: Option<Vec<u8>>,
+}
+
+/// A position of a key/value pair within a leaf node. The leaf node is cached, so the position is
+/// only valid as long as the tree has not been modified since it was found.
+struct Position {
+    leaf: Leaf,
+    index: usize,
+    bounds: Bounds,
+}
+
+impl Position {
+    /// Returns the key/value pair at the position.
+    fn get(&self) -> &(Vec<u8>, Vec<u8>) {
+        &self.leaf.items[self.index]
+    }
+
+    /// Returns the next position in the tree at the given root page, moving to the next leaf node
+    /// if necessary.
+    fn next(self, tree: &Tree, root: PageId) -> Result<Option<Self>> {
+        if self.index + 1 < self.leaf.items.len() {
+            Ok(Some(Self { index: self.index + 1, ..self }))
+        } else {
+            tree.seek_from(root, self.leaf, self.bounds)
+        }
+    }
+
+    /// Returns the previous position in the tree at the given root page, moving to the previous
+    /// leaf node if necessary.
+    fn prev(self, tree: &Tree, root: PageId) -> Result<Option<Self>> {
+        if self.index > 0 {
+            Ok(Some(Self { index: self.index - 1, ..self }))
+        } else {
+            tree.seek_back_from(root, self.leaf, self.bounds)
+        }
+    }
+}
+
+/// A key range scan. As for the in-memory B+tree, the iterator looks up its initial positions from
+/// the root node and then walks the leaf sibling links, unless the tree is modified between steps.
+struct Iter {
+    /// The tree we're iterating across.
+    tree: Arc<RwLock<Tree>>,
+    /// The root page of the snapshot we're iterating over, or None for the current tree.
+    root: Option<PageId>,
+    /// The range we're iterating over.
+    range: Range,
+    /// The front cursor keeps track of the last returned value from the front.
+    front_cursor: Option<Vec<u8>>,
+    /// The back cursor keeps track of the last returned value from the back.
+    back_cursor: Option<Vec<u8>>,
+    /// The leaf position of the front cursor, and the tree version it is valid for.
+    front_position: Option<(Position, u64)>,
+    /// The leaf position of the back cursor, and the tree version it is valid for.
+    back_position: Option<(Position, u64)>,
+}
+
+impl Iter {
+    /// Creates a new iterator, over the snapshot at the given root page if any.
+    fn new(tree: Arc<RwLock<Tree>>, root: Option<PageId>, range: Range) -> Self {
+        Self {
+            tree,
+            root,
+            range,
+            front_cursor: None,
+            back_cursor: None,
+            front_position: None,
+            back_position: None,
+        }
+    }
+
+    // next() with error handling.
+    fn try_next(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        let tree = self.tree.read()?;
+        let root = self.root.unwrap_or(tree.pool.root());
+        let position = match (self.front_position.take(), &self.front_cursor) {
+            // The tree hasn't changed since the last step, so we can follow the leaf links.
+            (Some((position, version)), _) if version == tree.version => {
+                position.next(&tree, root)?
+            }
+            (_, Some(k)) => tree.seek(root, Bound::Excluded(k))?,
+            (_, None) => tree.seek(root, self.range.start_bound())?,
+        };
+        let next = match position {
+            Some(position) => {
+                let (k, v) = position.get().clone();
+                if !self.range.contains(&k) {
+                    return Ok(None);
+                }
+                if let Some(bc) = &self.back_cursor {
+                    if bc <= &k {
+                        return Ok(None);
+                    }
+                }
+                self.front_cursor = Some(k.clone());
+                self.front_position = Some((position, tree.version));
+                Some((k, v))
+            }
+            None => None,
+        };
+        Ok(next)
+    }
+
+    /// next_back() with error handling.
+    fn try_next_back(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        let tree = self.tree.read()?;
+        let root = self.root.unwrap_or(tree.pool.root());
+        let position = match (self.back_position.take(), &self.back_cursor) {
+            // The tree hasn't changed since the last step, so we can follow the leaf links.
+            (Some((position, version)), _) if version == tree.version => {
+                position.prev(&tree, root)?
+            }
+            (_, Some(k)) => tree.seek_back(root, Bound::Excluded(k))?,
+            (_, None) => tree.seek_back(root, self.range.end_bound())?,
+        };
+        let prev = match position {
+            Some(position) => {
+                let (k, v) = position.get().clone();
+                if !self.range.contains(&k) {
+                    return Ok(None);
+                }
+                if let Some(fc) = &self.front_cursor {
+                    if fc >= &k {
+                        return Ok(None);
+                    }
+                }
+                self.back_cursor = Some(k.clone());
+                self.back_position = Some((position, tree.version));
+                Some((k, v))
+            }
+            None => None,
+        };
+        Ok(prev)
+    }
+}
+
+impl Iterator for Iter {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.try_next().transpose()
+    }
+}
+
+impl DoubleEndedIterator for Iter {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.try_next_back().transpose()
+    }
+}
+
+/// A cursor over the tree. As for the in-memory B+tree, it walks the leaf sibling links while the
+/// tree is unmodified, and otherwise looks up its next position from the root node.
+struct Cursor {
+    /// The tree.
+    tree: Arc<RwLock<Tree>>,
+    /// The root page of the snapshot we're iterating over, or None for the current tree.
+    root: Option<PageId>,
+    /// The key/value pair at the cursor position, if valid.
+    current: Option<(Vec<u8>, Vec<u8>)>,
+    /// The leaf position of the current key/value pair, and the tree version it is valid for.
+    position: Option<(Position, u64)>,
+}
+
+impl Cursor {
+    /// Creates a new, unpositioned cursor, over the snapshot at the given root page if any.
+    fn new(tree: Arc<RwLock<Tree>>, root: Option<PageId>) -> Self {
+        Self { tree, root, current: None, position: None }
+    }
+
+    /// Moves the cursor to the position found by the given function, which is given the tree and
+    /// root page, the current position if it is still valid, and the current key/value pair.
+    fn step(
+        &mut self,
+        f: impl FnOnce(
+            &Tree,
+            PageId,
+            Option<Position>,
+            Option<&(Vec<u8>, Vec<u8>)>,
+        ) -> Result<Option<Position>>,
+    ) -> Result<()> {
+        let tree = self.tree.read()?;
+        let root = self.root.unwrap_or(tree.pool.root());
+        let position = match self.position.take() {
+            Some((position, version)) if version == tree.version => Some(position),
+            _ => None,
+        };
+        match f(&tree, root, position, self.current.as_ref())? {
+            Some(position) => {
+                self.current = Some(position.get().clone());
+                self.position = Some((position, tree.version));
+            }
+            None => self.current = None,
+        }
+        Ok(())
+    }
+}
+
+impl super::Cursor for Cursor {
+    fn seek(&mut self, key: &[u8]) -> Result<()> {
+        self.step(|tree, root, _, _| tree.seek(root, Bound::Included(&key.to_vec())))
+    }
+
+    fn seek_for_prev(&mut self, key: &[u8]) -> Result<()> {
+        self.step(|tree, root, _, _| tree.seek_back(root, Bound::Included(&key.to_vec())))
+    }
+
+    fn seek_to_first(&mut self) -> Result<()> {
+        self.step(|tree, root, _, _| tree.seek(root, Bound::Unbounded))
+    }
+
+    fn seek_to_last(&mut self) -> Result<()> {
+        self.step(|tree, root, _, _| tree.seek_back(root, Bound::Unbounded))
+    }
+
+    fn next(&mut self) -> Result<()> {
+        if self.current.is_none() {
+            return Ok(());
+        }
+        self.step(|tree, root, position, current| match (position, current) {
+            // The tree hasn't changed since the last step, so we can follow the leaf links.
+            (Some(position), _) => position.next(tree, root),
+            (None, Some((k, _))) => tree.seek(root, Bound::Excluded(k)),
+            (None, None) => Ok(None),
+        })
+    }
+
+    fn prev(&mut self) -> Result<()> {
+        if self.current.is_none() {
+            return Ok(());
+        }
+        self.step(|tree, root, position, current| match (position, current) {
+            // The tree hasn't changed since the last step, so we can follow the leaf links.
+            (Some(position), _) => position.prev(tree, root),
+            (None, Some((k, _))) => tree.seek_back(root, Bound::Excluded(k)),
+            (None, None) => Ok(None),
+        })
+    }
+
+    fn valid(&self) -> bool {
+        self.current.is_some()
+    }
+
+    fn key(&self) -> Option<&[u8]> {
+        self.current.as_ref().map(|(k, _)| k.as_slice())
+    }
+
+    fn value(&self) -> Option<&[u8]> {
+        self.current.as_ref().map(|(_, v)| v.as_slice())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::collections::BTreeMap;
+
+    impl super::super::TestSuite<Paged> for Paged {
+        fn setup() -> Result<(Self, Option<tempdir::TempDir>)> {
+            let dir = tempdir::TempDir::new("kupier")?;
+            Ok((Paged::new(&dir.path().join("kupier"))?, Some(dir)))
+        }
+    }
+
+    /// Runs the test suite against a paged store in copy-on-write mode.
+    struct CopyOnWrite;
+
+    impl super::super::TestSuite<Paged> for CopyOnWrite {
+        fn setup() -> Result<(Paged, Option<tempdir::TempDir>)> {
+            let dir = tempdir::TempDir::new("kupier")?;
+            let store = Paged::new_copy_on_write(&dir.path().join("kupier"), DEFAULT_PAGE_SIZE)?;
+            Ok((store, Some(dir)))
+        }
+    }
+
+    #[test]
+    fn tests() -> Result<()> {
+        use super::super::TestSuite;
+        Paged::test()?;
+        CopyOnWrite::test()
+    }
+
+    #[tokio::test]
+    async fn async_store() -> Result<()> {
+        let dir = tempdir::TempDir::new("kupier")?;
+        let path = dir.path().join("kupier");
+        let mut s = Paged::new_with_page_size(&path, 512)?;
+        super::super::async_store::test_suite(&s).await?;
+        let expect: Vec<_> = s.scan(Range::from(..)).collect::<Result<_>>()?;
+        // Background scan tasks may still hold the tree, so flush rather than rely on dropping it.
+        s.flush()?;
+        drop(s);
+
+        let s = Paged::new_with_page_size(&path, 512)?;
+        assert_eq!(expect, s.scan(Range::from(..)).collect::<Result<Vec<_>>>()?);
+        Ok(())
+    }
+
+    #[test]
+    fn reopen() -> Result<()> {
+        let dir = tempdir::TempDir::new("kupier")?;
+        let path = dir.path().join("kupier");
+
+        let mut s = Paged::new_with_page_size(&path, 512)?;
+        for i in 0..100_u64 {
+            s.set(&i.to_be_bytes(), i.to_le_bytes().to_vec())?;
+        }
+        s.delete(&7_u64.to_be_bytes())?;
+        s.flush()?;
+        drop(s);
+
+        // Opening the file with a different page size should fail.
+        assert!(matches!(Paged::new(&path), Err(Error::Config(_))));
+
+        let s = Paged::new_with_page_size(&path, 512)?;
+        assert_eq!(None, s.get(&7_u64.to_be_bytes())?);
+        assert_eq!(Some(99_u64.to_le_bytes().to_vec()), s.get(&99_u64.to_be_bytes())?);
+        assert_eq!(99, s.scan(Range::from(..)).count());
+        Ok(())
+    }
+
+    #[test]
+    fn random_small_pages() -> Result<()> {
+        let dir = tempdir::TempDir::new("kupier")?;
+        let path = dir.path().join("kupier");
+        let mut rng: rand::rngs::StdRng = rand::SeedableRng::seed_from_u64(397_427_893);
+
+        // Use small pages and variable-sized items to get a deep tree with lots of splits,
+        // merges and redistributions, and compare it with a BTreeMap. In copy-on-write mode,
+        // flush regularly such that both flushed and fresh pages are modified.
+        for copy_on_write in [false, true] {
+            let path = path.with_extension(if copy_on_write { "cow" } else { "" });
+            let mut s = match copy_on_write {
+                true => Paged::new_copy_on_write(&path, 512)?,
+                false => Paged::new_with_page_size(&path, 512)?,
+            };
+            let expect = random_writes(&mut s, &mut rng, copy_on_write)?;
+            s.flush()?;
+            let report = Paged::verify(&path)?;
+            assert!(report.is_ok(), "{:?}", report.errors);
+            assert_eq!(Vec::<u64>::new(), report.orphans);
+            assert_eq!(s.stats()?.free_pages, report.free);
+
+            // Deleting everything should leave an empty tree.
+            for (key, _) in expect.iter() {
+                s.delete(key)?;
+            }
+            assert!(s.scan(Range::from(..)).next().is_none());
+        }
+        Ok(())
+    }
+
+    /// Applies random writes to a paged store and compares it with a BTreeMap, returning the
+    /// expected key/value pairs. If flush is true, the store is flushed regularly.
+    fn random_writes(
+        s: &mut Paged,
+        rng: &mut rand::rngs::StdRng,
+        flush: bool,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        use rand::Rng;
+        let mut expect = BTreeMap::new();
+        for i in 0..5000 {
+            if flush && i % 100 == 0 {
+                s.flush()?;
+            }
+            let key = rng.gen_range(0..1000_u32).to_be_bytes().repeat(rng.gen_range(1..8));
+            if rng.gen_bool(0.6) {
+                let value = vec![rng.gen(); rng.gen_range(0..64)];
+                s.set(&key, value.clone())?;
+                expect.insert(key, value);
+            } else {
+                s.delete(&key)?;
+                expect.remove(&key);
+            }
+        }
+        let expect: Vec<_> = expect.into_iter().collect();
+        assert_eq!(expect, s.scan(Range::from(..)).collect::<Result<Vec<_>>>()?);
+        assert_eq!(
+            expect.iter().rev().cloned().collect::<Vec<_>>(),
+            s.scan(Range::from(..)).rev().collect::<Result<Vec<_>>>()?
+        );
+        for (key, value) in expect.iter() {
+            assert_eq!(Some(value.clone()), s.get(key)?);
+        }
+        Ok(expect)
+    }
+
+    #[test]
+    fn free_pages() -> Result<()> {
+        let dir = tempdir::TempDir::new("kupier")?;
+        let path = dir.path().join("kupier");
+        let kv = |i: u32| (i.to_be_bytes().to_vec(), vec![i as u8; 32]);
+
+        // Deleting most keys merges nodes, freeing their pages.
+        let mut s = Paged::new_with_page_size(&path, 512)?;
+        for i in 0..1000 {
+            let (key, value) = kv(i);
+            s.set(&key, value)?;
+        }
+        let pages = s.stats()?.pages;
+        assert_eq!(0, s.stats()?.free_pages);
+        for i in (0..1000).filter(|i| i % 10 != 0) {
+            s.delete(&kv(i).0)?;
+        }
+        let free = s.stats()?.free_pages;
+        assert!(free > pages / 2, "{} of {} pages free", free, pages);
+        s.flush()?;
+        drop(s);
+
+        // The free pages are persisted, and mostly reused by later splits before the file is
+        // extended.
+        let mut s = Paged::new_with_page_size(&path, 512)?;
+        let stats = s.stats()?;
+        assert!(stats.free_pages > 0 && stats.free_pages <= free, "{:?}", stats);
+        assert!(stats.pages <= pages + 1);
+        for i in (0..1000).filter(|i| i % 10 != 0) {
+            let (key, value) = kv(i);
+            s.set(&key, value)?;
+        }
+        let after = s.stats()?;
+        assert!(after.pages - stats.pages < stats.free_pages - after.free_pages, "{:?}", after);
+        assert_eq!(1000, s.scan(Range::from(..)).count());
+        s.flush()?;
+        let report = Paged::verify(&path)?;
+        assert!(report.is_ok(), "{:?}", report.errors);
+        assert_eq!(Vec::<u64>::new(), report.orphans);
+        Ok(())
+    }
+
+    #[test]
+    fn copy_on_write() -> Result<()> {
+        let dir = tempdir::TempDir::new("kupier")?;
+        let path = dir.path().join("kupier");
+        let kv = |i: u64| (i.to_be_bytes().to_vec(), i.to_le_bytes().to_vec());
+
+        let mut s = Paged::new_copy_on_write(&path, 512)?;
+        for i in 0..100 {
+            let (key, value) = kv(i);
+            s.set(&key, value)?;
+        }
+        s.flush()?;
+
+        // A snapshot sees the flushed tree, regardless of later writes and flushes.
+        let snapshot = s.snapshot()?;
+        for i in 50..150 {
+            let (key, _) = kv(i);
+            s.set(&key, b"new".to_vec())?;
+        }
+        for i in 0..25 {
+            s.delete(&kv(i).0)?;
+        }
+        let expect: Vec<_> = (0..100).map(kv).collect();
+        assert_eq!(expect, snapshot.scan(Range::from(..)).collect::<Result<Vec<_>>>()?);
+        let mut rev = expect.clone();
+        rev.reverse();
+        assert_eq!(rev, snapshot.scan(Range::from(..)).rev().collect::<Result<Vec<_>>>()?);
+        assert_eq!(Some(kv(0).1), snapshot.get(&kv(0).0)?);
+        assert_eq!(None, snapshot.get(&kv(120).0)?);
+        // Pages of the snapshot's tree aren't freed while it exists, but are freed on the next
+        // flush once it's dropped.
+        let free = s.stats()?.free_pages;
+        s.flush()?;
+        assert_eq!(free, s.stats()?.free_pages);
+        assert_eq!(Some(kv(60).1), snapshot.get(&kv(60).0)?);
+        assert_eq!(Some(b"new".to_vec()), s.snapshot()?.get(&kv(60).0)?);
+        assert_eq!(125, s.snapshot()?.scan(Range::from(..)).count());
+
+        drop(snapshot);
+        s.set(&kv(60).0, b"new".to_vec())?;
+        s.flush()?;
+        assert!(s.stats()?.free_pages > free);
+
+        // Snapshots are read-only.
+        let mut snapshot = s.snapshot()?;
+        assert_eq!(Err(Error::ReadOnly), snapshot.set(b"a", vec![]));
+        assert_eq!(Err(Error::ReadOnly), snapshot.delete(b"a"));
+        drop(snapshot);
+
+        // Unflushed writes are lost in a crash, which is simulated by leaking the store. The
+        // store recovers to the last flush.
+        for i in 0..150 {
+            s.delete(&kv(i).0)?;
+        }
+        s.set(b"lost", vec![])?;
+        std::mem::forget(s);
+
+        let s = Paged::new_copy_on_write(&path, 512)?;
+        assert_eq!(None, s.get(b"lost")?);
+        assert_eq!(125, s.scan(Range::from(..)).count());
+        assert_eq!(Some(b"new".to_vec()), s.get(&kv(149).0)?);
+        drop(s);
+
+        let report = Paged::verify(&path)?;
+        assert!(report.is_ok(), "{:?}", report.errors);
+        assert_eq!(125, report.keys);
+
+        // Unflushed writes are also discarded when the store is dropped, and the pages they
+        // allocated are freed.
+        let mut s = Paged::new_copy_on_write(&path, 512)?;
+        for i in 0..150 {
+            s.delete(&kv(i).0)?;
+        }
+        s.set(b"lost", vec![])?;
+        drop(s);
+
+        let s = Paged::new_copy_on_write(&path, 512)?;
+        assert_eq!(None, s.get(b"lost")?);
+        assert_eq!(125, s.scan(Range::from(..)).count());
+        drop(s);
+
+        let report = Paged::verify(&path)?;
+        assert!(report.is_ok(), "{:?}", report.errors);
+        assert_eq!(Vec::<u64>::new(), report.orphans);
+
+        // The mode is fixed when the file is created. Files opened without asking for
+        // copy-on-write mode use the file's mode, and snapshots require it.
+        let other = dir.path().join("other");
+        drop(Paged::new_with_page_size(&other, 512)?);
+        assert!(matches!(Paged::new_copy_on_write(&other, 512), Err(Error::Config(_))));
+        assert!(matches!(
+            Paged::new_with_page_size(&other, 512)?.snapshot(),
+            Err(Error::Config(_))
+        ));
+        assert!(Paged::new_with_page_size(&path, 512)?.snapshot().is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn copy_on_write_failed_write() -> Result<()> {
+        use super::super::pager::PAGE_HEADER_SIZE;
+        use std::fs::OpenOptions;
+        use std::io::{Seek, SeekFrom, Write};
+
+        let dir = tempdir::TempDir::new("kupier")?;
+        let path = dir.path().join("kupier");
+        let kv = |i: u64| (i.to_be_bytes().to_vec(), vec![i as u8; 32]);
+        let mut s = Paged::new_copy_on_write(&path, 512)?;
+        for i in 0..20 {
+            let (key, value) = kv(i);
+            s.set(&key, value)?;
+        }
+        s.flush()?;
+        drop(s);
+
+        // Corrupt the second leaf, such that rebalancing the first leaf with it fails.
+        let pager = Pager::open_read_only(&path)?;
+        let (left, right) = match bincode::deserialize(&pager.read(pager.root())?)? {
+            Node::Inner(inner) => (inner.children[0], inner.children[1]),
+            node => panic!("unexpected root node {:?}", node),
+        };
+        let keys = match bincode::deserialize(&pager.read(left)?)? {
+            Node::Leaf(leaf) => leaf.items.into_iter().map(|(key, _)| key).collect::<Vec<_>>(),
+            node => panic!("unexpected leaf node {:?}", node),
+        };
+        drop(pager);
+        let mut file = OpenOptions::new().write(true).open(&path)?;
+        file.seek(SeekFrom::Start(right * 512 + PAGE_HEADER_SIZE as u64))?;
+        file.write_all(&[0xff; 8])?;
+        drop(file);
+
+        // Deleting keys from the first leaf eventually underflows it. The failed delete must
+        // leave the tree unchanged, and not retire any of its pages.
+        let mut s = Paged::new_copy_on_write(&path, 512)?;
+        let mut failed = None;
+        for key in keys {
+            s.flush()?;
+            let free = s.stats()?.free_pages;
+            if let Err(err) = s.delete(&key) {
+                assert!(matches!(err, Error::Corruption(id, _) if id == right), "{:?}", err);
+                assert_eq!(free, s.stats()?.free_pages);
+                failed = Some((key, free));
+                break;
+            }
+        }
+        let (key, free) = failed.expect("delete did not fail");
+        assert!(s.get(&key)?.is_some());
+        s.flush()?;
+        assert_eq!(free, s.stats()?.free_pages);
+        assert!(s.get(&key)?.is_some());
+
+        // A failed batch doesn't apply any of its writes.
+        let mut batch = WriteBatch::new();
+        batch.set(b"new", vec![]).delete(&key);
+        assert!(matches!(s.write(batch), Err(Error::Corruption(_, _))));
+        assert_eq!(None, s.get(b"new")?);
+        assert!(s.get(&key)?.is_some());
+        drop(s);
+
+        let report = Paged::verify(&path)?;
+        assert_eq!(1, report.errors.len(), "{:?}", report.errors);
+        assert!(matches!(report.errors[0], Error::Corruption(id, _) if id == right));
+        Ok(())
+    }
+
+    #[test]
+    fn cache() -> Result<()> {
+        for eviction in [Eviction::Clock, Eviction::Lru, Eviction::TwoQueue] {
+            let dir = tempdir::TempDir::new("kupier")?;
+            let path = dir.path().join("kupier");
+
+            // Write a tree much larger than the buffer pool, and check that it is read back.
+            let mut s = Paged::new_with_cache(&path, 512, 8, eviction)?;
+            let items: Vec<_> =
+                (0..1000_u32).map(|i| (i.to_be_bytes().to_vec(), vec![i as u8; 32])).collect();
+            for (key, value) in items.iter().rev() {
+                s.set(key, value.clone())?;
+            }
+            for i in (0..1000).step_by(3) {
+                assert_eq!(Some(items[i].1.clone()), s.get(&items[i].0)?);
+            }
+            assert_eq!(items, s.scan(Range::from(..)).collect::<Result<Vec<_>>>()?);
+            let stats = s.cache_stats()?;
+            assert_eq!(8, stats.pages, "{:?}", eviction);
+            assert!(stats.hits > 0 && stats.misses > 0 && stats.evictions > 0, "{:?}", eviction);
+
+            // Dirty pages are written back on flush, and when the store is dropped.
+            s.flush()?;
+            assert_eq!(0, s.cache_stats()?.dirty);
+            s.delete(&items[0].0)?;
+            assert!(s.cache_stats()?.dirty > 0);
+            drop(s);
+
+            let s = Paged::new_with_cache(&path, 512, 8, eviction)?;
+            assert_eq!(items[1..], s.scan(Range::from(..)).collect::<Result<Vec<_>>>()?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn verify() -> Result<()> {
+        use super::super::pager::PAGE_HEADER_SIZE;
+        use std::fs::OpenOptions;
+        use std::io::{Read, Seek, SeekFrom, Write};
+
+        let dir = tempdir::TempDir::new("kupier")?;
+        let path = dir.path().join("kupier");
+        let mut s = Paged::new_with_page_size(&path, 512)?;
+        for i in 0..1000_u32 {
+            s.set(&i.to_be_bytes(), vec![i as u8; 32])?;
+        }
+        for i in (0..1000_u32).filter(|i| i % 4 != 0) {
+            s.delete(&i.to_be_bytes())?;
+        }
+        let expect: Vec<_> = s.scan(Range::from(..)).collect::<Result<_>>()?;
+        drop(s);
+
+        // The tree is intact, and pages freed by merges are in the free list.
+        let report = Paged::verify(&path)?;
+        assert!(report.is_ok(), "{:?}", report.errors);
+        assert_eq!((250, 3), (report.keys, report.height));
+        assert!(report.free > 0 && report.orphans.is_empty());
+
+        // Flip a bit in an inner node below the root.
+        let pager = Pager::open_read_only(&path)?;
+        let inner = match bincode::deserialize(&pager.read(pager.root())?)? {
+            Node::Inner(inner) => inner.children[1],
+            node => panic!("unexpected root node {:?}", node),
+        };
+        drop(pager);
+        let mut file = OpenOptions::new().read(true).write(true).open(&path)?;
+        let mut byte = [0];
+        file.seek(SeekFrom::Start(inner * 512 + PAGE_HEADER_SIZE as u64))?;
+        file.read_exact(&mut byte)?;
+        file.seek(SeekFrom::Start(inner * 512 + PAGE_HEADER_SIZE as u64))?;
+        file.write_all(&[byte[0] ^ 0x01])?;
+        drop(file);
+
+        let report = Paged::verify(&path)?;
+        assert!(matches!(report.errors[0], Error::Corruption(id, _) if id == inner));
+        assert!(report.keys < 250);
+
+        // Salvaging recovers all keys via the leaf sibling links, into a valid tree.
+        let target = dir.path().join("salvaged");
+        assert_eq!(250, Paged::salvage(&path, &target)?);
+        assert!(matches!(Paged::salvage(&path, &target), Err(Error::Config(_))));
+        let report = Paged::verify(&target)?;
+        assert!(report.is_ok(), "{:?}", report.errors);
+        assert_eq!(250, report.keys);
+        let s = Paged::new_with_page_size(&target, 512)?;
+        assert_eq!(expect, s.scan(Range::from(..)).collect::<Result<Vec<_>>>()?);
+
+        // Misordered keys are detected.
+        let path = dir.path().join("unordered");
+        Paged::new_with_page_size(&path, 512)?.set(b"a", vec![])?;
+        let pager = Pager::open(&path, 512)?;
+        let items = vec![(b"b".to_vec(), vec![]), (b"a".to_vec(), vec![])];
+        let leaf = Node::Leaf(Leaf { items, ..Default::default() });
+        pager.write(pager.root(), &bincode::serialize(&leaf)?)?;
+        let root = pager.root();
+        drop(pager);
+        assert_eq!(
+            vec![Error::Corruption(root, "key [61] is out of order".into())],
+            Paged::verify(&path)?.errors
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn salvage_copy_on_write() -> Result<()> {
+        use super::super::pager::PAGE_HEADER_SIZE;
+        use std::fs::OpenOptions;
+        use std::io::{Seek, SeekFrom, Write};
+
+        let dir = tempdir::TempDir::new("kupier")?;
+        let path = dir.path().join("kupier");
+        let mut s = Paged::new_copy_on_write(&path, 512)?;
+        for i in 0..1000_u32 {
+            s.set(&i.to_be_bytes(), vec![i as u8; 32])?;
+        }
+        s.flush()?;
+        for i in (0..1000_u32).filter(|i| i % 4 != 0) {
+            s.delete(&i.to_be_bytes())?;
+        }
+        s.flush()?;
+        let expect: Vec<_> = s.scan(Range::from(..)).collect::<Result<_>>()?;
+        drop(s);
+
+        // Corrupt an inner node below the root. Its leaves aren't linked to their siblings, and
+        // free pages hold stale leaves with deleted keys.
+        let pager = Pager::open_read_only(&path)?;
+        let inner = match bincode::deserialize(&pager.read(pager.root())?)? {
+            Node::Inner(inner) => inner.children[1],
+            node => panic!("unexpected root node {:?}", node),
+        };
+        drop(pager);
+        let mut file = OpenOptions::new().write(true).open(&path)?;
+        file.seek(SeekFrom::Start(inner * 512 + PAGE_HEADER_SIZE as u64))?;
+        file.write_all(&[0xff; 8])?;
+        drop(file);
+        assert!(Paged::verify(&path)?.keys < 250);
+
+        // Salvaging recovers all keys by scanning the pages, but not the deleted keys.
+        let target = dir.path().join("salvaged");
+        assert_eq!(250, Paged::salvage(&path, &target)?);
+        let s = Paged::new_with_page_size(&target, 512)?;
+        assert_eq!(expect, s.scan(Range::from(..)).collect::<Result<Vec<_>>>()?);
+        Ok(())
+    }
+
+    #[test]
+    fn set_too_large() -> Result<()> {
+        let dir = tempdir::TempDir::new("kupier")?;
+        let mut s = Paged::new_with_page_size(&dir.path().join("kupier"), 512)?;
+        assert!(matches!(s.set(b"key", vec![0; 512]), Err(Error::Value(_))));
+        assert_eq!(None, s.get(b"key")?);
+
+        // A batch with an oversized item should not apply any of its writes.
+        let mut batch = WriteBatch::new();
+        batch.set(b"a", vec![0x01]).set(b"key", vec![0; 512]);
+        assert!(matches!(s.write(batch), Err(Error::Value(_))));
+        assert_eq!(None, s.get(b"a")?);
+        Ok(())
+    }
+}
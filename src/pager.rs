@@ -0,0 +1,457 @@
+use crate::error::{Error, Result};
+
+use log::debug;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fs::{create_dir_all, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+/// A page ID, i.e. the index of the page in the file.
+pub type PageId = u64;
+
+/// The default page size.
+pub const DEFAULT_PAGE_SIZE: usize = 8192;
+
+/// The minimum page size. Pages must be able to hold a few maximum-sized items.
+pub const MIN_PAGE_SIZE: usize = 512;
+
+/// The size of the header at the start of every page.
+pub const PAGE_HEADER_SIZE: usize = 64;
+
+/// The page ID of the file metadata page.
+pub const META_PAGE: PageId = 0;
+
+/// The magic bytes at the start of the file metadata, identifying the file format.
+const MAGIC: [u8; 8] = *b"KUPIERBT";
+
+/// The file format version.
+const FORMAT_VERSION: u32 = 4;
+
+/// File metadata, stored in the first page of the file.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct Meta {
+    magic: [u8; 8],
+    version: u32,
+    page_size: u32,
+    root: PageId,
+    copy_on_write: bool,
+    free_list: PageId,
+}
+
+/// A page of the free list, which lists free pages and links to the next free list page, if any.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+struct FreeList {
+    pages: Vec<PageId>,
+    next: Option<PageId>,
+}
+
+/// Reads and writes fixed-size pages in a file. The first page contains file metadata, including
+/// the root page ID, and the remaining pages are used by the B+tree. Each page starts with a
+/// fixed-size header, currently containing the length of the page body and a checksum, followed by
+/// the body and zero padding.
+///
+/// The checksum is a CRC32C of the entire page except the checksum itself, including the padding.
+/// It is verified whenever a page is read, and mismatches are returned as Error::Corruption.
+///
+/// Page writes go straight to the file, but are not durable until sync() is called.
+///
+/// Freed pages are kept in a free list and reused by allocate() before the file is extended. The
+/// free list is stored in a chain of free list pages, which are themselves taken from the free
+/// pages. On every sync where the free pages have changed, the entire free list is written to new
+/// free list pages before the metadata is switched over to them, such that the free list in the
+/// file is always consistent with the metadata. The previous free list pages are then free too.
+pub struct Pager {
+    /// The file, guarded by a mutex to allow I/O via a shared reference.
+    file: Mutex<File>,
+    /// The page size, in bytes.
+    page_size: usize,
+    /// The number of pages in the file, including the metadata page.
+    page_count: u64,
+    /// The file metadata.
+    meta: Meta,
+    /// The free pages, excluding the free list pages.
+    free: BTreeSet<PageId>,
+    /// The free list pages referenced by the metadata in the file.
+    free_list: Vec<PageId>,
+    /// Whether the free pages have changed since the free list was last written.
+    free_changed: bool,
+}
+
+impl Pager {
+    /// Opens a paged file, or creates it if it does not exist. Existing files must have the given
+    /// page size. Newly created files have no root page, i.e. a root page ID of 0.
+    pub fn open(path: &Path, page_size: usize) -> Result<Self> {
+        if page_size < MIN_PAGE_SIZE {
+            return Err(Error::Config(format!("Page size must be at least {}", MIN_PAGE_SIZE)));
+        }
+        if let Some(dir) = path.parent() {
+            create_dir_all(dir)?
+        }
+        let file =
+            OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+        if file.metadata()?.len() > 0 {
+            return Self::load(file, Some(page_size));
+        }
+        let meta = Meta {
+            magic: MAGIC,
+            version: FORMAT_VERSION,
+            page_size: page_size as u32,
+            root: 0,
+            copy_on_write: false,
+            free_list: 0,
+        };
+        let mut pager = Self {
+            file: Mutex::new(file),
+            page_size,
+            page_count: 1,
+            meta,
+            free: BTreeSet::new(),
+            free_list: Vec::new(),
+            free_changed: false,
+        };
+        pager.sync()?;
+        Ok(pager)
+    }
+
+    /// Opens an existing paged file read-only, using the page size it was created with. Writes
+    /// and syncs will fail. The free list is not loaded, so that files with a corrupt free list
+    /// can still be read, but it can be read with read_free_list().
+    pub fn open_read_only(path: &Path) -> Result<Self> {
+        Self::load(OpenOptions::new().read(true).open(path)?, None)
+    }
+
+    /// Loads an existing paged file, checking that it has the given page size if any.
+    fn load(mut file: File, page_size: Option<usize>) -> Result<Self> {
+        // Check the metadata before reading it as a page, since the page size may not be known or
+        // may not match. It is then read again as a page below, verifying its checksum.
+        let len = file.metadata()?.len();
+        let mut head = vec![0; std::cmp::min(len, MIN_PAGE_SIZE as u64) as usize];
+        file.seek(SeekFrom::Start(0))?;
+        file.read_exact(&mut head)?;
+        let meta: Meta = bincode::deserialize(head.get(PAGE_HEADER_SIZE..).unwrap_or_default())?;
+        if meta.magic != MAGIC {
+            return Err(Error::Internal("Invalid file format".into()));
+        }
+        if meta.version != FORMAT_VERSION {
+            return Err(Error::Internal(format!("Unsupported file format {}", meta.version)));
+        }
+        let file_page_size = meta.page_size as usize;
+        if let Some(page_size) = page_size.filter(|&page_size| page_size != file_page_size) {
+            return Err(Error::Config(format!(
+                "File page size {} does not match {}",
+                file_page_size, page_size
+            )));
+        }
+        if file_page_size < MIN_PAGE_SIZE || len % file_page_size as u64 != 0 {
+            return Err(Error::Internal(format!(
+                "File size {} is not a multiple of the page size {}",
+                len, file_page_size
+            )));
+        }
+        let page_count = len / file_page_size as u64;
+        let mut pager = Self {
+            file: Mutex::new(file),
+            page_size: file_page_size,
+            page_count,
+            meta,
+            free: BTreeSet::new(),
+            free_list: Vec::new(),
+            free_changed: false,
+        };
+        pager.meta = bincode::deserialize(&pager.read(META_PAGE)?)?;
+        if page_size.is_some() {
+            (pager.free_list, pager.free) = pager.read_free_list()?;
+        }
+        Ok(pager)
+    }
+
+    /// Allocates a page, returning its ID. The lowest free page is reused if any, otherwise a new
+    /// page is added at the end of the file. The page contents are undefined until it is written.
+    pub fn allocate(&mut self) -> Result<PageId> {
+        if let Some(id) = self.free.pop_first() {
+            self.free_changed = true;
+            return Ok(id);
+        }
+        let id = self.page_count;
+        self.page_count += 1;
+        Ok(id)
+    }
+
+    /// Frees a page, such that it can be reused by allocate(). The free list is written to the
+    /// file on the next sync.
+    pub fn free(&mut self, id: PageId) -> Result<()> {
+        if id == META_PAGE || id >= self.page_count || !self.free.insert(id) {
+            return Err(Error::Internal(format!("Can't free page {}", id)));
+        }
+        self.free_changed = true;
+        Ok(())
+    }
+
+    /// Returns the number of free pages, excluding the free list pages.
+    pub fn free_count(&self) -> u64 {
+        self.free.len() as u64
+    }
+
+    /// Returns the maximum size of a page body.
+    pub fn capacity(&self) -> usize {
+        self.page_size - PAGE_HEADER_SIZE
+    }
+
+    /// Returns true if the file was created in copy-on-write mode, see Paged::new_copy_on_write().
+    pub fn copy_on_write(&self) -> bool {
+        self.meta.copy_on_write
+    }
+
+    /// Sets the copy-on-write mode flag. It is written to the file metadata on the next sync, and
+    /// should only be set when the file is created.
+    pub fn set_copy_on_write(&mut self, copy_on_write: bool) {
+        self.meta.copy_on_write = copy_on_write;
+    }
+
+    /// Returns the number of pages in the file, including the metadata page.
+    pub fn page_count(&self) -> u64 {
+        self.page_count
+    }
+
+    /// Returns the page size, in bytes.
+    pub fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    /// Reads the body of a page.
+    pub fn read(&self, id: PageId) -> Result<Vec<u8>> {
+        if id >= self.page_count {
+            return Err(Error::Internal(format!("Page {} is out of bounds", id)));
+        }
+        let mut page = vec![0; self.page_size];
+        let mut file = self.file.lock()?;
+        file.seek(SeekFrom::Start(id * self.page_size as u64))?;
+        file.read_exact(&mut page)?;
+
+        let (checksum, expect) =
+            (u32::from_le_bytes(page[4..8].try_into()?), Self::checksum(&page));
+        if checksum != expect {
+            return Err(Error::Corruption(
+                id,
+                format!("checksum {:08x} does not match contents {:08x}", checksum, expect),
+            ));
+        }
+        let len = u32::from_le_bytes(page[0..4].try_into()?) as usize;
+        if len > self.capacity() {
+            return Err(Error::Corruption(id, format!("invalid body length {}", len)));
+        }
+        page.truncate(PAGE_HEADER_SIZE + len);
+        Ok(page.split_off(PAGE_HEADER_SIZE))
+    }
+
+    /// Reads the free list from the file, returning the free list pages and the free pages they
+    /// list. Undecodable or invalid free list pages are returned as corruption errors.
+    pub fn read_free_list(&self) -> Result<(Vec<PageId>, BTreeSet<PageId>)> {
+        let (mut lists, mut free) = (Vec::new(), BTreeSet::new());
+        let mut next = Some(self.meta.free_list).filter(|&id| id != 0);
+        while let Some(id) = next {
+            if id == META_PAGE || id >= self.page_count || lists.contains(&id) {
+                return Err(Error::Corruption(id, "invalid free list page".into()));
+            }
+            let list: FreeList = bincode::deserialize(&self.read(id)?)
+                .map_err(|err| Error::Corruption(id, format!("{}", err)))?;
+            if let Some(page) = list.pages.iter().find(|&&page| page >= self.page_count) {
+                return Err(Error::Corruption(id, format!("free page {} is out of bounds", page)));
+            }
+            lists.push(id);
+            free.extend(list.pages);
+            next = list.next;
+        }
+        Ok((lists, free))
+    }
+
+    /// Returns the root page ID.
+    pub fn root(&self) -> PageId {
+        self.meta.root
+    }
+
+    /// Sets the root page ID. It is written to the file metadata on the next sync, such that the
+    /// file never refers to a root page whose contents have not been written yet.
+    pub fn set_root(&mut self, root: PageId) {
+        self.meta.root = root;
+    }
+
+    /// Flushes all written pages to durable storage, and then writes and flushes the file metadata.
+    /// Pages are made durable before the metadata, such that the metadata never refers to a root
+    /// page that has not been written yet. The metadata fits in a single disk sector, so writing it
+    /// is atomic on common hardware.
+    pub fn sync(&mut self) -> Result<()> {
+        debug!("Syncing {} pages to disk", self.page_count);
+        let released = match self.free_changed {
+            true => self.write_free_list()?,
+            false => Vec::new(),
+        };
+        self.file.lock()?.sync_data()?;
+        self.write_meta()?;
+        self.file.lock()?.sync_all()?;
+        // The previous free list pages are no longer referenced, and were listed as free pages.
+        self.free.extend(released);
+        self.free_changed = false;
+        Ok(())
+    }
+
+    /// Checks that a page body of the given size can be written to the given page.
+    pub fn check_write(&self, id: PageId, size: usize) -> Result<()> {
+        if id >= self.page_count {
+            return Err(Error::Internal(format!("Page {} is out of bounds", id)));
+        }
+        if size > self.capacity() {
+            return Err(Error::Internal(format!(
+                "Page {} body size {} exceeds capacity {}",
+                id,
+                size,
+                self.capacity()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Writes the body of a page.
+    pub fn write(&self, id: PageId, body: &[u8]) -> Result<()> {
+        self.check_write(id, body.len())?;
+        let mut page = Vec::with_capacity(self.page_size);
+        page.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        page.resize(PAGE_HEADER_SIZE, 0);
+        page.extend_from_slice(body);
+        page.resize(self.page_size, 0);
+        let checksum = Self::checksum(&page);
+        page[4..8].copy_from_slice(&checksum.to_le_bytes());
+
+        let mut file = self.file.lock()?;
+        file.seek(SeekFrom::Start(id * self.page_size as u64))?;
+        file.write_all(&page)?;
+        Ok(())
+    }
+
+    /// Computes the checksum of a page, skipping the checksum field in the header.
+    fn checksum(page: &[u8]) -> u32 {
+        crc32c::crc32c_append(crc32c::crc32c(&page[0..4]), &page[8..])
+    }
+
+    /// Writes the free list to new free list pages, and points the metadata at them. The new free
+    /// list pages are taken from the highest free pages, or added at the end of the file, while
+    /// the previous free list pages are listed as free pages, since they are free once the
+    /// metadata has been written. Returns the previous free list pages.
+    fn write_free_list(&mut self) -> Result<Vec<PageId>> {
+        let per_page = (self.capacity() - 24) / 8;
+        let released = std::mem::take(&mut self.free_list);
+        let mut lists = Vec::new();
+        while lists.len() * per_page < self.free.len() + released.len() {
+            match self.free.pop_last() {
+                Some(id) => lists.push(id),
+                None => {
+                    lists.push(self.page_count);
+                    self.page_count += 1;
+                }
+            }
+        }
+        let pages: Vec<_> = self.free.iter().chain(&released).copied().collect();
+        for (i, &id) in lists.iter().enumerate() {
+            let chunk = pages.iter().skip(i * per_page).take(per_page).copied().collect();
+            let list = FreeList { pages: chunk, next: lists.get(i + 1).copied() };
+            self.write(id, &bincode::serialize(&list)?)?;
+        }
+        debug!("Wrote {} free pages to {} free list pages", pages.len(), lists.len());
+        self.meta.free_list = lists.first().copied().unwrap_or(0);
+        self.free_list = lists;
+        Ok(released)
+    }
+
+    /// Writes the file metadata to the metadata page.
+    fn write_meta(&mut self) -> Result<()> {
+        let meta = bincode::serialize(&self.meta)?;
+        self.write(META_PAGE, &meta)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn checksum() -> Result<()> {
+        let dir = tempdir::TempDir::new("kupier")?;
+        let path = dir.path().join("kupier");
+        let mut pager = Pager::open(&path, 512)?;
+        for id in 1..=3 {
+            assert_eq!(id, pager.allocate()?);
+            pager.write(id, &[id as u8; 16])?;
+        }
+        pager.sync()?;
+        drop(pager);
+
+        // Flip a bit in the body of page 1 and in the padding of page 2.
+        let mut file = OpenOptions::new().read(true).write(true).open(&path)?;
+        for offset in [512 + PAGE_HEADER_SIZE as u64, 1024 + 500] {
+            let mut byte = [0];
+            file.seek(SeekFrom::Start(offset))?;
+            file.read_exact(&mut byte)?;
+            file.seek(SeekFrom::Start(offset))?;
+            file.write_all(&[byte[0] ^ 0x10])?;
+        }
+        drop(file);
+
+        let pager = Pager::open(&path, 512)?;
+        assert!(matches!(pager.read(1), Err(Error::Corruption(1, _))));
+        assert!(matches!(pager.read(2), Err(Error::Corruption(2, _))));
+        assert_eq!(vec![3; 16], pager.read(3)?);
+        Ok(())
+    }
+
+    #[test]
+    fn free_list() -> Result<()> {
+        let dir = tempdir::TempDir::new("kupier")?;
+        let path = dir.path().join("kupier");
+        let mut pager = Pager::open(&path, 512)?;
+        for id in 1..=300 {
+            assert_eq!(id, pager.allocate()?);
+            pager.write(id, &[])?;
+        }
+        for id in (1..=300).filter(|id| id % 3 != 0) {
+            pager.free(id)?;
+        }
+        assert_eq!(200, pager.free_count());
+        assert!(matches!(pager.free(1), Err(Error::Internal(_))));
+        assert!(matches!(pager.free(301), Err(Error::Internal(_))));
+
+        // Syncing writes the free list to the highest free pages, which spans several pages.
+        pager.sync()?;
+        let (lists, free) = pager.read_free_list()?;
+        assert_eq!(4, lists.len());
+        assert_eq!(196, free.len());
+        assert!(lists.iter().all(|id| *id > 290 && id % 3 != 0 && !free.contains(id)));
+        assert_eq!(free.len() as u64, pager.free_count());
+        drop(pager);
+
+        // The free list is loaded on open, and the lowest free pages are reused first.
+        let mut pager = Pager::open(&path, 512)?;
+        assert_eq!(196, pager.free_count());
+        assert_eq!(1, pager.allocate()?);
+        assert_eq!(2, pager.allocate()?);
+        assert_eq!(301, pager.page_count());
+
+        // The previous free list pages are free once a new free list has been written.
+        pager.sync()?;
+        let (new_lists, free) = pager.read_free_list()?;
+        assert_eq!(4, new_lists.len());
+        assert!(lists.iter().all(|id| !new_lists.contains(id) && free.contains(id)));
+        assert_eq!(194, pager.free_count());
+
+        // Allocating all free pages extends the file afterwards.
+        for _ in 0..194 {
+            assert!(pager.allocate()? <= 300);
+        }
+        assert_eq!(301, pager.allocate()?);
+        pager.sync()?;
+        assert_eq!(4, pager.free_count());
+        Ok(())
+    }
+}
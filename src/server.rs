@@ -0,0 +1,204 @@
+use super::{AsyncStore, Range};
+use crate::error::{Error, Result};
+
+use futures::StreamExt;
+use log::{error, info};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::{TcpListener, TcpStream};
+
+/// The maximum size of a protocol frame, to avoid allocating arbitrary amounts of memory.
+const MAX_FRAME_SIZE: usize = 64 << 20;
+
+/// A client request.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) enum Request {
+    Delete(Vec<u8>),
+    Flush,
+    Get(Vec<u8>),
+    Scan(Range),
+    Set(Vec<u8>, Vec<u8>),
+}
+
+/// A server response. Scans respond with a ScanItem per key/value pair followed by ScanEnd, or an
+/// Error if the scan fails midway. Other requests get a single response.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) enum Response {
+    Done,
+    Error(u8, String),
+    ScanEnd,
+    ScanItem(Vec<u8>, Vec<u8>),
+    Value(Option<Vec<u8>>),
+}
+
+impl From<Error> for Response {
+    /// Converts an error into an error response, mapping the error variant to a wire error code.
+    fn from(err: Error) -> Self {
+        let code = match &err {
+            Error::Abort => 1,
+            Error::Config(_) => 2,
+            Error::Internal(_) => 3,
+            Error::Parse(_) => 4,
+            Error::ReadOnly => 5,
+            Error::Serialization => 6,
+            Error::Value(_) => 7,
+            Error::Corruption(..) => 8,
+        };
+        let message = match err {
+            Error::Config(s) | Error::Internal(s) | Error::Parse(s) | Error::Value(s) => s,
+            Error::Corruption(page, s) => format!("{}:{}", page, s),
+            err => err.to_string(),
+        };
+        Self::Error(code, message)
+    }
+}
+
+impl Response {
+    /// Converts an error response back into the original error, or returns the response as is.
+    /// Unknown error codes and malformed corruption errors are returned as internal errors.
+    pub(crate) fn into_result(self) -> Result<Self> {
+        match self {
+            Self::Error(code, message) => Err(match code {
+                1 => Error::Abort,
+                2 => Error::Config(message),
+                4 => Error::Parse(message),
+                5 => Error::ReadOnly,
+                6 => Error::Serialization,
+                7 => Error::Value(message),
+                8 => match message.split_once(':').map(|(page, s)| (page.parse(), s)) {
+                    Some((Ok(page), s)) => Error::Corruption(page, s.to_string()),
+                    _ => Error::Internal(message),
+                },
+                _ => Error::Internal(message),
+            }),
+            response => Ok(response),
+        }
+    }
+}
+
+/// Reads a frame, returning None if the stream was closed before the frame. Frames are written as
+/// a 32-bit little-endian length followed by the bincode-encoded message.
+pub(crate) async fn read_frame<R, T>(reader: &mut R) -> Result<Option<T>>
+where
+    R: AsyncRead + Unpin,
+    T: DeserializeOwned,
+{
+    let mut len = [0; 4];
+    match reader.read_exact(&mut len).await {
+        Ok(_) => {}
+        Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into()),
+    }
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(Error::Value(format!("Frame size {} exceeds maximum {}", len, MAX_FRAME_SIZE)));
+    }
+    let mut frame = vec![0; len];
+    reader.read_exact(&mut frame).await?;
+    Ok(Some(bincode::deserialize(&frame)?))
+}
+
+/// Writes a frame, without flushing the writer.
+pub(crate) async fn write_frame<W, T>(writer: &mut W, message: &T) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+    T: Serialize,
+{
+    let frame = bincode::serialize(message)?;
+    writer.write_all(&(frame.len() as u32).to_le_bytes()).await?;
+    writer.write_all(&frame).await?;
+    Ok(())
+}
+
+/// Serves a key/value store over TCP, using a simple length-prefixed binary protocol. Each
+/// connection is handled by a separate task, which executes the client's requests in order and
+/// streams scan results as they are read from the store. Any Store can be served by wrapping it
+/// in an AsyncAdapter.
+pub struct Server {
+    /// The store to serve.
+    store: Arc<dyn AsyncStore>,
+}
+
+impl Server {
+    /// Creates a new server for the given store.
+    pub fn new<S: AsyncStore + 'static>(store: S) -> Self {
+        Self { store: Arc::new(store) }
+    }
+
+    /// Serves clients connecting to the given listener, until accepting a connection fails.
+    pub async fn serve(self, listener: TcpListener) -> Result<()> {
+        info!("Serving {} on {}", self.store, listener.local_addr()?);
+        loop {
+            let (socket, peer) = listener.accept().await?;
+            info!("Client {} connected", peer);
+            let store = self.store.clone();
+            tokio::spawn(async move {
+                match Self::session(store, socket).await {
+                    Ok(()) => info!("Client {} disconnected", peer),
+                    Err(err) => error!("Client {} session failed: {}", peer, err),
+                }
+            });
+        }
+    }
+
+    /// Handles a client connection, until the client disconnects.
+    async fn session(store: Arc<dyn AsyncStore>, socket: TcpStream) -> Result<()> {
+        let (reader, writer) = socket.into_split();
+        let (mut reader, mut writer) = (BufReader::new(reader), BufWriter::new(writer));
+        while let Some(request) = read_frame(&mut reader).await? {
+            let response = match request {
+                Request::Delete(key) => store.delete(&key).await.map(|_| Response::Done),
+                Request::Flush => store.flush().await.map(|_| Response::Done),
+                Request::Get(key) => store.get(&key).await.map(Response::Value),
+                Request::Set(key, value) => store.set(&key, value).await.map(|_| Response::Done),
+                Request::Scan(range) => {
+                    let mut scan = store.scan(range);
+                    let mut response = Ok(Response::ScanEnd);
+                    while let Some(item) = scan.next().await {
+                        match item {
+                            Ok((key, value)) => {
+                                write_frame(&mut writer, &Response::ScanItem(key, value)).await?
+                            }
+                            Err(err) => {
+                                response = Err(err);
+                                break;
+                            }
+                        }
+                    }
+                    response
+                }
+            };
+            write_frame(&mut writer, &response.unwrap_or_else(Response::from)).await?;
+            writer.flush().await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn error_codes() -> Result<()> {
+        let errors = vec![
+            Error::Abort,
+            Error::Config("config".into()),
+            Error::Corruption(7, "checksum: mismatch".into()),
+            Error::Internal("internal".into()),
+            Error::Parse("parse".into()),
+            Error::ReadOnly,
+            Error::Serialization,
+            Error::Value("value".into()),
+        ];
+        for err in errors {
+            assert_eq!(Err(err.clone()), Response::from(err).into_result());
+        }
+        assert_eq!(Err(Error::Internal("x".into())), Response::Error(0, "x".into()).into_result());
+        assert_eq!(Err(Error::Internal("x".into())), Response::Error(8, "x".into()).into_result());
+        assert_eq!(Ok(Response::Done), Response::Done.into_result());
+        Ok(())
+    }
+}
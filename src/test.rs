@@ -1,4 +1,4 @@
-use super::{Memory, Range, Scan, Store};
+use super::{Cursor, Memory, Range, Scan, Store, WriteBatch};
 use crate::error::Result;
 
 use std::fmt::Display;
@@ -33,6 +33,14 @@ impl Store for Test {
         self.kv.write()?.delete(key)
     }
 
+    fn delete_range(&mut self, range: Range) -> Result<usize> {
+        self.kv.write()?.delete_range(range)
+    }
+
+    fn cursor(&self) -> Box<dyn Cursor> {
+        self.kv.read().unwrap().cursor()
+    }
+
     fn flush(&mut self) -> Result<()> {
         Ok(())
     }
@@ -42,19 +50,23 @@ impl Store for Test {
     }
 
     fn scan(&self, range: Range) -> Scan {
-        // Since the mutex guard is scoped to this method, we simply buffer the result.
-        Box::new(self.kv.read().unwrap().scan(range).collect::<Vec<Result<_>>>().into_iter())
+        // The Memory scan runs against a snapshot, so it doesn't borrow the lock guard.
+        self.kv.read().unwrap().scan(range)
     }
 
     fn set(&mut self, key: &[u8], value: Vec<u8>) -> Result<()> {
         self.kv.write()?.set(key, value)
     }
+
+    fn write(&mut self, batch: WriteBatch) -> Result<()> {
+        self.kv.write()?.write(batch)
+    }
 }
 
 #[cfg(test)]
 impl super::TestSuite<Test> for Test {
-    fn setup() -> Result<Self> {
-        Ok(Test::new())
+    fn setup() -> Result<(Self, Option<tempdir::TempDir>)> {
+        Ok((Test::new(), None))
     }
 }
 
@@ -64,6 +76,96 @@ fn tests() -> Result<()> {
     Test::test()
 }
 
+/// A store which only implements the required Store methods, by wrapping a Memory store, to test
+/// the default implementations of the other methods.
+struct Minimal(Memory);
+
+impl Display for Minimal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "minimal")
+    }
+}
+
+impl Store for Minimal {
+    fn delete(&mut self, key: &[u8]) -> Result<()> {
+        self.0.delete(key)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.0.flush()
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.0.get(key)
+    }
+
+    fn scan(&self, range: Range) -> Scan {
+        self.0.scan(range)
+    }
+
+    fn set(&mut self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        self.0.set(key, value)
+    }
+}
+
+#[test]
+fn default_cursor() -> Result<()> {
+    let mut s = Minimal(Memory::new());
+    let mut c = s.cursor();
+    c.seek_to_first()?;
+    assert!(!c.valid());
+
+    s.set(b"a", vec![0x01])?;
+    s.set(b"b", vec![0x02])?;
+    s.set(b"ba", vec![0x02, 0x01])?;
+    s.set(b"c", vec![0x03])?;
+
+    // The cursor reads the store when it is first positioned, and doesn't see later writes.
+    let mut c = s.cursor();
+    c.seek(b"b\x00")?;
+    assert_eq!((Some(&b"ba"[..]), Some(&[0x02, 0x01][..])), (c.key(), c.value()));
+    s.delete(b"a")?;
+    c.seek_for_prev(b"az")?;
+    assert_eq!(Some(&b"a"[..]), c.key());
+    c.prev()?;
+    assert!(!c.valid());
+    c.seek_to_last()?;
+    assert_eq!(Some(&b"c"[..]), c.key());
+    c.next()?;
+    assert!(!c.valid());
+    c.seek(b"d")?;
+    assert!(!c.valid());
+    c.seek_to_first()?;
+    c.next()?;
+    assert_eq!(Some(&b"b"[..]), c.key());
+    Ok(())
+}
+
+#[test]
+fn default_write() -> Result<()> {
+    let mut s = Minimal(Memory::new());
+    s.set(b"a", vec![0x01])?;
+    let mut batch = WriteBatch::new();
+    batch.set(b"b", vec![0x02]).delete(b"a").set(b"c", vec![0x03]).delete(b"c");
+    s.write(batch)?;
+    let expect = vec![(b"b".to_vec(), vec![0x02])];
+    assert_eq!(expect, s.scan(Range::from(..)).collect::<Result<Vec<_>>>()?);
+    Ok(())
+}
+
+#[test]
+fn default_delete_range() -> Result<()> {
+    let mut s = Minimal(Memory::new());
+    for key in [b"a", b"b", b"c", b"d"] {
+        s.set(key, vec![])?;
+    }
+    assert_eq!(2, s.delete_range(Range::from(b"b".to_vec()..=b"c".to_vec()))?);
+    assert_eq!(0, s.delete_range(Range::from(b"b".to_vec()..b"c".to_vec()))?);
+    let expect = vec![(b"a".to_vec(), vec![]), (b"d".to_vec(), vec![])];
+    assert_eq!(expect, s.scan(Range::from(..)).collect::<Result<Vec<_>>>()?);
+    Ok(())
+}
+
 fn calculate_efficiency(page_size: u32, optimum_page_size: u32, value: u32, records: u64) {
     let percentage = page_size as f64 / optimum_page_size as f64;
     println!("{}", page_size);
@@ -0,0 +1,123 @@
+use super::keycode::{self, Key};
+use super::Store;
+use crate::error::Result;
+
+use serde::{de::DeserializeOwned, Serialize};
+use std::fmt::Display;
+use std::marker::PhantomData;
+use std::ops::RangeBounds;
+
+/// A typed key/value store on top of a byte-oriented store. Keys are encoded with an
+/// order-preserving encoding (see keycode::Key), such that scans return keys in their natural
+/// order, while values are serialized using bincode.
+pub struct TypedStore<K, V> {
+    /// The underlying store.
+    store: Box<dyn Store>,
+    /// The key and value types. The fn pointer keeps the store Send and Sync regardless of them.
+    types: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K, V> Display for TypedStore<K, V> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "typed:{}", self.store)
+    }
+}
+
+impl<K: Key, V: Serialize + DeserializeOwned> TypedStore<K, V> {
+    /// Creates a new typed store with the given key-value store for storage.
+    pub fn new(store: Box<dyn Store>) -> Self {
+        Self { store, types: PhantomData }
+    }
+
+    /// Deletes a key, or does nothing if it does not exist.
+    pub fn delete(&mut self, key: &K) -> Result<()> {
+        self.store.delete(&key.encode())
+    }
+
+    /// Flushes any buffered data to the underlying storage medium.
+    pub fn flush(&mut self) -> Result<()> {
+        self.store.flush()
+    }
+
+    /// Gets a value for a key, if it exists.
+    pub fn get(&self, key: &K) -> Result<Option<V>> {
+        self.store.get(&key.encode())?.map(|v| Ok(bincode::deserialize(&v)?)).transpose()
+    }
+
+    /// Returns the underlying store.
+    pub fn into_inner(self) -> Box<dyn Store> {
+        self.store
+    }
+
+    /// Iterates over an ordered range of key/value pairs.
+    pub fn scan<R: RangeBounds<K>>(
+        &self,
+        range: R,
+    ) -> impl DoubleEndedIterator<Item = Result<(K, V)>> {
+        self.store.scan(keycode::range(range)).map(|r| {
+            let (key, value) = r?;
+            Ok((K::decode(&key)?, bincode::deserialize(&value)?))
+        })
+    }
+
+    /// Sets a value for a key, replacing the existing value if any.
+    pub fn set(&mut self, key: &K, value: &V) -> Result<()> {
+        self.store.set(&key.encode(), bincode::serialize(value)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::Memory;
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn typed_store() -> Result<()> {
+        type Typed = TypedStore<(String, i64), Vec<String>>;
+        let mut s = Typed::new(Box::new(Memory::new()));
+        assert_eq!("typed:memory", s.to_string());
+        assert_eq!(None, s.get(&("a".into(), 1))?);
+
+        let items: Vec<((String, i64), Vec<String>)> = vec![
+            (("a".into(), -10), vec!["x".into()]),
+            (("a".into(), -1), vec![]),
+            (("a".into(), 0), vec!["y".into(), "z".into()]),
+            (("a".into(), 3), vec!["\0".into()]),
+            (("a\0".into(), i64::MIN), vec![]),
+            (("b".into(), 2), vec!["w".into()]),
+        ];
+        for (key, value) in items.iter().rev() {
+            s.set(key, value)?;
+        }
+        for (key, value) in &items {
+            assert_eq!(Some(value), s.get(key)?.as_ref());
+        }
+
+        // Scans return keys in their natural order, in both directions.
+        assert_eq!(items, s.scan(..).collect::<Result<Vec<_>>>()?);
+        assert_eq!(
+            items.iter().rev().cloned().collect::<Vec<_>>(),
+            s.scan(..).rev().collect::<Result<Vec<_>>>()?
+        );
+        assert_eq!(
+            items[1..4].to_vec(),
+            s.scan(("a".into(), -5)..("a\0".into(), i64::MIN)).collect::<Result<Vec<_>>>()?
+        );
+        assert_eq!(items[..=2].to_vec(), s.scan(..=("a".into(), 0)).collect::<Result<Vec<_>>>()?);
+
+        s.delete(&("a".into(), 0))?;
+        s.set(&("a".into(), -1), &vec!["v".into()])?;
+        s.flush()?;
+        assert_eq!(None, s.get(&("a".into(), 0))?);
+        assert_eq!(Some(vec!["v".into()]), s.get(&("a".into(), -1))?);
+        assert_eq!(5, s.scan(..).count());
+
+        // Keys and values that don't decode are returned as errors.
+        let mut store = s.into_inner();
+        store.set(&[0xff], vec![])?;
+        let s = Typed::new(store);
+        assert!(s.scan(..).next_back().unwrap().is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,373 @@
+use super::{Cursor, Range, Scan, Store, WriteBatch};
+use crate::error::{Error, Result};
+
+use log::{debug, info, warn};
+use serde_derive::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::fs::{create_dir_all, File, OpenOptions};
+use std::io::{BufReader, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// The size of a log record header: the record length, and the checksums of the length and record.
+const HEADER_SIZE: usize = 12;
+
+/// A write-ahead log record, describing a single store mutation. A write batch is logged as a
+/// single record, so that it is either recovered in full or not at all.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+enum Record {
+    Set(Vec<u8>, Vec<u8>),
+    Delete(Vec<u8>),
+    DeleteRange(Range),
+    Write(WriteBatch),
+}
+
+/// Wraps a store with an append-only write-ahead log. Every mutation is appended to the log before
+/// it is applied to the store, and flush() syncs the log to durable storage before flushing the
+/// store. When opened, the log is replayed into the store, recovering all mutations that were
+/// written to the log before a crash.
+///
+/// Log records are written as a 32-bit little-endian length, a CRC32C checksum of the length and
+/// a CRC32C checksum of the record (both 32-bit little-endian), followed by the bincode-encoded
+/// record. A crash may leave a partially written record at the end of the log, which is discarded
+/// during recovery, such that the recovered state is always a prefix of the logged mutations. A
+/// corrupt record followed by a valid record is an error, rather than discarding the committed
+/// records after it. Mutations that fail are removed from the log again, so the log only contains
+/// mutations that were applied to the store.
+///
+/// Replaying the log is idempotent, so it can be replayed into either an empty store such as
+/// Memory, or into a persistent store which may already contain some or all of the mutations. The
+/// log is never compacted, and grows with every mutation.
+pub struct Wal<S: Store> {
+    /// The underlying store.
+    store: S,
+    /// The log file, opened for appending.
+    file: File,
+    /// The length of the log, i.e. the offset of the next record.
+    len: u64,
+}
+
+impl<S: Store> Display for Wal<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "wal:{}", self.store)
+    }
+}
+
+impl<S: Store> Wal<S> {
+    /// Opens a write-ahead log in the given file, or creates it if it does not exist, and replays
+    /// it into the given store. Any partially written record at the end of the log is discarded,
+    /// but corruption elsewhere in the log returns an error.
+    pub fn new(path: &Path, mut store: S) -> Result<Self> {
+        if let Some(dir) = path.parent() {
+            create_dir_all(dir)?
+        }
+        let mut file =
+            OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+        info!("Recovering write-ahead log {:?}", path);
+        let len = Self::replay(&mut file, &mut store)?;
+        file.set_len(len)?;
+        file.seek(SeekFrom::Start(len))?;
+        Ok(Self { store, file, len })
+    }
+
+    /// Appends a record to the log, and then applies it to the store using the given function. If
+    /// either fails, the record is truncated from the log again. The record is written straight to
+    /// the file, such that it can be recovered after a process crash, but it is not durable until
+    /// the log is synced.
+    fn append<T>(&mut self, record: &Record, apply: impl FnOnce(&mut S) -> Result<T>) -> Result<T> {
+        let record = bincode::serialize(record)?;
+        let len = u32::try_from(record.len()).map_err(|_| {
+            Error::Value(format!("Log record size {} exceeds maximum {}", record.len(), u32::MAX))
+        })?;
+        let len = len.to_le_bytes();
+        let mut buf = Vec::with_capacity(HEADER_SIZE + record.len());
+        buf.extend_from_slice(&len);
+        buf.extend_from_slice(&crc32c::crc32c(&len).to_le_bytes());
+        buf.extend_from_slice(&crc32c::crc32c(&record).to_le_bytes());
+        buf.extend_from_slice(&record);
+        match self.file.write_all(&buf).map_err(Error::from).and_then(|()| apply(&mut self.store)) {
+            Ok(result) => {
+                self.len += buf.len() as u64;
+                Ok(result)
+            }
+            Err(err) => {
+                self.file.set_len(self.len)?;
+                self.file.seek(SeekFrom::Start(self.len))?;
+                Err(err)
+            }
+        }
+    }
+
+    /// Decodes the log record at the start of the given buffer, returning its body, or None if the
+    /// record is incomplete or its checksums don't match.
+    fn decode(buf: &[u8]) -> Option<&[u8]> {
+        let header = buf.get(..HEADER_SIZE)?;
+        let word = |i: usize| u32::from_le_bytes(header[i..i + 4].try_into().unwrap());
+        if crc32c::crc32c(&header[0..4]) != word(4) {
+            return None;
+        }
+        let record = buf.get(HEADER_SIZE..HEADER_SIZE + word(0) as usize)?;
+        (crc32c::crc32c(record) == word(8)).then_some(record)
+    }
+
+    /// Replays the log into the given store, returning the length of the valid part of the log.
+    /// Replay stops at the first incomplete or invalid record. If it is followed by a valid record
+    /// it can't have been torn by a crash, so it is corruption and returns an error.
+    fn replay(file: &mut File, store: &mut S) -> Result<u64> {
+        let file_len = file.metadata()?.len();
+        file.seek(SeekFrom::Start(0))?;
+        let mut reader = BufReader::new(file);
+        let mut len = 0;
+        let mut records = 0;
+        let mut header = [0; HEADER_SIZE];
+        loop {
+            match reader.read_exact(&mut header) {
+                Ok(()) => {}
+                Err(err) if err.kind() == ErrorKind::UnexpectedEof => break,
+                Err(err) => return Err(err.into()),
+            }
+            let record_len = u32::from_le_bytes(header[0..4].try_into()?) as u64;
+            let end = len + HEADER_SIZE as u64 + record_len;
+            if crc32c::crc32c(&header[0..4]) != u32::from_le_bytes(header[4..8].try_into()?)
+                || end > file_len
+            {
+                Self::check_tail(&mut reader, len)?;
+                break;
+            }
+            let mut record = vec![0; record_len as usize];
+            reader.read_exact(&mut record)?;
+            if crc32c::crc32c(&record) != u32::from_le_bytes(header[8..12].try_into()?) {
+                Self::check_tail(&mut reader, len)?;
+                break;
+            }
+            match bincode::deserialize(&record).map_err(|err| {
+                Error::Internal(format!("Log record at offset {} is corrupt: {}", len, err))
+            })? {
+                Record::Set(key, value) => store.set(&key, value)?,
+                Record::Delete(key) => store.delete(&key)?,
+                Record::DeleteRange(range) => {
+                    store.delete_range(range)?;
+                }
+                Record::Write(batch) => store.write(batch)?,
+            }
+            len = end;
+            records += 1;
+        }
+        info!("Replayed {} records ({} bytes) from write-ahead log", records, len);
+        if len < file_len {
+            warn!("Discarding {} bytes of incomplete records at end of log", file_len - len);
+        }
+        Ok(len)
+    }
+
+    /// Checks that the invalid record at the given offset is at the tail of the log, i.e. that no
+    /// valid record starts anywhere after it, and returns an error otherwise.
+    fn check_tail(reader: &mut BufReader<&mut File>, offset: u64) -> Result<()> {
+        let mut tail = Vec::new();
+        reader.seek(SeekFrom::Start(offset))?;
+        reader.read_to_end(&mut tail)?;
+        match (1..tail.len()).find(|i| Self::decode(&tail[*i..]).is_some()) {
+            Some(i) => Err(Error::Internal(format!(
+                "Log record at offset {} is corrupt, but followed by a valid record at offset {}",
+                offset,
+                offset + i as u64
+            ))),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<S: Store> Store for Wal<S> {
+    fn delete(&mut self, key: &[u8]) -> Result<()> {
+        self.append(&Record::Delete(key.to_vec()), |store| store.delete(key))
+    }
+
+    fn delete_range(&mut self, range: Range) -> Result<usize> {
+        self.append(&Record::DeleteRange(range.clone()), |store| store.delete_range(range))
+    }
+
+    fn cursor(&self) -> Box<dyn Cursor> {
+        self.store.cursor()
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        debug!("Syncing write-ahead log");
+        self.file.sync_data()?;
+        self.store.flush()
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.store.get(key)
+    }
+
+    fn scan(&self, range: Range) -> Scan {
+        self.store.scan(range)
+    }
+
+    fn set(&mut self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        self.append(&Record::Set(key.to_vec(), value.clone()), |store| store.set(key, value))
+    }
+
+    fn write(&mut self, batch: WriteBatch) -> Result<()> {
+        self.append(&Record::Write(batch.clone()), |store| store.write(batch))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Memory, Paged};
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    impl super::super::TestSuite<Wal<Memory>> for Wal<Memory> {
+        fn setup() -> Result<(Self, Option<tempdir::TempDir>)> {
+            let dir = tempdir::TempDir::new("kupier")?;
+            Ok((Wal::new(&dir.path().join("kupier"), Memory::new())?, Some(dir)))
+        }
+    }
+
+    #[test]
+    fn tests() -> Result<()> {
+        use super::super::TestSuite;
+        Wal::<Memory>::test()
+    }
+
+    /// Returns all key/value pairs in a store.
+    fn dump<S: Store>(s: &S) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        s.scan(Range::from(..)).collect()
+    }
+
+    #[test]
+    fn recover() -> Result<()> {
+        let dir = tempdir::TempDir::new("kupier")?;
+        let path = dir.path().join("wal");
+
+        let mut s = Wal::new(&path, Memory::new())?;
+        s.set(b"a", vec![0x01])?;
+        s.set(b"b", vec![0x02])?;
+        s.delete(b"a")?;
+        s.set(b"c", vec![0x03])?;
+        s.set(b"d", vec![0x04])?;
+        s.delete_range(Range::from(b"b".to_vec()..b"c".to_vec()))?;
+        s.flush()?;
+        let expect = dump(&s)?;
+        drop(s);
+
+        // Replaying into an empty store and into a persistent store which already contains the
+        // mutations should both recover the same state.
+        let s = Wal::new(&path, Memory::new())?;
+        assert_eq!(expect, dump(&s)?);
+
+        let paged_path = dir.path().join("paged");
+        let mut s = Wal::new(&path, Paged::new(&paged_path)?)?;
+        s.flush()?;
+        drop(s);
+        let s = Wal::new(&path, Paged::new(&paged_path)?)?;
+        assert_eq!(expect, dump(&s)?);
+        Ok(())
+    }
+
+    #[test]
+    fn recover_truncated() -> Result<()> {
+        use rand::Rng;
+        let dir = tempdir::TempDir::new("kupier")?;
+        let path = dir.path().join("wal");
+        let mut rng: rand::rngs::StdRng = rand::SeedableRng::seed_from_u64(397_427_893);
+
+        // Apply a random history of mutations, recording the state and log length after each.
+        let mut s = Wal::new(&path, Memory::new())?;
+        let mut history = vec![(0, vec![])];
+        for _ in 0..100 {
+            let key = vec![rng.gen_range(0..16)];
+            match rng.gen_range(0..10) {
+                0..=5 => s.set(&key, vec![rng.gen(); rng.gen_range(0..8)])?,
+                6..=7 => s.delete(&key)?,
+                _ => {
+                    let mut batch = WriteBatch::new();
+                    for _ in 0..rng.gen_range(1..4) {
+                        batch.set(&[rng.gen_range(0..16)], vec![rng.gen(); rng.gen_range(0..8)]);
+                    }
+                    batch.delete(&key);
+                    s.write(batch)?
+                }
+            }
+            history.push((std::fs::metadata(&path)?.len(), dump(&s)?));
+        }
+        s.flush()?;
+        drop(s);
+        let log = std::fs::read(&path)?;
+
+        // Truncating the log at any offset should recover the state after the last complete
+        // record, including all or none of a write batch, and further mutations should be
+        // appended after it.
+        let crash_path = dir.path().join("crash");
+        for offset in 0..=log.len() {
+            std::fs::write(&crash_path, &log[..offset])?;
+            let mut s = Wal::new(&crash_path, Memory::new())?;
+            let (len, expect) =
+                history.iter().rev().find(|(len, _)| *len <= offset as u64).unwrap();
+            assert_eq!(expect, &dump(&s)?, "invalid state at offset {}", offset);
+            assert_eq!(*len, std::fs::metadata(&crash_path)?.len());
+
+            s.set(b"x", vec![0xff])?;
+            drop(s);
+            let s = Wal::new(&crash_path, Memory::new())?;
+            assert_eq!(Some(vec![0xff]), s.get(b"x")?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn recover_corrupt() -> Result<()> {
+        let dir = tempdir::TempDir::new("kupier")?;
+        let path = dir.path().join("wal");
+
+        let mut s = Wal::new(&path, Memory::new())?;
+        s.set(b"a", vec![0x01])?;
+        let first = std::fs::metadata(&path)?.len() as usize;
+        s.set(b"b", vec![0x02])?;
+        s.flush()?;
+        drop(s);
+        let log = std::fs::read(&path)?;
+
+        // Corrupting the final record should discard it, like a torn write.
+        let crash_path = dir.path().join("crash");
+        let mut corrupt = log.clone();
+        *corrupt.last_mut().unwrap() ^= 0xff;
+        std::fs::write(&crash_path, &corrupt)?;
+        let s = Wal::new(&crash_path, Memory::new())?;
+        assert_eq!(vec![(b"a".to_vec(), vec![0x01])], dump(&s)?);
+        assert_eq!(first as u64, std::fs::metadata(&crash_path)?.len());
+        drop(s);
+
+        // Corrupting an earlier record or its length should error, and leave the log intact.
+        for offset in [first - 1, 0] {
+            let mut corrupt = log.clone();
+            corrupt[offset] ^= 0x01;
+            std::fs::write(&crash_path, &corrupt)?;
+            assert!(matches!(Wal::new(&crash_path, Memory::new()), Err(Error::Internal(_))));
+            assert_eq!(corrupt, std::fs::read(&crash_path)?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn rejected() -> Result<()> {
+        let dir = tempdir::TempDir::new("kupier")?;
+        let path = dir.path().join("wal");
+        let paged_path = dir.path().join("paged");
+
+        // A mutation rejected by the store is removed from the log again, so it isn't replayed.
+        let mut s = Wal::new(&path, Paged::new_with_page_size(&paged_path, 512)?)?;
+        s.set(b"a", vec![0x01])?;
+        let len = std::fs::metadata(&path)?.len();
+        assert!(matches!(s.set(b"b", vec![0; 512]), Err(Error::Value(_))));
+        assert_eq!(len, std::fs::metadata(&path)?.len());
+        s.set(b"c", vec![0x03])?;
+        s.flush()?;
+        drop(s);
+
+        let s = Wal::new(&path, Memory::new())?;
+        assert_eq!(vec![(b"a".to_vec(), vec![0x01]), (b"c".to_vec(), vec![0x03])], dump(&s)?);
+        Ok(())
+    }
+}